@@ -0,0 +1,158 @@
+extern crate rppal;
+
+use std::fmt;
+use rppal::i2c::I2c;
+use rppal::spi::{ Spi, Bus, SlaveSelect, Mode };
+
+/// Errors that can happen while reading an absolute encoder.
+#[derive(Debug)]
+pub enum AbsoluteEncoderError {
+    /// The I2C transfer to the sensor failed.
+    I2c(rppal::i2c::Error),
+    /// The SPI transfer to the sensor failed.
+    Spi(rppal::spi::Error),
+    /// The sensor cannot see its magnet (it has fallen off or is too far away).
+    NoMagnet,
+    /// The reply from the sensor failed its parity check, had its error flag set, or was all zeros.
+    BadReply,
+    /// Nothing answered like the sensor when it was opened (it is not connected, or not powered).
+    NotResponding
+}
+
+impl fmt::Display for AbsoluteEncoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AbsoluteEncoderError::I2c(error) => write!(f, "I2C error: {}", error),
+            AbsoluteEncoderError::Spi(error) => write!(f, "SPI error: {}", error),
+            AbsoluteEncoderError::NoMagnet => write!(f, "magnet not detected"),
+            AbsoluteEncoderError::BadReply => write!(f, "bad reply from sensor"),
+            AbsoluteEncoderError::NotResponding => write!(f, "sensor not responding")
+        }
+    }
+}
+
+impl From<rppal::i2c::Error> for AbsoluteEncoderError {
+    fn from(error: rppal::i2c::Error) -> Self {
+        AbsoluteEncoderError::I2c(error)
+    }
+}
+
+impl From<rppal::spi::Error> for AbsoluteEncoderError {
+    fn from(error: rppal::spi::Error) -> Self {
+        AbsoluteEncoderError::Spi(error)
+    }
+}
+
+/// A sensor that measures the absolute angle of a shaft (it knows the angle as soon as it powers on, unlike an incremental encoder).
+pub trait AbsoluteEncoder {
+    /// Read the angle of the shaft in degrees, from 0.0 up to (but not including) 360.0.
+    fn read_angle(&mut self) -> Result<f64, AbsoluteEncoderError>;
+}
+
+/// AS5600 12-bit magnetic angle sensor, connected over I2C.
+pub struct As5600 {
+    i2c: I2c
+}
+
+impl As5600 {
+    const I2C_ADDRESS: u16 = 0x36;
+    const REGISTER_STATUS: u8 = 0x0B;
+    const REGISTER_RAW_ANGLE: u8 = 0x0C;
+    const STATUS_MAGNET_DETECTED: u8 = 0x20;
+    const COUNTS_PER_REVOLUTION: f64 = 4096.0;
+
+    /// Open an AS5600 on the default I2C bus. The AS5600 has a fixed address, so only one can be connected to each bus.
+    pub fn new() -> Result<As5600, AbsoluteEncoderError> {
+        let mut i2c: I2c = I2c::new()?;
+        i2c.set_slave_address(As5600::I2C_ADDRESS)?;
+        let mut as5600 = As5600 { i2c: i2c };
+        as5600.read_angle()?;
+        Ok(as5600)
+    }
+}
+
+impl AbsoluteEncoder for As5600 {
+    fn read_angle(&mut self) -> Result<f64, AbsoluteEncoderError> {
+        let mut status: [u8; 1] = [0; 1];
+        self.i2c.write_read(&[As5600::REGISTER_STATUS], &mut status)?;
+        if status[0] & As5600::STATUS_MAGNET_DETECTED == 0 {
+            return Err(AbsoluteEncoderError::NoMagnet);
+        }
+
+        let mut buf: [u8; 2] = [0; 2];
+        self.i2c.write_read(&[As5600::REGISTER_RAW_ANGLE], &mut buf)?;
+        let counts: u16 = (((buf[0] & 0x0F) as u16) << 8) | (buf[1] as u16);
+
+        Ok(counts as f64 * 360.0 / As5600::COUNTS_PER_REVOLUTION)
+    }
+}
+
+/// AS5048A 14-bit magnetic angle sensor, connected over SPI.
+pub struct As5048 {
+    spi: Spi
+}
+
+impl As5048 {
+    /// Command to read the angle register (read bit and address 0x3FFF, with the even parity bit set).
+    const COMMAND_READ_ANGLE: [u8; 2] = [0xFF, 0xFF];
+    /// Command to read the diagnostics and automatic gain control register (read bit and address 0x3FFD, which already have even parity).
+    const COMMAND_READ_DIAGNOSTICS: [u8; 2] = [0x7F, 0xFD];
+    const REPLY_ERROR_FLAG: u16 = 0x4000;
+    const REPLY_VALUE_MASK: u16 = 0x3FFF;
+    /// Diagnostics bit set once the sensor has finished its offset compensation after powering on.
+    const DIAGNOSTICS_OFFSET_COMPENSATION_FINISHED: u16 = 0x0100;
+    /// Diagnostics bits set when the magnetic field is too strong or too weak.
+    const DIAGNOSTICS_FIELD_OUT_OF_RANGE: u16 = 0x0C00;
+    const CLOCK_SPEED: u32 = 1_000_000;
+    const COUNTS_PER_REVOLUTION: f64 = 16384.0;
+
+    /// Open an AS5048A. SPI has no acknowledge, so a missing sensor reads as all zeros rather than
+    /// failing. The diagnostics register is checked to tell a real sensor from an empty bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - SPI bus that the sensor is connected to.
+    ///
+    /// * `slave_select` - Chip select line that the sensor is connected to.
+    pub fn new(bus: Bus, slave_select: SlaveSelect) -> Result<As5048, AbsoluteEncoderError> {
+        let spi: Spi = Spi::new(bus, slave_select, As5048::CLOCK_SPEED, Mode::Mode1)?;
+        let mut as5048 = As5048 { spi: spi };
+        let diagnostics: u16 = match as5048.read_register(&As5048::COMMAND_READ_DIAGNOSTICS) {
+            Ok(diagnostics) => diagnostics,
+            Err(AbsoluteEncoderError::BadReply) => return Err(AbsoluteEncoderError::NotResponding),
+            Err(error) => return Err(error)
+        };
+        if diagnostics & As5048::DIAGNOSTICS_OFFSET_COMPENSATION_FINISHED == 0 {
+            return Err(AbsoluteEncoderError::NotResponding);
+        }
+        if diagnostics & As5048::DIAGNOSTICS_FIELD_OUT_OF_RANGE != 0 {
+            return Err(AbsoluteEncoderError::NoMagnet);
+        }
+        as5048.read_angle()?;
+        Ok(as5048)
+    }
+
+    /// Send a read command and get the 14-bit value of the register from the reply.
+    fn read_register(&mut self, command: &[u8; 2]) -> Result<u16, AbsoluteEncoderError> {
+        // The AS5048A answers each command during the following transfer, so the command is sent twice.
+        let mut buf: [u8; 2] = [0; 2];
+        self.spi.transfer(&mut buf, command)?;
+        self.spi.transfer(&mut buf, command)?;
+        let reply: u16 = ((buf[0] as u16) << 8) | (buf[1] as u16);
+
+        // An all-zero reply passes the parity and error checks, but is also what a disconnected
+        // sensor gives, so it is never trusted (a real sensor only sends it at exactly 0 counts
+        // with no flags set, and the next reading a step away is accepted again).
+        if reply == 0 || reply.count_ones() % 2 != 0 || reply & As5048::REPLY_ERROR_FLAG != 0 {
+            return Err(AbsoluteEncoderError::BadReply);
+        }
+        Ok(reply & As5048::REPLY_VALUE_MASK)
+    }
+}
+
+impl AbsoluteEncoder for As5048 {
+    fn read_angle(&mut self) -> Result<f64, AbsoluteEncoderError> {
+        let counts: u16 = self.read_register(&As5048::COMMAND_READ_ANGLE)?;
+        Ok(counts as f64 * 360.0 / As5048::COUNTS_PER_REVOLUTION)
+    }
+}
@@ -0,0 +1,247 @@
+use std::fs;
+
+/// Header of the CSV files logged by Pid.
+pub const PID_LOG_HEADER: &str = "Time,Error,P,I-Accumulator,D,Output,Input,Target,Dt";
+/// Header of PID logs from before the time step was logged.
+const PID_LOG_HEADER_WITHOUT_DT: &str = "Time,Error,P,I-Accumulator,D,Output,Input,Target";
+
+/// Default settling band, as a percentage of the step size.
+pub const DEFAULT_SETTLING_BAND: f64 = 2.0;
+/// Fraction of a step's samples, at its end, averaged for the steady-state error.
+const STEADY_STATE_FRACTION: f64 = 0.1;
+
+/// One row of a PID log.
+#[derive(Clone, Copy, Debug)]
+pub struct PidLogRow {
+    /// Seconds since the first row of the log.
+    pub time: f64,
+    pub error: f64,
+    pub p: f64,
+    pub i_accumulator: f64,
+    pub d: f64,
+    pub output: f64,
+    pub input: f64,
+    pub target: f64,
+    /// Seconds since the previous update that the PID used, or None if the log is from before it was logged.
+    pub dt: Option<f64>
+}
+
+/// Read a PID log (see PID_LOG_HEADER), with or without the Dt column. Times are converted from milliseconds since the UNIX epoch to seconds since the first row.
+pub fn read_pid_log(path: &str) -> Result<Vec<PidLogRow>, String> {
+    let contents: String = fs::read_to_string(path).map_err(|error| format!("failed to read {}: {}", path, error))?;
+    let mut lines = contents.lines();
+    let columns: usize = match lines.next().map(|header| header.trim()) {
+        Some(PID_LOG_HEADER) => 9,
+        Some(PID_LOG_HEADER_WITHOUT_DT) => 8,
+        _ => return Err(format!("{} is not a PID log (expected the header {})", path, PID_LOG_HEADER))
+    };
+
+    let mut rows: Vec<PidLogRow> = Vec::new();
+    let mut first_time: Option<f64> = None;
+    for (index, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let values: Vec<f64> = match line.split(',').map(|value| value.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>() {
+            Ok(values) if values.len() == columns => values,
+            _ => return Err(format!("line {} of {} is malformed: {}", index + 2, path, line))
+        };
+        let time_ms: f64 = values[0];
+        let start: f64 = *first_time.get_or_insert(time_ms);
+        rows.push(PidLogRow { time: (time_ms - start) / 1000.0, error: values[1], p: values[2], i_accumulator: values[3], d: values[4], output: values[5], input: values[6], target: values[7], dt: values.get(8).copied() });
+    }
+    Ok(rows)
+}
+
+/// How the controlled value responded to one change of the target.
+#[derive(Clone, Copy, Debug)]
+pub struct StepResponse {
+    /// Time (in seconds since the start of the log) that the target changed.
+    pub start_time: f64,
+    /// Number of seconds until the next target change (or the end of the log).
+    pub duration: f64,
+    /// Value when the target changed.
+    pub initial: f64,
+    /// The new target.
+    pub target: f64,
+    /// Seconds taken to go from 10% to 90% of the step, or None if the value never got to 90%.
+    pub rise_time: Option<f64>,
+    /// How far the value went past the target, as a percentage of the step.
+    pub overshoot: f64,
+    /// Seconds until the value stayed within the settling band, or None if it never did.
+    pub settling_time: Option<f64>,
+    /// Mean error over the end of the step.
+    pub steady_state_error: f64,
+    /// Frequency (in Hz) of oscillation around the target, or None if it did not oscillate.
+    pub oscillation_frequency: Option<f64>
+}
+
+/// Measure the response to every target change in a PID log.
+///
+/// # Arguments
+///
+/// * `rows` - The log.
+///
+/// * `settling_band` - Settling band, as a percentage of the step size. Also used as hysteresis when counting oscillations.
+///
+/// * `min_step` - Target changes smaller than this are ignored (they are treated as part of the step before them).
+pub fn step_responses(rows: &[PidLogRow], settling_band: f64, min_step: f64) -> Vec<StepResponse> {
+    // Find where the target changes.
+    let mut starts: Vec<usize> = Vec::new();
+    for index in 1..rows.len() {
+        let reference: f64 = match starts.last() {
+            Some(&start) => rows[start].target,
+            None => rows[0].target
+        };
+        if rows[index].target != rows[index - 1].target && (rows[index].target - reference).abs() > min_step {
+            starts.push(index);
+        }
+    }
+
+    let mut responses: Vec<StepResponse> = Vec::new();
+    for (number, &start) in starts.iter().enumerate() {
+        let end: usize = if number + 1 < starts.len() { starts[number + 1] } else { rows.len() };
+        if let Some(response) = step_response(&rows[start - 1..end], settling_band) {
+            responses.push(response);
+        }
+    }
+    responses
+}
+
+/// Measure the response to one step. `rows[0]` is the last row before the target changed, and the rest run until the next change.
+fn step_response(rows: &[PidLogRow], settling_band: f64) -> Option<StepResponse> {
+    let initial: f64 = rows[0].input;
+    let start_time: f64 = rows[1].time;
+    let target: f64 = rows[rows.len() - 1].target;
+    let step: f64 = target - initial;
+    if step == 0.0 {
+        return None;
+    }
+    let rows: &[PidLogRow] = &rows[1..];
+    // Fraction of the way from the initial value to the target.
+    let fraction = |row: &PidLogRow| (row.input - initial) / step;
+    let band: f64 = settling_band / 100.0;
+
+    let rise_start: Option<f64> = rows.iter().find(|row| fraction(row) >= 0.1).map(|row| row.time);
+    let rise_end: Option<f64> = rows.iter().find(|row| fraction(row) >= 0.9).map(|row| row.time);
+    let rise_time: Option<f64> = match (rise_start, rise_end) {
+        (Some(rise_start), Some(rise_end)) => Some(rise_end - rise_start),
+        _ => None
+    };
+
+    let peak: f64 = rows.iter().map(|row| fraction(row)).fold(f64::MIN, f64::max);
+    let overshoot: f64 = ((peak - 1.0) * 100.0).max(0.0);
+
+    let settling_time: Option<f64> = match rows.iter().rposition(|row| (fraction(row) - 1.0).abs() > band) {
+        None => Some(0.0),
+        Some(last_outside) if last_outside + 1 < rows.len() => Some(rows[last_outside + 1].time - start_time),
+        Some(_) => None
+    };
+
+    let steady_state_count: usize = ((rows.len() as f64 * STEADY_STATE_FRACTION).ceil() as usize).max(1);
+    let steady_state_rows: &[PidLogRow] = &rows[rows.len() - steady_state_count..];
+    let steady_state_error: f64 = steady_state_rows.iter().map(|row| row.target - row.input).sum::<f64>() / steady_state_count as f64;
+
+    // Count crossings of the target, with hysteresis so that noise around the target is not counted.
+    let hysteresis: f64 = band * step.abs();
+    let mut side: i8 = 0;
+    let mut crossing_times: Vec<f64> = Vec::new();
+    for row in rows.iter() {
+        let error: f64 = row.input - row.target;
+        let new_side: i8 = if error > hysteresis { 1 } else if error < -hysteresis { -1 } else { side };
+        if side != 0 && new_side != side {
+            crossing_times.push(row.time);
+        }
+        side = new_side;
+    }
+    // Each full cycle crosses the target twice.
+    let oscillation_frequency: Option<f64> = if crossing_times.len() >= 3 {
+        let span: f64 = crossing_times[crossing_times.len() - 1] - crossing_times[0];
+        if span > 0.0 { Some((crossing_times.len() - 1) as f64 / 2.0 / span) } else { None }
+    }
+    else {
+        None
+    };
+
+    Some(StepResponse {
+        start_time: start_time,
+        duration: rows[rows.len() - 1].time - start_time,
+        initial: initial,
+        target: target,
+        rise_time: rise_time,
+        overshoot: overshoot,
+        settling_time: settling_time,
+        steady_state_error: steady_state_error,
+        oscillation_frequency: oscillation_frequency
+    })
+}
+
+/// Format an optional measurement, or "n/a" if there is none.
+fn format_option(value: Option<f64>, unit: &str) -> String {
+    match value {
+        Some(value) => format!("{:.3} {}", value, unit),
+        None => "n/a".to_string()
+    }
+}
+
+/// Run the analyze subcommand: `analyze <log.csv> [<settling band %>] [<min step>]`.
+pub fn run(args: &[String]) {
+    let path: &str = match args.get(0) {
+        Some(path) => path,
+        None => {
+            println!("Analysis: ERROR, no log file given.");
+            return;
+        }
+    };
+    let settling_band: f64 = match args.get(1).map(|arg| arg.parse::<f64>()) {
+        None => DEFAULT_SETTLING_BAND,
+        Some(Ok(settling_band)) if settling_band > 0.0 => settling_band,
+        Some(_) => {
+            println!("Analysis: ERROR, the settling band must be a positive percentage.");
+            return;
+        }
+    };
+    let min_step: f64 = match args.get(2).map(|arg| arg.parse::<f64>()) {
+        None => 0.0,
+        Some(Ok(min_step)) if min_step >= 0.0 => min_step,
+        Some(_) => {
+            println!("Analysis: ERROR, the minimum step must be a number of at least 0.");
+            return;
+        }
+    };
+
+    let rows: Vec<PidLogRow> = match read_pid_log(path) {
+        Ok(rows) => rows,
+        Err(error) => {
+            println!("Analysis: ERROR, {}.", error);
+            return;
+        }
+    };
+    print_step_responses(path, &rows, settling_band, min_step);
+}
+
+/// Print the step response metrics of each setpoint change in a PID log.
+///
+/// # Arguments
+///
+/// * `name` - Name of the log, e.g. its path.
+///
+/// * `rows` - The log.
+///
+/// * `settling_band` - Settling band, as a percentage of the step size.
+///
+/// * `min_step` - Target changes smaller than this are ignored.
+pub fn print_step_responses(name: &str, rows: &[PidLogRow], settling_band: f64, min_step: f64) {
+    let responses: Vec<StepResponse> = step_responses(rows, settling_band, min_step);
+    println!("{}: {} rows over {:.2} s, {} setpoint changes.", name, rows.len(), rows.last().map(|row| row.time).unwrap_or(0.0), responses.len());
+
+    for (number, response) in responses.iter().enumerate() {
+        println!();
+        println!("Step {} at {:.3} s: {:.5} -> {:.5} (step {:.5}, held for {:.3} s)", number + 1, response.start_time, response.initial, response.target, response.target - response.initial, response.duration);
+        println!("    {:<25}{}", "Rise time (10-90%):", format_option(response.rise_time, "s"));
+        println!("    {:<25}{:.1} %", "Overshoot:", response.overshoot);
+        println!("    {:<25}{}", format!("Settling time ({}%):", settling_band), format_option(response.settling_time, "s"));
+        println!("    {:<25}{:.5}", "Steady-state error:", response.steady_state_error);
+        println!("    {:<25}{}", "Oscillation frequency:", format_option(response.oscillation_frequency, "Hz"));
+    }
+}
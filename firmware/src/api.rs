@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use gpredict::Location;
+use crate::http::{ Request, Response };
+use crate::json::{ self, Value };
+use crate::state::{ RotatorState, Mode, Satellite };
+use crate::tracking;
+
+/// Number of passes listed when the request does not say.
+const DEFAULT_PASS_COUNT: usize = 5;
+/// Longest time (in seconds) that a jog with a timeout may last.
+const MAX_JOG_TIMEOUT: f64 = 10.0;
+
+/// JSON control API for the rotator, so that it can be driven from a phone browser or a script.
+///
+/// * `GET /api/status` - Everything below in one object.
+///
+/// * `GET /api/position` - Where the antenna points, as {"altitude": a, "azimuth": z}.
+///
+/// * `GET /api/target`, `PUT /api/target` - Where the antenna is going ({"altitude": a, "azimuth": z}, either may be left out). Setting it switches to goto mode.
+///
+/// * `GET /api/mode`, `PUT /api/mode` - What is deciding where the antenna points ({"mode": "stop" | "manual" | "goto" | "track" | "park"}).
+///
+/// * `PUT /api/jog` - Move the axes at speeds in degrees per second, up to MAX_JOG_SPEED either way ({"altitude_speed": a, "azimuth_speed": z}, either may be left out). Switches to manual mode. With "timeout": t, the axes stop after t seconds unless the jog is sent again, so that a client that goes away does not leave the rotator moving.
+///
+/// * `GET /api/satellite`, `PUT /api/satellite` - Satellite that track mode follows ({"name": n, "tle_file": f}, the file may be left out to keep the current one).
+///
+/// * `GET /api/passes?count=n` - Upcoming passes of the satellite.
+///
+/// * `GET /api/pass_track` - Path of the satellite across the sky during its current or next pass, as {"aos": t, "los": t, "points": [[azimuth, elevation], ...]} (null if it never rises).
+///
+/// * `GET /api/faults` - Hardware faults, and the readings behind them.
+///
+/// POST is accepted everywhere that PUT is, for clients that cannot send PUT. Either must carry a
+/// JSON body with `Content-Type: application/json`, which a web page on another site cannot send
+/// without the browser asking first, so that such a page cannot drive the rotator.
+pub struct Api {
+    state: Arc<RotatorState>,
+    /// Where the rotator is, for predicting passes.
+    location: &'static Location,
+    /// Angles (in degrees) that park mode goes to.
+    home_altitude: f64,
+    home_azimuth: f64
+}
+
+impl Api {
+    /// Create the API.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The rotator state that requests act on.
+    ///
+    /// * `location` - Where the rotator is.
+    ///
+    /// * `home_altitude` - Altitude (in degrees) that park mode goes to.
+    ///
+    /// * `home_azimuth` - Azimuth (in degrees) that park mode goes to.
+    pub fn new(state: Arc<RotatorState>, location: &'static Location, home_altitude: f64, home_azimuth: f64) -> Api {
+        Api { state: state, location: location, home_altitude: home_altitude, home_azimuth: home_azimuth }
+    }
+
+    /// Answer a request. Returns None if the path is not part of the API.
+    pub fn handle(&self, request: &Request) -> Option<Response> {
+        if !request.path.starts_with("/api/") {
+            return None;
+        }
+        let writing: bool = request.method == "PUT" || request.method == "POST";
+        if request.method != "GET" && !writing {
+            return Some(Response::error(405, "Only GET, PUT and POST are supported."));
+        }
+        let body: HashMap<String, Value> = if writing {
+            let content_type: &str = request.headers.get("content-type").map_or("", |content_type| content_type.split(';').next().unwrap().trim());
+            if !content_type.eq_ignore_ascii_case("application/json") {
+                return Some(Response::error(415, "The body must be sent as application/json."));
+            }
+            match json::parse_object(&request.body) {
+                Ok(body) => body,
+                Err(error) => return Some(Response::error(400, &format!("The body must be a JSON object: {}.", error)))
+            }
+        }
+        else {
+            HashMap::new()
+        };
+
+        let result: Result<String, String> = match (request.path.as_str(), writing) {
+            ("/api/status", false) => Ok(self.status()),
+            ("/api/position", false) => Ok(self.position()),
+            ("/api/target", false) => Ok(self.target()),
+            ("/api/target", true) => self.set_target(&body).map(|_| self.target()),
+            ("/api/mode", false) => Ok(self.mode()),
+            ("/api/mode", true) => self.set_mode(&body).map(|_| self.mode()),
+            ("/api/jog", true) => self.jog(&body).map(|_| self.mode()),
+            ("/api/satellite", false) => Ok(self.satellite()),
+            ("/api/satellite", true) => self.set_satellite(&body).map(|_| self.satellite()),
+            ("/api/passes", false) => {
+                let count: usize = request.query.get("count").and_then(|count| count.parse::<usize>().ok()).unwrap_or(DEFAULT_PASS_COUNT);
+                self.passes(count)
+            }
+            ("/api/pass_track", false) => self.pass_track(),
+            ("/api/faults", false) => Ok(self.faults()),
+            ("/api/position", true) | ("/api/status", true) | ("/api/passes", true) | ("/api/pass_track", true) | ("/api/faults", true) | ("/api/jog", false) => return Some(Response::error(405, "Method not allowed.")),
+            _ => return Some(Response::error(404, "No such endpoint."))
+        };
+        Some(match result {
+            Ok(body) => Response::json(200, body),
+            Err(error) => Response::error(400, &error)
+        })
+    }
+
+    /// Get everything that GET /api/status returns, as a JSON object.
+    pub fn status(&self) -> String {
+        format!("{{\"position\":{},\"target\":{},\"mode\":{},\"satellite\":{},\"faults\":{}}}", self.position(), self.target(), json::string(self.state.get_mode().name()), self.satellite(), self.faults())
+    }
+
+    fn position(&self) -> String {
+        format!("{{\"altitude\":{},\"azimuth\":{}}}", json::number(self.state.altitude.load(Ordering::Relaxed)), json::number(self.state.azimuth.load(Ordering::Relaxed)))
+    }
+
+    /// The target is null when there is none (while stopped or jogging).
+    fn target(&self) -> String {
+        let (altitude, azimuth) = match self.state.get_mode() {
+            Mode::Stop | Mode::Manual => return "null".to_string(),
+            Mode::Park => (self.home_altitude, self.home_azimuth),
+            Mode::Goto | Mode::Track => (self.state.target_altitude.load(Ordering::Relaxed), self.state.target_azimuth.load(Ordering::Relaxed))
+        };
+        format!("{{\"altitude\":{},\"azimuth\":{}}}", json::number(altitude), json::number(azimuth))
+    }
+
+    fn set_target(&self, body: &HashMap<String, Value>) -> Result<(), String> {
+        match (number_field(body, "altitude")?, number_field(body, "azimuth")?) {
+            (Some(altitude), Some(azimuth)) => self.state.goto(altitude, azimuth),
+            (Some(altitude), None) => self.state.goto_altitude(altitude),
+            (None, Some(azimuth)) => self.state.goto_azimuth(azimuth),
+            (None, None) => Err("Give an altitude, an azimuth, or both.".to_string())
+        }
+    }
+
+    fn mode(&self) -> String {
+        let (altitude_speed, azimuth_speed) = self.state.get_jog_speeds();
+        format!("{{\"mode\":{},\"altitude_speed\":{},\"azimuth_speed\":{}}}", json::string(self.state.get_mode().name()), json::number(altitude_speed), json::number(azimuth_speed))
+    }
+
+    fn set_mode(&self, body: &HashMap<String, Value>) -> Result<(), String> {
+        let name: &str = body.get("mode").and_then(|mode| mode.as_str()).ok_or("Give the mode as a string.")?;
+        match Mode::from_name(name) {
+            // Manual mode starts with the axes still, until a jog sets their speeds.
+            Some(Mode::Manual) => self.state.jog(0.0, 0.0)?,
+            // Goto mode holds the target of the last goto.
+            Some(mode) => self.state.set_mode(mode),
+            None => return Err(format!("There is no mode called \"{}\". Modes are stop, manual, goto, track and park.", name))
+        }
+        Ok(())
+    }
+
+    fn jog(&self, body: &HashMap<String, Value>) -> Result<(), String> {
+        let timeout: Option<f64> = number_field(body, "timeout")?;
+        if let Some(timeout) = timeout {
+            if !(timeout > 0.0 && timeout <= MAX_JOG_TIMEOUT) {
+                return Err(format!("timeout must be more than 0 and at most {} seconds.", MAX_JOG_TIMEOUT));
+            }
+        }
+        let (altitude_speed, azimuth_speed) = match (number_field(body, "altitude_speed")?, number_field(body, "azimuth_speed")?) {
+            (None, None) => return Err("Give an altitude_speed, an azimuth_speed, or both.".to_string()),
+            (altitude_speed, azimuth_speed) => {
+                // An axis that is left out carries on as it was.
+                let (current_altitude_speed, current_azimuth_speed) = if self.state.get_mode() == Mode::Manual { self.state.get_jog_speeds() } else { (0.0, 0.0) };
+                (altitude_speed.unwrap_or(current_altitude_speed), azimuth_speed.unwrap_or(current_azimuth_speed))
+            }
+        };
+        match timeout {
+            Some(timeout) => self.state.jog_for(altitude_speed, azimuth_speed, Duration::from_secs_f64(timeout)),
+            None => self.state.jog(altitude_speed, azimuth_speed)
+        }
+    }
+
+    fn satellite(&self) -> String {
+        let satellite: Satellite = self.state.get_satellite();
+        format!("{{\"name\":{},\"tle_file\":{}}}", json::string(&satellite.name), json::string(&satellite.tle_file))
+    }
+
+    fn set_satellite(&self, body: &HashMap<String, Value>) -> Result<(), String> {
+        let name: &str = body.get("name").and_then(|name| name.as_str()).ok_or("Give the satellite name as a string.")?;
+        let tle_file: String = match body.get("tle_file") {
+            None => self.state.get_satellite().tle_file,
+            Some(Value::String(tle_file)) => tle_file.clone(),
+            Some(_) => return Err("Give the TLE file as a string.".to_string())
+        };
+        // Only TLE files next to the firmware can be chosen, so that the API cannot be used to poke around the file system.
+        if tle_file.contains('/') || tle_file.starts_with('.') {
+            return Err("The TLE file must be a file name in the firmware's directory.".to_string());
+        }
+        let satellite = Satellite { name: name.to_string(), tle_file: tle_file };
+        tracking::load(&satellite, self.location)?;
+        self.state.set_satellite(satellite);
+        Ok(())
+    }
+
+    fn passes(&self, count: usize) -> Result<String, String> {
+        let mut predict = tracking::load(&self.state.get_satellite(), self.location)?;
+        let passes: Vec<String> = tracking::passes(&mut predict, time::now_utc(), count).iter().map(|pass| {
+            format!("{{\"aos\":{},\"los\":{},\"duration\":{},\"max_elevation\":{}}}", json::string(&pass.aos.rfc3339().to_string()), json::string(&pass.los.rfc3339().to_string()), (pass.los - pass.aos).num_seconds(), json::number(pass.max_elevation))
+        }).collect();
+        Ok(format!("[{}]", passes.join(",")))
+    }
+
+    fn pass_track(&self) -> Result<String, String> {
+        let mut predict = tracking::load(&self.state.get_satellite(), self.location)?;
+        let pass = match tracking::passes(&mut predict, time::now_utc(), 1).pop() {
+            Some(pass) => pass,
+            None => return Ok("null".to_string())
+        };
+        let points: Vec<String> = tracking::pass_track(&mut predict, &pass).iter().map(|&(_, azimuth, elevation)| format!("[{},{}]", json::number(azimuth), json::number(elevation))).collect();
+        Ok(format!("{{\"aos\":{},\"los\":{},\"points\":[{}]}}", json::string(&pass.aos.rfc3339().to_string()), json::string(&pass.los.rfc3339().to_string()), points.join(",")))
+    }
+
+    fn faults(&self) -> String {
+        let faults = &self.state.faults;
+        let active: Vec<String> = faults.active().iter().map(|fault| json::string(fault)).collect();
+        format!("{{\"active\":[{}],\"battery_voltage\":{},\"altitude_encoder_errors\":{},\"azimuth_encoder_errors\":{},\"i2c_errors\":{},\"altitude_slip_events\":{},\"azimuth_slip_events\":{},\"altitude_encoder_discrepancy\":{},\"azimuth_encoder_discrepancy\":{},\"altitude_slip\":{},\"azimuth_slip\":{},\"altitude_absolute_encoder_failing\":{},\"azimuth_absolute_encoder_failing\":{}}}",
+            active.join(","),
+            json::number(faults.battery_voltage.load(Ordering::Relaxed)),
+            faults.altitude_encoder_errors.load(Ordering::Relaxed),
+            faults.azimuth_encoder_errors.load(Ordering::Relaxed),
+            faults.i2c_errors.load(Ordering::Relaxed),
+            faults.altitude_slip_events.load(Ordering::Relaxed),
+            faults.azimuth_slip_events.load(Ordering::Relaxed),
+            json::number(faults.altitude_encoder_discrepancy.load(Ordering::Relaxed)),
+            json::number(faults.azimuth_encoder_discrepancy.load(Ordering::Relaxed)),
+            json::number(faults.altitude_slip.load(Ordering::Relaxed)),
+            json::number(faults.azimuth_slip.load(Ordering::Relaxed)),
+            faults.altitude_absolute_encoder_failing.load(Ordering::Relaxed),
+            faults.azimuth_absolute_encoder_failing.load(Ordering::Relaxed))
+    }
+}
+
+/// Get an optional number from a request body. Returns an error if it is there but is not a finite number.
+fn number_field(body: &HashMap<String, Value>, name: &str) -> Result<Option<f64>, String> {
+    match body.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => match value.as_f64() {
+            Some(number) if number.is_finite() => Ok(Some(number)),
+            _ => Err(format!("{} must be a number.", name))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LOCATION: Location = Location { lat_deg: 37.649250, lon_deg: -121.875070, alt_m: 105.0 };
+
+    /// Create an API for a new, stopped rotator. Returns the API and the rotator state.
+    fn new_api() -> (Api, Arc<RotatorState>) {
+        let state = Arc::new(RotatorState::new(45.0, 90.0, Satellite { name: "ISS".to_string(), tle_file: "iss.tle".to_string() }));
+        (Api::new(Arc::clone(&state), &LOCATION, 0.0, 180.0), state)
+    }
+
+    fn request(method: &str, path: &str, content_type: Option<&str>, body: &str) -> Request {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert("content-type".to_string(), content_type.to_string());
+        }
+        Request { method: method.to_string(), path: path.to_string(), query: HashMap::new(), headers: headers, body: body.to_string() }
+    }
+
+    /// Send a request and get back the status and body of the reply.
+    fn send(api: &Api, request: Request) -> (u16, String) {
+        let response: Response = api.handle(&request).expect("the path is part of the API");
+        (response.status, String::from_utf8(response.body).unwrap())
+    }
+
+    fn put(api: &Api, path: &str, body: &str) -> (u16, String) {
+        send(api, request("PUT", path, Some("application/json"), body))
+    }
+
+    #[test]
+    fn leaves_other_paths_alone() {
+        let (api, _) = new_api();
+        assert!(api.handle(&request("GET", "/", None, "")).is_none());
+        assert!(api.handle(&request("GET", "/metrics", None, "")).is_none());
+    }
+
+    #[test]
+    fn reads_the_position_and_mode() {
+        let (api, _) = new_api();
+        assert_eq!(send(&api, request("GET", "/api/position", None, "")), (200, "{\"altitude\":45,\"azimuth\":90}".to_string()));
+        assert_eq!(send(&api, request("GET", "/api/target", None, "")), (200, "null".to_string()));
+        assert_eq!(send(&api, request("GET", "/api/mode", None, "")), (200, "{\"mode\":\"stop\",\"altitude_speed\":0,\"azimuth_speed\":0}".to_string()));
+        let (status, body) = send(&api, request("GET", "/api/status", None, ""));
+        assert_eq!(status, 200);
+        assert!(body.starts_with("{\"position\":{\"altitude\":45,\"azimuth\":90},\"target\":null,\"mode\":\"stop\""));
+    }
+
+    #[test]
+    fn sets_the_target_and_mode() {
+        let (api, state) = new_api();
+        assert_eq!(put(&api, "/api/target", "{\"altitude\": 30, \"azimuth\": 120}"), (200, "{\"altitude\":30,\"azimuth\":120}".to_string()));
+        assert_eq!(state.get_mode(), Mode::Goto);
+        let (status, _) = send(&api, request("POST", "/api/mode", Some("application/json; charset=utf-8"), "{\"mode\": \"park\"}"));
+        assert_eq!(status, 200);
+        assert_eq!(state.get_mode(), Mode::Park);
+        assert_eq!(send(&api, request("GET", "/api/target", None, "")), (200, "{\"altitude\":0,\"azimuth\":180}".to_string()));
+    }
+
+    #[test]
+    fn rejects_bad_bodies() {
+        let (api, state) = new_api();
+        assert_eq!(put(&api, "/api/target", "{}").0, 400);
+        assert_eq!(put(&api, "/api/target", "{\"altitude\": \"high\"}").0, 400);
+        assert_eq!(put(&api, "/api/target", "altitude=30").0, 400);
+        assert_eq!(put(&api, "/api/mode", "{\"mode\": \"spin\"}").0, 400);
+        assert_eq!(put(&api, "/api/jog", "{\"altitude_speed\": 1, \"timeout\": 0}").0, 400);
+        assert_eq!(put(&api, "/api/satellite", "{\"name\": \"ISS\", \"tle_file\": \"../etc/passwd\"}").0, 400);
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn requires_json_to_write() {
+        let (api, state) = new_api();
+        assert_eq!(send(&api, request("PUT", "/api/mode", None, "{\"mode\": \"park\"}")).0, 415);
+        assert_eq!(send(&api, request("POST", "/api/mode", Some("text/plain"), "{\"mode\": \"park\"}")).0, 415);
+        assert_eq!(send(&api, request("POST", "/api/mode", Some("application/x-www-form-urlencoded"), "mode=park")).0, 415);
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn rejects_unknown_methods_and_paths() {
+        let (api, _) = new_api();
+        assert_eq!(send(&api, request("DELETE", "/api/target", None, "")).0, 405);
+        assert_eq!(put(&api, "/api/position", "{}").0, 405);
+        assert_eq!(put(&api, "/api/faults", "{}").0, 405);
+        assert_eq!(send(&api, request("GET", "/api/jog", None, "")).0, 405);
+        assert_eq!(send(&api, request("GET", "/api/nothing", None, "")).0, 404);
+        assert_eq!(put(&api, "/api/nothing", "{}").0, 404);
+    }
+}
@@ -0,0 +1,265 @@
+use std::f64::consts::PI;
+use std::time::{ Duration, Instant };
+use std::thread;
+use crate::config::Config;
+use crate::motors::Motors;
+
+/// Number of relay oscillations to measure (after the first, which is discarded while the oscillation settles).
+const AUTOTUNE_CYCLES: usize = 4;
+/// Give up if the oscillation has not finished after this many seconds.
+const AUTOTUNE_TIMEOUT: f64 = 60.0;
+
+/// Motor power level held while the speed loop's operating point is measured, and the relay swing around it.
+const SPEED_AUTOTUNE_BIAS: f64 = 0.5;
+const SPEED_AUTOTUNE_AMPLITUDE: f64 = 0.2;
+/// Relay hysteresis for the speed loop, in revolutions per second.
+const SPEED_AUTOTUNE_HYSTERESIS: f64 = 0.02;
+/// Number of seconds to hold the bias power before the relay starts.
+const SPEED_AUTOTUNE_SETTLE_TIME: f64 = 2.0;
+
+/// Target speed swing (in revolutions per second) that the relay applies to the position loop.
+const POSITION_AUTOTUNE_AMPLITUDE: f64 = 0.3;
+/// Relay hysteresis for the position loop, in revolutions of the driving motor.
+const POSITION_AUTOTUNE_HYSTERESIS: f64 = 0.01;
+
+/// Ultimate gain and period measured by a relay autotune.
+#[derive(Clone, Copy, Debug)]
+pub struct AutotuneResult {
+    /// Proportional gain at which the loop would oscillate steadily.
+    pub ultimate_gain: f64,
+    /// Period (in seconds) of that oscillation.
+    pub ultimate_period: f64,
+    /// -1.0 if the output must decrease to make the value increase, 1.0 otherwise.
+    pub direction: f64
+}
+
+impl AutotuneResult {
+    /// Suggested (p, i, d) gains in the physical units used by Pid.
+    ///
+    /// These use the Tyreus-Luyben rules rather than Ziegler-Nichols, since they overshoot far less,
+    /// which matters more for pointing an antenna than a fast rise time.
+    pub fn pid_gains(&self) -> (f64, f64, f64) {
+        let p: f64 = self.ultimate_gain / 2.2;
+        let integral_time: f64 = 2.2 * self.ultimate_period;
+        let derivative_time: f64 = self.ultimate_period / 6.3;
+        (self.direction * p, self.direction * p / integral_time, self.direction * p * derivative_time)
+    }
+}
+
+/// Relay-feedback (Astrom-Hagglund) autotuner. Instead of a PID, a relay drives the loop, which
+/// makes it oscillate at its ultimate period. The size of that oscillation gives the ultimate gain.
+pub struct RelayAutotuner {
+    /// Value that the relay switches around.
+    setpoint: f64,
+    /// Output level in the middle of the relay swing.
+    bias: f64,
+    /// How far the relay swings the output either side of bias.
+    amplitude: f64,
+    /// The value must cross this far past the setpoint before the relay switches, so that noise does not make it chatter.
+    hysteresis: f64,
+    /// -1.0 if the output must decrease to make the value increase, 1.0 otherwise.
+    direction: f64,
+    /// True while the relay is pushing the value up.
+    relay_high: bool,
+    /// Times (in seconds) that the relay switched to pushing the value up.
+    switch_times: Vec<f64>,
+    /// Half of the peak-to-peak swing of the value in each complete oscillation.
+    amplitudes: Vec<f64>,
+    /// Largest value seen in the current oscillation.
+    cycle_max: f64,
+    /// Smallest value seen in the current oscillation.
+    cycle_min: f64
+}
+
+impl RelayAutotuner {
+    /// Create a relay autotuner.
+    ///
+    /// # Arguments
+    ///
+    /// * `setpoint` - Value that the relay switches around.
+    ///
+    /// * `bias` - Output level in the middle of the relay swing.
+    ///
+    /// * `amplitude` - How far the relay swings the output either side of bias.
+    ///
+    /// * `hysteresis` - How far past the setpoint the value must go before the relay switches.
+    ///
+    /// * `direction` - -1.0 if the output must decrease to make the value increase, 1.0 otherwise.
+    pub fn new(setpoint: f64, bias: f64, amplitude: f64, hysteresis: f64, direction: f64) -> RelayAutotuner {
+        RelayAutotuner { setpoint: setpoint, bias: bias, amplitude: amplitude, hysteresis: hysteresis, direction: direction, relay_high: true, switch_times: Vec::new(), amplitudes: Vec::new(), cycle_max: f64::MIN, cycle_min: f64::MAX }
+    }
+
+    /// Update the relay.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Current value of the controlled quantity.
+    ///
+    /// * `time` - Current time in seconds (from any fixed starting point).
+    ///
+    /// Returns the output that should be applied.
+    pub fn update(&mut self, value: f64, time: f64) -> f64 {
+        self.cycle_max = self.cycle_max.max(value);
+        self.cycle_min = self.cycle_min.min(value);
+
+        let error: f64 = self.setpoint - value;
+        if self.relay_high && error < -self.hysteresis {
+            self.relay_high = false;
+        }
+        else if !self.relay_high && error > self.hysteresis {
+            self.relay_high = true;
+            if !self.switch_times.is_empty() {
+                self.amplitudes.push((self.cycle_max - self.cycle_min) / 2.0);
+            }
+            self.switch_times.push(time);
+            self.cycle_max = value;
+            self.cycle_min = value;
+        }
+
+        let swing: f64 = if self.relay_high { self.amplitude } else { -self.amplitude };
+        self.bias + self.direction * swing
+    }
+
+    /// Returns true once enough oscillations have been measured.
+    pub fn is_finished(&self) -> bool {
+        self.amplitudes.len() > AUTOTUNE_CYCLES
+    }
+
+    /// Get the measured ultimate gain and period, or None if not enough oscillations have been measured.
+    pub fn result(&self) -> Option<AutotuneResult> {
+        if !self.is_finished() {
+            return None;
+        }
+
+        // The first oscillation is skipped, since the loop has not settled into its limit cycle yet.
+        let periods: Vec<f64> = self.switch_times.windows(2).skip(1).map(|pair| pair[1] - pair[0]).collect();
+        let ultimate_period: f64 = periods.iter().sum::<f64>() / periods.len() as f64;
+        let amplitudes: &[f64] = &self.amplitudes[1..];
+        let amplitude: f64 = amplitudes.iter().sum::<f64>() / amplitudes.len() as f64;
+        if amplitude <= self.hysteresis {
+            return None;
+        }
+
+        let ultimate_gain: f64 = 4.0 * self.amplitude / (PI * (amplitude * amplitude - self.hysteresis * self.hysteresis).sqrt());
+        Some(AutotuneResult { ultimate_gain: ultimate_gain, ultimate_period: ultimate_period, direction: self.direction })
+    }
+}
+
+/// Run the relay until it has finished or timed out.
+///
+/// # Arguments
+///
+/// * `autotuner` - The relay autotuner.
+///
+/// * `period` - Time between updates.
+///
+/// * `step` - Called with each new relay output. Returns the resulting value of the controlled quantity.
+fn run_relay<F: FnMut(f64) -> f64>(autotuner: &mut RelayAutotuner, period: Duration, mut step: F) -> Option<AutotuneResult> {
+    let start = Instant::now();
+    let mut output: f64 = autotuner.update(autotuner.setpoint, 0.0);
+    while !autotuner.is_finished() {
+        let time: f64 = start.elapsed().as_secs_f64();
+        if time > AUTOTUNE_TIMEOUT {
+            println!("Autotune: ERROR, no steady oscillation after {} seconds.", AUTOTUNE_TIMEOUT);
+            return None;
+        }
+        let value: f64 = step(output);
+        output = autotuner.update(value, time);
+        thread::sleep(period);
+    }
+    autotuner.result()
+}
+
+/// Save a result to the config under the name of the PID it was measured for.
+fn save_result(config: &mut Config, pid_name: &str, result: Option<AutotuneResult>) {
+    match result {
+        Some(result) => {
+            let gains = result.pid_gains();
+            println!("Autotune: {} ultimate gain {:.4}, ultimate period {:.3} s.", pid_name, result.ultimate_gain, result.ultimate_period);
+            println!("Autotune: Suggested gains for {}: p = {:.5}, i = {:.5}, d = {:.5}.", pid_name, gains.0, gains.1, gains.2);
+            config.set_pid_gains(pid_name, gains);
+            match config.save() {
+                Ok(()) => println!("Autotune: Saved gains to the config file."),
+                Err(error) => println!("Autotune: ERROR, failed to save the config file: {}.", error)
+            }
+        }
+        None => {
+            println!("Autotune: Failed to tune {}, leaving its gains unchanged.", pid_name);
+        }
+    }
+}
+
+/// Autotune the speed PID of one motor by driving its power directly with a relay.
+///
+/// # Arguments
+///
+/// * `motors` - The motors. The other motor is left stopped.
+///
+/// * `motor` - Which motor to tune (1 or 2).
+///
+/// * `config` - Config that the suggested gains are written to.
+pub fn autotune_speed(motors: &mut Motors, motor: u8, config: &mut Config) {
+    let period = Duration::from_millis(5);
+    let set_power = |motors: &mut Motors, power: Option<f64>| if motor == 1 { motors.set_power_override_1(power) } else { motors.set_power_override_2(power) };
+    let get_speed = |motors: &mut Motors| if motor == 1 { motors.get_speed_1() } else { motors.get_speed_2() };
+
+    // Hold the bias power to find the speed that the relay should switch around, and which way the motor turns.
+    println!("Autotune: Measuring the operating point of motor {}.", motor);
+    set_power(motors, Some(SPEED_AUTOTUNE_BIAS));
+    let start = Instant::now();
+    let mut speed_total: f64 = 0.0;
+    let mut speed_samples: u32 = 0;
+    while start.elapsed().as_secs_f64() < SPEED_AUTOTUNE_SETTLE_TIME {
+        if start.elapsed().as_secs_f64() > SPEED_AUTOTUNE_SETTLE_TIME / 2.0 {
+            speed_total += get_speed(motors);
+            speed_samples += 1;
+        }
+        thread::sleep(period);
+    }
+    let setpoint: f64 = speed_total / speed_samples.max(1) as f64;
+    let direction: f64 = if setpoint < 0.0 { -1.0 } else { 1.0 };
+
+    println!("Autotune: Relay testing motor {} around {:.3} rev/s.", motor, setpoint);
+    let mut autotuner = RelayAutotuner::new(setpoint, SPEED_AUTOTUNE_BIAS, SPEED_AUTOTUNE_AMPLITUDE, SPEED_AUTOTUNE_HYSTERESIS, direction);
+    let result = run_relay(&mut autotuner, period, |power| {
+        set_power(motors, Some(power));
+        get_speed(motors)
+    });
+    set_power(motors, Some(0.0));
+    thread::sleep(Duration::from_millis(500));
+    set_power(motors, None);
+
+    save_result(config, &format!("motor_{}_speed", motor), result);
+}
+
+/// Autotune the position PID of one axis by driving its target speed with a relay. The axis oscillates around where it currently is.
+///
+/// # Arguments
+///
+/// * `motors` - The motors. The other motor is left stopped.
+///
+/// * `motor` - Which motor drives the axis (1 for altitude, 2 for azimuth).
+///
+/// * `pid_name` - Name of the axis' position PID in the config.
+///
+/// * `config` - Config that the suggested gains are written to.
+pub fn autotune_position(motors: &mut Motors, motor: u8, pid_name: &str, config: &mut Config) {
+    let period = Duration::from_millis(10);
+    let set_speed = |motors: &mut Motors, speed: f64| if motor == 1 { motors.set_target_speed_1(speed) } else { motors.set_target_speed_2(speed) };
+    let get_revs = |motors: &mut Motors| if motor == 1 { motors.get_revs_1() } else { motors.get_revs_2() };
+
+    // The position PID's gains have the same sign as the relay needs.
+    let direction: f64 = if config.get_pid_gains(pid_name, (1.0, 0.0, 0.0)).0 < 0.0 { -1.0 } else { 1.0 };
+    let setpoint: f64 = get_revs(motors);
+
+    println!("Autotune: Relay testing {} around {:.3} revs.", pid_name, setpoint);
+    let mut autotuner = RelayAutotuner::new(setpoint, 0.0, POSITION_AUTOTUNE_AMPLITUDE, POSITION_AUTOTUNE_HYSTERESIS, direction);
+    let result = run_relay(&mut autotuner, period, |speed| {
+        set_speed(motors, speed);
+        get_revs(motors)
+    });
+    set_speed(motors, 0.0);
+    thread::sleep(Duration::from_millis(500));
+
+    save_result(config, pid_name, result);
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use crate::pid::PidParameters;
+
+/// Settings stored in a simple `key = value` text file, one setting per line. Blank lines and lines starting with `#` are ignored.
+pub struct Config {
+    /// Path of the file that the settings were loaded from and are saved to.
+    path: String,
+    /// Settings by key.
+    values: BTreeMap<String, String>
+}
+
+impl Config {
+    /// Load settings from a file. A missing file gives an empty configuration, so that every setting uses its default.
+    pub fn load(path: &str) -> Config {
+        let mut values: BTreeMap<String, String> = BTreeMap::new();
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let line = line.trim();
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    match line.find('=') {
+                        Some(index) => {
+                            values.insert(line[..index].trim().to_string(), line[index + 1..].trim().to_string());
+                        }
+                        None => {
+                            println!("Config: Ignoring malformed line in {}: {}", path, line);
+                        }
+                    }
+                }
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => {
+                println!("Config: ERROR, failed to read {}: {}. Using defaults.", path, error);
+            }
+        }
+        Config { path: path.to_string(), values: values }
+    }
+
+    /// Write the settings back to the file that they were loaded from.
+    pub fn save(&self) -> io::Result<()> {
+        let mut contents = String::new();
+        for (key, value) in self.values.iter() {
+            contents.push_str(&format!("{} = {}\n", key, value));
+        }
+        fs::write(&self.path, contents)
+    }
+
+    /// Get a text setting, or `default` if the setting is missing.
+    pub fn get_str<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        match self.values.get(key) {
+            Some(value) => value,
+            None => default
+        }
+    }
+
+    /// Get a number, or `default` if the setting is missing or is not a finite number.
+    pub fn get_f64(&self, key: &str, default: f64) -> f64 {
+        match self.values.get(key) {
+            Some(value) => match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => number,
+                _ => {
+                    println!("Config: ERROR, {} is not a finite number ({}). Using {}.", key, value, default);
+                    default
+                }
+            },
+            None => default
+        }
+    }
+
+    /// Set a number.
+    pub fn set_f64(&mut self, key: &str, value: f64) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// Get the (p, i, d) gains of the PID called `name`, or `default` for any gain that is not set.
+    pub fn get_pid_gains(&self, name: &str, default: (f64, f64, f64)) -> (f64, f64, f64) {
+        (self.get_f64(&format!("{}.p", name), default.0), self.get_f64(&format!("{}.i", name), default.1), self.get_f64(&format!("{}.d", name), default.2))
+    }
+
+    /// Set the (p, i, d) gains of the PID called `name`.
+    pub fn set_pid_gains(&mut self, name: &str, gains: (f64, f64, f64)) {
+        self.set_f64(&format!("{}.p", name), gains.0);
+        self.set_f64(&format!("{}.i", name), gains.1);
+        self.set_f64(&format!("{}.d", name), gains.2);
+    }
+
+    /// Get all of the settings of the PID called `name`, or the setting from `default` for any that is
+    /// not set or is not valid. If the settings do not make sense together, all of `default` is used.
+    pub fn get_pid_parameters(&self, name: &str, default: PidParameters) -> PidParameters {
+        let mut parameters: PidParameters = default;
+        for parameter in PidParameters::NAMES.iter() {
+            let value: f64 = self.get_f64(&format!("{}.{}", name, parameter), default.get(parameter).unwrap());
+            if !parameters.set(parameter, value) {
+                println!("Config: ERROR, {}.{} cannot be {}. Using {}.", name, parameter, value, default.get(parameter).unwrap());
+            }
+        }
+        match parameters.check() {
+            Ok(()) => parameters,
+            Err(error) => {
+                println!("Config: ERROR, the settings of {} are not valid ({}). Using the defaults.", name, error);
+                default
+            }
+        }
+    }
+
+    /// Set all of the settings of the PID called `name`.
+    pub fn set_pid_parameters(&mut self, name: &str, parameters: &PidParameters) {
+        for parameter in PidParameters::NAMES.iter() {
+            self.set_f64(&format!("{}.{}", name, parameter), parameters.get(parameter).unwrap());
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::io::{ Read, Write };
+use std::net::TcpListener;
+use std::os::unix::fs::symlink;
+use std::thread;
+use crate::config::Config;
+use crate::serial::SerialPort;
+
+/// A rotator control protocol spoken by other programs (e.g. tracking software). It turns the bytes
+/// that a client sends into commands for the rotator, and returns the bytes to reply with.
+pub trait Protocol: Send {
+    /// Handle bytes received from the client. They may hold part of a command, or several commands.
+    /// Returns the reply (which may be empty).
+    fn receive(&mut self, data: &[u8]) -> Vec<u8>;
+}
+
+/// Pass everything received on a port through a protocol and write back its replies, until the port fails.
+fn run<P: Read + Write>(name: &str, port: &mut P, protocol: &mut dyn Protocol) {
+    let mut buffer: [u8; 256] = [0; 256];
+    loop {
+        let count: usize = match port.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(count) => count,
+            Err(error) => {
+                println!("{}: ERROR, failed to read: {}.", name, error);
+                return;
+            }
+        };
+        let reply: Vec<u8> = protocol.receive(&buffer[..count]);
+        if !reply.is_empty() {
+            if let Err(error) = port.write_all(&reply).and_then(|_| port.flush()) {
+                println!("{}: ERROR, failed to write: {}.", name, error);
+                return;
+            }
+        }
+    }
+}
+
+/// Speak a protocol on a serial port in a background thread, as set up in the config file:
+///
+/// * `<key>.device` - Serial device to use, "pty" to create a pseudo-terminal, or "off".
+///
+/// * `<key>.baud` - Baud rate of the serial device.
+///
+/// * `<key>.link` - For a pseudo-terminal, a path to make a symlink to it at, so that clients can find it (optional).
+///
+/// # Arguments
+///
+/// * `name` - Name of the protocol, used in messages.
+///
+/// * `key` - Prefix of the protocol's settings in the config file.
+///
+/// * `config` - The config.
+///
+/// * `default_baud` - Baud rate used if the config does not set one.
+///
+/// * `protocol` - The protocol.
+pub fn serve_serial(name: &'static str, key: &str, config: &Config, default_baud: u32, mut protocol: Box<dyn Protocol>) {
+    let device: String = config.get_str(&format!("{}.device", key), "pty").to_string();
+    let port = match device.as_str() {
+        "off" => return,
+        "pty" => SerialPort::open_pty(),
+        device => SerialPort::open(device, config.get_f64(&format!("{}.baud", key), default_baud as f64) as u32)
+    };
+    let mut port: SerialPort = match port {
+        Ok(port) => port,
+        Err(error) => {
+            println!("{}: ERROR, failed to open {}: {}.", name, device, error);
+            return;
+        }
+    };
+
+    match config.get_str(&format!("{}.link", key), "") {
+        "" => println!("{}: Listening on {}.", name, port.path),
+        link => {
+            // Replace the link left behind by the last run.
+            let _ = fs::remove_file(link);
+            match symlink(&port.path, link) {
+                Ok(()) => println!("{}: Listening on {} (linked from {}).", name, port.path, link),
+                Err(error) => println!("{}: Listening on {} (ERROR, failed to link it from {}: {}).", name, port.path, link, error)
+            }
+        }
+    }
+
+    thread::spawn(move || {
+        run(name, &mut port.file, protocol.as_mut());
+        println!("{}: Stopped listening on {}.", name, port.path);
+    });
+}
+
+/// Speak a protocol over TCP in a background thread, as set up in the config file. Each client
+/// gets its own instance of the protocol, so that half-received commands do not get mixed up.
+///
+/// * `<key>.address` - Address and port to listen on, or "off".
+///
+/// # Arguments
+///
+/// * `name` - Name of the protocol, used in messages.
+///
+/// * `key` - Prefix of the protocol's settings in the config file.
+///
+/// * `config` - The config.
+///
+/// * `default_address` - Address used if the config does not set one, e.g. "0.0.0.0:4535".
+///
+/// * `new_protocol` - Creates the protocol for a new client.
+pub fn serve_tcp(name: &'static str, key: &str, config: &Config, default_address: &str, new_protocol: Box<dyn Fn() -> Box<dyn Protocol> + Send>) {
+    let address: &str = config.get_str(&format!("{}.address", key), default_address);
+    if address == "off" {
+        return;
+    }
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("{}: ERROR, failed to listen on {}: {}.", name, address, error);
+            return;
+        }
+    };
+    println!("{}: Listening on {}.", name, address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    let mut protocol: Box<dyn Protocol> = new_protocol();
+                    thread::spawn(move || run(name, &mut stream, protocol.as_mut()));
+                }
+                Err(error) => println!("{}: ERROR, failed to accept connection: {}.", name, error)
+            }
+        }
+    });
+}
+
+/// Helpers shared by the tests of the protocols.
+#[cfg(test)]
+pub mod testing {
+    use std::sync::Arc;
+    use crate::state::{ RotatorState, Satellite };
+    use super::Protocol;
+
+    /// Create a protocol driving a new, stopped rotator that points at an angle. Returns the protocol and the rotator state.
+    ///
+    /// # Arguments
+    ///
+    /// * `altitude` - Altitude that the rotator points at, in degrees.
+    ///
+    /// * `azimuth` - Azimuth that the rotator points at, in degrees.
+    ///
+    /// * `new_protocol` - Creates the protocol for the rotator state.
+    pub fn new_protocol<P: Protocol>(altitude: f64, azimuth: f64, new_protocol: impl FnOnce(Arc<RotatorState>) -> P) -> (P, Arc<RotatorState>) {
+        let state = Arc::new(RotatorState::new(altitude, azimuth, Satellite { name: "ISS".to_string(), tle_file: "iss.tle".to_string() }));
+        (new_protocol(Arc::clone(&state)), state)
+    }
+
+    /// Send text to a protocol and get its reply as text.
+    pub fn send(protocol: &mut dyn Protocol, data: &str) -> String {
+        String::from_utf8(protocol.receive(data.as_bytes())).unwrap()
+    }
+}
@@ -0,0 +1,14 @@
+use crate::http::{ Request, Response };
+
+/// The web dashboard: a sky plot of the antenna and the satellite's pass, the upcoming passes,
+/// and buttons to drive the rotator. It is a single page with no outside scripts or fonts, so that
+/// it works on the rotator's own network in the field. Everything it shows comes from the API (see api.rs).
+const PAGE: &str = include_str!("../web/index.html");
+
+/// Answer a request for the dashboard. Returns None for any other path.
+pub fn handle(request: &Request) -> Option<Response> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") | ("GET", "/index.html") => Some(Response::new(200, "text/html; charset=utf-8", PAGE.as_bytes().to_vec())),
+        _ => None
+    }
+}
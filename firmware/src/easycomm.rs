@@ -0,0 +1,154 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use crate::control_port::Protocol;
+use crate::state::RotatorState;
+
+/// Speed (in degrees per second) that the ML, MR, MU and MD commands move at. EasyComm II has no way to choose one.
+const JOG_SPEED: f64 = 5.0;
+/// Longest line accepted. Anything longer is garbage, and is thrown away.
+const MAX_LINE_LENGTH: usize = 128;
+
+/// EasyComm has no error reply, so a command that the rotator refuses is only logged.
+fn report(result: Result<(), String>) -> Option<String> {
+    if let Err(error) = result {
+        println!("EasyComm: ERROR, command refused: {}", error);
+    }
+    None
+}
+
+/// EasyComm I and II rotator command set. Commands are separated by spaces, and lines end with a
+/// carriage return or line feed. Anything else that EasyComm sends (e.g. radio frequencies) is ignored.
+///
+/// * `AZ` / `EL` - Report the azimuth / elevation, as "AZ123.4" / "EL45.6".
+///
+/// * `AZaaa.a` / `ELeee.e` - Go to an azimuth / elevation.
+///
+/// * `ML` / `MR` - Turn anticlockwise / clockwise. `MU` / `MD` - Turn up / down.
+///
+/// * `SA` / `SE` - Stop the azimuth / elevation.
+///
+/// * `VE` - Report the firmware version.
+///
+/// Replies to the commands on a line are sent together, separated by spaces, on one line.
+pub struct EasyComm {
+    state: Arc<RotatorState>,
+    /// Line received so far.
+    line: String
+}
+
+impl EasyComm {
+    /// Create an EasyComm protocol handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The rotator state that commands act on.
+    pub fn new(state: Arc<RotatorState>) -> EasyComm {
+        EasyComm { state: state, line: String::new() }
+    }
+
+    /// Run one command. Returns the reply, if there is one.
+    fn command(&self, command: &str) -> Option<String> {
+        let name: &str = command.get(..2)?;
+        let argument: Option<f64> = match &command[2..] {
+            "" => None,
+            argument => match argument.parse::<f64>() {
+                Ok(value) if value.is_finite() => Some(value),
+                _ => return None
+            }
+        };
+
+        match (name, argument) {
+            ("AZ", None) => Some(format!("AZ{:.1}", self.state.azimuth.load(Ordering::Relaxed).rem_euclid(360.0))),
+            ("EL", None) => Some(format!("EL{:.1}", self.state.altitude.load(Ordering::Relaxed))),
+            ("AZ", Some(azimuth)) => report(self.state.goto_azimuth(azimuth)),
+            ("EL", Some(elevation)) => report(self.state.goto_altitude(elevation)),
+            ("ML", None) => report(self.state.jog_azimuth(-JOG_SPEED)),
+            ("MR", None) => report(self.state.jog_azimuth(JOG_SPEED)),
+            ("MU", None) => report(self.state.jog_altitude(JOG_SPEED)),
+            ("MD", None) => report(self.state.jog_altitude(-JOG_SPEED)),
+            ("SA", None) => {
+                self.state.stop_azimuth();
+                None
+            }
+            ("SE", None) => {
+                self.state.stop_altitude();
+                None
+            }
+            ("VE", None) => Some(format!("VE{}", env!("CARGO_PKG_VERSION"))),
+            _ => None
+        }
+    }
+}
+
+impl Protocol for EasyComm {
+    fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut reply: Vec<u8> = Vec::new();
+        for &byte in data.iter() {
+            if byte == b'\r' || byte == b'\n' {
+                let line: String = std::mem::replace(&mut self.line, String::new()).to_uppercase();
+                let responses: Vec<String> = line.split_whitespace().filter_map(|command| self.command(command)).collect();
+                if !responses.is_empty() {
+                    reply.extend_from_slice(responses.join(" ").as_bytes());
+                    reply.push(b'\n');
+                }
+            }
+            else if self.line.len() < MAX_LINE_LENGTH {
+                self.line.push(byte as char);
+            }
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_port::testing::{ new_protocol, send };
+    use crate::state::Mode;
+
+    #[test]
+    fn reports_position_on_one_line() {
+        let (mut easycomm, _) = new_protocol(45.4, -10.0, EasyComm::new);
+        assert_eq!(send(&mut easycomm, "AZ EL\n"), "AZ350.0 EL45.4\n");
+        assert_eq!(send(&mut easycomm, "el\r"), "EL45.4\n");
+    }
+
+    #[test]
+    fn follows_hamlib_set_line() {
+        // Hamlib's EasyComm I backend sends the uplink and downlink along with the angles.
+        let (mut easycomm, state) = new_protocol(45.4, -10.0, EasyComm::new);
+        assert_eq!(send(&mut easycomm, "AZ123.4 EL56.7 UP000 XXX DN000 XXX\n"), "");
+        assert_eq!(state.get_mode(), Mode::Goto);
+        assert_eq!(state.target_azimuth.load(Ordering::Relaxed), 123.4);
+        assert_eq!(state.target_altitude.load(Ordering::Relaxed), 56.7);
+    }
+
+    #[test]
+    fn ignores_bad_angles() {
+        let (mut easycomm, state) = new_protocol(45.4, -10.0, EasyComm::new);
+        assert_eq!(send(&mut easycomm, "AZnan ELinf AZ1e9 EL-90\n"), "");
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn jogs_and_stops_each_axis() {
+        let (mut easycomm, state) = new_protocol(45.4, -10.0, EasyComm::new);
+        send(&mut easycomm, "MR MU\n");
+        assert_eq!(state.get_mode(), Mode::Manual);
+        assert_eq!(state.get_jog_speeds(), (JOG_SPEED, JOG_SPEED));
+        send(&mut easycomm, "SA\n");
+        assert_eq!(state.get_jog_speeds(), (JOG_SPEED, 0.0));
+        send(&mut easycomm, "ML MD\n");
+        assert_eq!(state.get_jog_speeds(), (-JOG_SPEED, -JOG_SPEED));
+        send(&mut easycomm, "SE SA\n");
+        assert_eq!(state.get_jog_speeds(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn joins_lines_split_across_reads() {
+        let (mut easycomm, _) = new_protocol(45.4, -10.0, EasyComm::new);
+        assert_eq!(send(&mut easycomm, "A"), "");
+        assert_eq!(send(&mut easycomm, "Z VE"), "");
+        assert_eq!(send(&mut easycomm, "\n"), format!("AZ350.0 VE{}\n", env!("CARGO_PKG_VERSION")));
+    }
+}
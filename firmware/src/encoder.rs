@@ -1,33 +1,189 @@
 extern crate rppal;
 
-use std::sync::{ Arc, Mutex };
-use rppal::gpio::{ Gpio, Trigger, InputPin };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU8, AtomicI64, AtomicU64, Ordering };
+use std::time::Instant;
+use rppal::gpio::{ Gpio, Trigger, InputPin, Level };
 
+/// Order that the (A, B) channel states go through when the encoder turns forwards. Each state is
+/// stored as `(A << 1) | B`.
+const FORWARD_SEQUENCE: [u8; 4] = [0b00, 0b01, 0b11, 0b10];
+
+/// Number of edge timestamps kept for speed estimation.
+const EDGE_BUFFER_SIZE: usize = 64;
+/// Only edges from this many microseconds before the newest edge are used to estimate speed.
+const SPEED_WINDOW: u64 = 20_000;
+/// If no edge has been seen for this many microseconds, the encoder is considered stopped.
+const STOPPED_TIMEOUT: u64 = 250_000;
+
+/// Work out which way the encoder moved when going from one (A, B) state to another.
+///
+/// Returns `Some(1)` for a forward step and `Some(-1)` for a backward step, and `None` for an
+/// illegal transition. Each interrupt only updates the bit of its own channel, so the only illegal
+/// transition that can actually be seen is an edge reported without its channel's level changing,
+/// which means the opposite edge on that channel was missed. That is caused by electrical noise or
+/// by edges arriving faster than the interrupts can be handled. Both channels changing at once
+/// shows up as two legal steps (in a direction decided by which interrupt runs first), not as an
+/// error.
+fn transition(prev_state: u8, state: u8) -> Option<i64> {
+    let prev_index = FORWARD_SEQUENCE.iter().position(|&s| s == prev_state).unwrap();
+    let index = FORWARD_SEQUENCE.iter().position(|&s| s == state).unwrap();
+    match (index + 4 - prev_index) % 4 {
+        1 => Some(1),
+        3 => Some(-1),
+        _ => None
+    }
+}
+
+/// Decoder state shared (without locks) between the interrupt handlers and the readers of an encoder.
+struct EncoderState {
+    /// Last known (A, B) state, stored as `(A << 1) | B`.
+    state: AtomicU8,
+    /// Total number of steps counted.
+    steps: AtomicI64,
+    /// Number of illegal transitions (edges with no level change) seen so far.
+    errors: AtomicU64,
+    /// Total number of edges written to edge_times.
+    edge_count: AtomicU64,
+    /// Ring buffer of edge timestamps. Each entry holds the time of the edge (in microseconds since
+    /// `start`) shifted left by one, with the lowest bit set if the step was forwards.
+    edge_times: [AtomicU64; EDGE_BUFFER_SIZE],
+    /// Time that the encoder was created.
+    start: Instant
+}
+
+impl EncoderState {
+    /// Handle an edge on one channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `mask` - Bit of the (A, B) state belonging to the channel that changed.
+    ///
+    /// * `level` - New level of that channel.
+    fn edge(&self, mask: u8, level: Level) {
+        let time: u64 = self.start.elapsed().as_micros() as u64;
+        let prev_state: u8 = self.state.fetch_update(Ordering::AcqRel, Ordering::Acquire, |state| {
+            Some(if level == Level::High { state | mask } else { state & !mask })
+        }).unwrap();
+        let state: u8 = if level == Level::High { prev_state | mask } else { prev_state & !mask };
+
+        match transition(prev_state, state) {
+            Some(step) => {
+                self.steps.fetch_add(step, Ordering::Relaxed);
+                let index = self.edge_count.fetch_add(1, Ordering::AcqRel) as usize;
+                self.edge_times[index % EDGE_BUFFER_SIZE].store((time << 1) | (step > 0) as u64, Ordering::Release);
+            }
+            None => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Quadrature encoder decoded at 4x resolution (every edge of both channels counts as a step).
 pub struct Encoder {
     _channel_a_pin: InputPin,
-    _channel_b_pin: Arc<Mutex<InputPin>>,
-    pub steps: Arc<Mutex<i64>>
+    _channel_b_pin: InputPin,
+    shared: Arc<EncoderState>
 }
 
 impl Encoder {
     pub fn new(gpio: Arc::<Gpio>, channel_a_pin_number: u8, channel_b_pin_number: u8) -> Encoder {
         let mut channel_a_pin = gpio.get(channel_a_pin_number).unwrap().into_input();
-        let channel_b_pin = Arc::new(Mutex::new(gpio.get(channel_b_pin_number).unwrap().into_input()));
-        let steps: Arc<Mutex<i64>> = Arc::new(Mutex::new(0));
+        let mut channel_b_pin = gpio.get(channel_b_pin_number).unwrap().into_input();
 
-        let channel_b_pin_reference = Arc::clone(&channel_b_pin);
-        let steps_reference = Arc::clone(&steps);
+        // Every edge of both channels is interrupted on. Each interrupt only reports the new level
+        // of its own channel, so the last known level of both channels is kept in the shared state.
+        let initial_state: u8 = ((channel_a_pin.is_high() as u8) << 1) | (channel_b_pin.is_high() as u8);
+        let shared = Arc::new(EncoderState {
+            state: AtomicU8::new(initial_state),
+            steps: AtomicI64::new(0),
+            errors: AtomicU64::new(0),
+            edge_count: AtomicU64::new(0),
+            edge_times: std::array::from_fn(|_| AtomicU64::new(0)),
+            start: Instant::now()
+        });
 
-        &mut channel_a_pin.set_async_interrupt(Trigger::RisingEdge, move |_level| {
-            let mut steps = steps_reference.lock().unwrap();
-            if channel_b_pin_reference.lock().unwrap().is_high() {
-                *steps += 1;
-            }
-            else {
-                *steps -= 1;
-            }
+        let shared_reference = Arc::clone(&shared);
+        channel_a_pin.set_async_interrupt(Trigger::Both, move |level| {
+            shared_reference.edge(0b10, level);
+        }).unwrap();
+
+        let shared_reference = Arc::clone(&shared);
+        channel_b_pin.set_async_interrupt(Trigger::Both, move |level| {
+            shared_reference.edge(0b01, level);
         }).unwrap();
 
-        Encoder { _channel_a_pin: channel_a_pin, _channel_b_pin: channel_b_pin, steps: steps }
+        Encoder { _channel_a_pin: channel_a_pin, _channel_b_pin: channel_b_pin, shared: shared }
+    }
+
+    /// Get the total number of steps counted (increases for forward rotation, decreases for backward).
+    pub fn get_steps(&self) -> i64 {
+        self.shared.steps.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of illegal transitions seen so far.
+    pub fn get_errors(&self) -> u64 {
+        self.shared.errors.load(Ordering::Relaxed)
+    }
+
+    /// Estimate the speed of the encoder (in steps per second) from the time between its most recent edges.
+    ///
+    /// This measures the period between edges (the "1/T" method) rather than counting steps over a
+    /// fixed interval, so it stays accurate at low speeds where only a few steps happen per
+    /// control loop. Edges are averaged over a short window, and the estimate decays towards zero
+    /// when no new edge arrives for longer than the measured period.
+    pub fn get_speed(&self) -> f64 {
+        let now: u64 = self.shared.start.elapsed().as_micros() as u64;
+        let edge_count = self.shared.edge_count.load(Ordering::Acquire) as usize;
+        if edge_count < 2 {
+            return 0.0;
+        }
+
+        let edge = |index: usize| -> (u64, bool) {
+            let entry = self.shared.edge_times[index % EDGE_BUFFER_SIZE].load(Ordering::Acquire);
+            (entry >> 1, entry & 1 == 1)
+        };
+
+        // The newest slot may have been claimed by an interrupt that has not finished writing it yet.
+        let mut newest_index: usize = edge_count - 1;
+        if edge(newest_index).0 < edge(newest_index - 1).0 {
+            newest_index -= 1;
+        }
+        let (newest_time, forwards) = edge(newest_index);
+        if now.saturating_sub(newest_time) > STOPPED_TIMEOUT {
+            return 0.0;
+        }
+
+        let oldest_allowed_index: usize = (newest_index + 1).saturating_sub(EDGE_BUFFER_SIZE);
+        let mut oldest_time: u64 = newest_time;
+        let mut edges: u64 = 0;
+        let mut index: usize = newest_index;
+        while index > oldest_allowed_index {
+            let (time, edge_forwards) = edge(index - 1);
+            // At least one period is always measured, however slowly the encoder is turning.
+            if edge_forwards != forwards || time > oldest_time || (edges > 0 && newest_time - time > SPEED_WINDOW) {
+                break;
+            }
+            oldest_time = time;
+            edges += 1;
+            index -= 1;
+        }
+        if edges == 0 {
+            return 0.0;
+        }
+
+        let mut period: f64 = (newest_time - oldest_time) as f64 / edges as f64;
+        // If the next edge is overdue, the encoder has slowed down by at least that much.
+        let time_since_newest: f64 = now.saturating_sub(newest_time) as f64;
+        if time_since_newest > period {
+            period = time_since_newest;
+        }
+        if period <= 0.0 {
+            return 0.0;
+        }
+
+        let speed: f64 = 1.0e6 / period;
+        if forwards { speed } else { -speed }
     }
 }
@@ -0,0 +1,141 @@
+use std::time::Instant;
+use crate::pid::Pid;
+
+/// Fractional hysteresis around each schedule threshold, so that the gains do not flip back and forth when the scheduling variable sits on a threshold.
+const SCHEDULE_HYSTERESIS: f64 = 0.2;
+/// Time constant (in seconds) of the low-pass filter on the target velocity. The target only moves in steps when tracking, so it has to be smoothed.
+const TARGET_VELOCITY_FILTER_TIME: f64 = 1.0;
+
+/// Quantity that picks which set of gains a ScheduledPid uses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScheduleVariable {
+    /// Magnitude of the error between the target and the value.
+    ErrorMagnitude,
+    /// Magnitude of how fast the target is moving, in units per second.
+    TargetVelocity
+}
+
+/// A PID whose gains change with the operating point, so that, for example, a large goto can use
+/// fast gains while slow tracking uses gentle ones. The switch between gains is bumpless.
+pub struct ScheduledPid {
+    /// The PID doing the control.
+    pid: Pid,
+    /// Quantity that the schedule is keyed on.
+    variable: ScheduleVariable,
+    /// Schedule entries as (threshold, (p, i, d)), sorted by threshold. An entry applies once the scheduling variable is above its threshold.
+    schedule: Vec<(f64, (f64, f64, f64))>,
+    /// Index of the schedule entry in use.
+    current: usize,
+    /// Target from the previous call to compute(). Used to measure the target velocity.
+    previous_target: Option<f64>,
+    /// Filtered target velocity.
+    target_velocity: f64,
+    /// Time of the previous call to compute().
+    previous_time: Instant
+}
+
+impl ScheduledPid {
+    /// Create a scheduled PID.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The PID to schedule. Its gains are replaced by the first schedule entry.
+    ///
+    /// * `variable` - Quantity that the schedule is keyed on.
+    ///
+    /// * `schedule` - Schedule entries as (threshold, (p, i, d)). An entry applies once the scheduling variable is above its threshold, so the lowest threshold should be 0.0.
+    ///
+    /// Returns an error if the schedule is empty or a threshold is not a number.
+    pub fn new(mut pid: Pid, variable: ScheduleVariable, mut schedule: Vec<(f64, (f64, f64, f64))>) -> Result<ScheduledPid, String> {
+        if schedule.is_empty() {
+            return Err("The gain schedule has no entries.".to_string());
+        }
+        if let Some(&(threshold, _)) = schedule.iter().find(|(threshold, _)| threshold.is_nan()) {
+            return Err(format!("Gain schedule threshold {} is not a number.", threshold));
+        }
+        schedule.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let gains = schedule[0].1;
+        pid.set_gains(gains.0, gains.1, gains.2);
+        Ok(ScheduledPid { pid: pid, variable: variable, schedule: schedule, current: 0, previous_target: None, target_velocity: 0.0, previous_time: Instant::now() })
+    }
+
+    /// Compute the output of the PID, switching gains first if the operating point has moved to another schedule entry.
+    pub fn compute(&mut self, value: f64, target_value: f64) -> f64 {
+        let time = Instant::now();
+        let dt: f64 = time.duration_since(self.previous_time).as_secs_f64();
+        self.previous_time = time;
+
+        self.schedule(value, target_value, dt);
+        self.pid.compute(value, target_value)
+    }
+
+    /// Compute the output of the PID for a known time step (see Pid::compute_with_dt()).
+    pub fn compute_with_dt(&mut self, value: f64, target_value: f64, dt: f64) -> f64 {
+        self.schedule(value, target_value, dt);
+        self.pid.compute_with_dt(value, target_value, dt)
+    }
+
+    /// Switch to the gains of the schedule entry for the current operating point.
+    fn schedule(&mut self, value: f64, target_value: f64, dt: f64) {
+        if let Some(previous_target) = self.previous_target {
+            if dt > 0.0 {
+                let velocity: f64 = (target_value - previous_target) / dt;
+                self.target_velocity += (dt / (TARGET_VELOCITY_FILTER_TIME + dt)) * (velocity - self.target_velocity);
+            }
+        }
+        self.previous_target = Some(target_value);
+
+        let scheduling_value: f64 = match self.variable {
+            ScheduleVariable::ErrorMagnitude => (target_value - value).abs(),
+            ScheduleVariable::TargetVelocity => self.target_velocity.abs()
+        };
+
+        let mut index: usize = self.current;
+        while index + 1 < self.schedule.len() && scheduling_value > self.schedule[index + 1].0 * (1.0 + SCHEDULE_HYSTERESIS) {
+            index += 1;
+        }
+        while index > 0 && scheduling_value < self.schedule[index].0 * (1.0 - SCHEDULE_HYSTERESIS) {
+            index -= 1;
+        }
+        if index != self.current {
+            self.current = index;
+            let gains = self.schedule[index].1;
+            self.pid.set_gains(gains.0, gains.1, gains.2);
+        }
+    }
+
+    /// Carry on from a state recorded in a PID log (see Pid::resume()), using the gains of the
+    /// schedule entry for the logged operating point.
+    pub fn resume(&mut self, value: f64, target_value: f64, i_accumulator: f64, d_term: f64) {
+        let scheduling_value: f64 = match self.variable {
+            ScheduleVariable::ErrorMagnitude => (target_value - value).abs(),
+            // The log does not say how fast the target was moving.
+            ScheduleVariable::TargetVelocity => 0.0
+        };
+        self.current = self.schedule.iter().rposition(|&(threshold, _)| scheduling_value > threshold).unwrap_or(0);
+        let gains = self.schedule[self.current].1;
+        self.pid.set_gains(gains.0, gains.1, gains.2);
+        self.pid.resume(value, target_value, i_accumulator, d_term);
+        self.previous_target = Some(target_value);
+        self.target_velocity = 0.0;
+        self.previous_time = Instant::now();
+    }
+
+    /// Get the (p, i, d) gains of a schedule entry. Entries are numbered in order of threshold.
+    pub fn get_entry_gains(&self, entry: usize) -> (f64, f64, f64) {
+        self.schedule[entry].1
+    }
+
+    /// Change the (p, i, d) gains of a schedule entry. If the entry is in use, the PID switches to them bumplessly.
+    pub fn set_entry_gains(&mut self, entry: usize, gains: (f64, f64, f64)) {
+        self.schedule[entry].1 = gains;
+        if entry == self.current {
+            self.pid.set_gains(gains.0, gains.1, gains.2);
+        }
+    }
+
+    /// Get the scheduled PID, for changing its settings.
+    pub fn pid_mut(&mut self) -> &mut Pid {
+        &mut self.pid
+    }
+}
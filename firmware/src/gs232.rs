@@ -0,0 +1,237 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use crate::control_port::Protocol;
+use crate::state::{ RotatorState, Mode };
+
+/// Jog speeds (in degrees per second) selected by the X1 to X4 commands.
+const SPEEDS: [f64; 4] = [2.0, 5.0, 10.0, 20.0];
+/// Speed level used until an X command picks another (X3).
+const DEFAULT_SPEED_LEVEL: usize = 2;
+/// Reply to a command that is not understood.
+const ERROR_REPLY: &str = "?>";
+/// Longest command accepted. Anything longer is garbage, and is thrown away.
+const MAX_COMMAND_LENGTH: usize = 32;
+/// Largest azimuth and elevation that a GS-232 accepts. Azimuths past 360 are on the overlap.
+const MAX_AZIMUTH: f64 = 450.0;
+const MAX_ELEVATION: f64 = 180.0;
+
+/// Whether an azimuth is one that a GS-232 accepts (which also rules out NaN and infinity).
+fn valid_azimuth(azimuth: f64) -> bool {
+    azimuth >= 0.0 && azimuth <= MAX_AZIMUTH
+}
+
+/// Whether an elevation is one that a GS-232 accepts.
+fn valid_elevation(elevation: f64) -> bool {
+    elevation >= 0.0 && elevation <= MAX_ELEVATION
+}
+
+/// Reply to a command that moves the rotator: nothing if it was carried out, or the error reply if the rotator refused it.
+fn acknowledge(result: Result<(), String>) -> Option<String> {
+    result.err().map(|_| ERROR_REPLY.to_string())
+}
+
+/// Which controller's replies to imitate. Hamlib calls the GS-232A model 601 and the GS-232B model 603.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    /// Positions are replied as "+0aaa+0eee".
+    A,
+    /// Positions are replied as "AZ=aaa  EL=eee".
+    B
+}
+
+/// Yaesu GS-232 rotator controller command set. Commands are single letters, some followed by
+/// angles, and end with a carriage return:
+///
+/// * `C` / `B` / `C2` - Report the azimuth, the elevation, or both.
+///
+/// * `Waaa eee` - Go to an azimuth (0 to 450 degrees) and elevation (0 to 180 degrees). `Maaa` - Go to an azimuth. Angles out of range get the error reply, "?>".
+///
+/// * `R` / `L` - Turn clockwise / anticlockwise. `U` / `D` - Turn up / down. `A` / `E` - Stop the azimuth / elevation. `S` - Stop everything.
+///
+/// * `X1` to `X4` - Set the speed of R, L, U and D.
+pub struct Gs232 {
+    state: Arc<RotatorState>,
+    variant: Variant,
+    /// Index into SPEEDS of the jog speed.
+    speed_level: usize,
+    /// Command received so far.
+    line: String
+}
+
+impl Gs232 {
+    /// Create a GS-232 protocol handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The rotator state that commands act on.
+    ///
+    /// * `variant` - Which controller's replies to imitate.
+    pub fn new(state: Arc<RotatorState>, variant: Variant) -> Gs232 {
+        Gs232 { state: state, variant: variant, speed_level: DEFAULT_SPEED_LEVEL, line: String::new() }
+    }
+
+    /// Current azimuth, as the controller reports it (0 to 359 degrees).
+    fn azimuth(&self) -> i32 {
+        (self.state.azimuth.load(Ordering::Relaxed).round() as i32).rem_euclid(360)
+    }
+
+    /// Current elevation, as the controller reports it (0 to 180 degrees).
+    fn elevation(&self) -> i32 {
+        (self.state.altitude.load(Ordering::Relaxed).round() as i32).max(0).min(180)
+    }
+
+    fn azimuth_reply(&self) -> String {
+        match self.variant {
+            Variant::A => format!("+0{:03}", self.azimuth()),
+            Variant::B => format!("AZ={:03}", self.azimuth())
+        }
+    }
+
+    fn elevation_reply(&self) -> String {
+        match self.variant {
+            Variant::A => format!("+0{:03}", self.elevation()),
+            Variant::B => format!("EL={:03}", self.elevation())
+        }
+    }
+
+    /// Run one command. Returns the reply, if there is one.
+    fn command(&mut self, command: &str) -> Option<String> {
+        let command: String = command.trim().to_uppercase();
+        let letter: char = command.chars().next()?;
+        let arguments: &str = &command[letter.len_utf8()..];
+        let arguments: Vec<f64> = match arguments.split_whitespace().map(|argument| argument.parse::<f64>()).collect::<Result<Vec<f64>, _>>() {
+            Ok(arguments) => arguments,
+            Err(_) => return Some(ERROR_REPLY.to_string())
+        };
+        let speed: f64 = SPEEDS[self.speed_level];
+
+        match (letter, arguments.as_slice()) {
+            ('C', []) => Some(self.azimuth_reply()),
+            ('B', []) => Some(self.elevation_reply()),
+            ('C', [number]) if *number == 2.0 => Some(match self.variant {
+                Variant::A => format!("{}{}", self.azimuth_reply(), self.elevation_reply()),
+                Variant::B => format!("{}  {}", self.azimuth_reply(), self.elevation_reply())
+            }),
+            ('W', [azimuth, elevation]) if valid_azimuth(*azimuth) && valid_elevation(*elevation) => acknowledge(self.state.goto(*elevation, *azimuth)),
+            ('M', [azimuth]) if valid_azimuth(*azimuth) => acknowledge(self.state.goto_azimuth(*azimuth)),
+            ('R', []) => acknowledge(self.state.jog_azimuth(speed)),
+            ('L', []) => acknowledge(self.state.jog_azimuth(-speed)),
+            ('U', []) => acknowledge(self.state.jog_altitude(speed)),
+            ('D', []) => acknowledge(self.state.jog_altitude(-speed)),
+            ('A', []) => {
+                self.state.stop_azimuth();
+                None
+            }
+            ('E', []) => {
+                self.state.stop_altitude();
+                None
+            }
+            ('S', []) => {
+                self.state.set_mode(Mode::Stop);
+                None
+            }
+            ('X', [level]) if *level >= 1.0 && *level <= 4.0 && level.fract() == 0.0 => {
+                self.speed_level = *level as usize - 1;
+                None
+            }
+            _ => Some(ERROR_REPLY.to_string())
+        }
+    }
+}
+
+impl Protocol for Gs232 {
+    fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut reply: Vec<u8> = Vec::new();
+        for &byte in data.iter() {
+            if byte == b'\r' || byte == b'\n' {
+                if !self.line.trim().is_empty() {
+                    let line: String = std::mem::replace(&mut self.line, String::new());
+                    if let Some(response) = self.command(&line) {
+                        reply.extend_from_slice(response.as_bytes());
+                        reply.extend_from_slice(b"\r\n");
+                    }
+                }
+                self.line.clear();
+            }
+            else if self.line.len() < MAX_COMMAND_LENGTH {
+                self.line.push(byte as char);
+            }
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_port::testing::{ new_protocol, send };
+
+    #[test]
+    fn reports_position_as_gs232a() {
+        let (mut gs232, _) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::A));
+        assert_eq!(send(&mut gs232, "C\r"), "+0124\r\n");
+        assert_eq!(send(&mut gs232, "B\r"), "+0045\r\n");
+        assert_eq!(send(&mut gs232, "C2\r"), "+0124+0045\r\n");
+    }
+
+    #[test]
+    fn reports_position_as_gs232b() {
+        let (mut gs232, _) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::B));
+        assert_eq!(send(&mut gs232, "C\r"), "AZ=124\r\n");
+        assert_eq!(send(&mut gs232, "B\r"), "EL=045\r\n");
+        assert_eq!(send(&mut gs232, "c2\r"), "AZ=124  EL=045\r\n");
+    }
+
+    #[test]
+    fn wraps_and_limits_reported_angles() {
+        let (mut gs232, state) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::B));
+        state.azimuth.store(-10.0, Ordering::Relaxed);
+        state.altitude.store(-3.0, Ordering::Relaxed);
+        assert_eq!(send(&mut gs232, "C2\r"), "AZ=350  EL=000\r\n");
+    }
+
+    #[test]
+    fn goes_to_w_and_m_targets() {
+        let (mut gs232, state) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::A));
+        assert_eq!(send(&mut gs232, "W090 045\r"), "");
+        assert_eq!(state.get_mode(), Mode::Goto);
+        assert_eq!(state.target_azimuth.load(Ordering::Relaxed), 90.0);
+        assert_eq!(state.target_altitude.load(Ordering::Relaxed), 45.0);
+
+        // M keeps the elevation target, and azimuths on the overlap are allowed.
+        assert_eq!(send(&mut gs232, "M420\r"), "");
+        assert_eq!(state.target_azimuth.load(Ordering::Relaxed), 420.0);
+        assert_eq!(state.target_altitude.load(Ordering::Relaxed), 45.0);
+    }
+
+    #[test]
+    fn refuses_bad_angles() {
+        let (mut gs232, state) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::A));
+        for command in ["Wnan 045\r", "W090 inf\r", "W-1 045\r", "W451 045\r", "W090 181\r", "Mnan\r", "Minf\r", "M9639\r", "W090\r", "Wabc 045\r"].iter() {
+            assert_eq!(send(&mut gs232, command), "?>\r\n", "{}", command);
+        }
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn jogs_at_selected_speed() {
+        let (mut gs232, state) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::A));
+        assert_eq!(send(&mut gs232, "R\r"), "");
+        assert_eq!(state.get_jog_speeds(), (0.0, SPEEDS[DEFAULT_SPEED_LEVEL]));
+        assert_eq!(send(&mut gs232, "X4\rU\r"), "");
+        assert_eq!(state.get_jog_speeds(), (SPEEDS[3], SPEEDS[DEFAULT_SPEED_LEVEL]));
+        assert_eq!(send(&mut gs232, "A\r"), "");
+        assert_eq!(state.get_jog_speeds(), (SPEEDS[3], 0.0));
+        assert_eq!(send(&mut gs232, "X5\r"), "?>\r\n");
+        assert_eq!(send(&mut gs232, "S\r"), "");
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn joins_commands_split_across_reads() {
+        let (mut gs232, _) = new_protocol(45.4, 123.6, |state| Gs232::new(state, Variant::A));
+        assert_eq!(send(&mut gs232, "C"), "");
+        assert_eq!(send(&mut gs232, "2\r\n"), "+0124+0045\r\n");
+        assert_eq!(send(&mut gs232, "\r\nQ\r"), "?>\r\n");
+    }
+}
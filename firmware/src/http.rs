@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crate::websocket::{ self, WebSocket };
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Longest request line or header line accepted, in bytes.
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+/// Most header lines accepted in a request.
+const MAX_HEADERS: usize = 64;
+/// Clients that send nothing for this long are disconnected.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An HTTP request.
+pub struct Request {
+    /// Method, e.g. "GET".
+    pub method: String,
+    /// Path, without the query string.
+    pub path: String,
+    /// Query string parameters.
+    pub query: HashMap<String, String>,
+    /// Headers, with lowercase names.
+    pub headers: HashMap<String, String>,
+    /// Body.
+    pub body: String
+}
+
+/// An HTTP response.
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+    /// Headers beyond the standard ones.
+    pub headers: Vec<(&'static str, String)>,
+    /// For a WebSocket handshake, what to do with the connection once it is upgraded.
+    pub upgrade: Option<Box<dyn FnOnce(WebSocket) + Send>>
+}
+
+impl Response {
+    /// Create a response.
+    pub fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response { status: status, content_type: content_type, body: body, headers: Vec::new(), upgrade: None }
+    }
+
+    /// Create a response with a JSON body.
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status, "application/json", body.into_bytes())
+    }
+
+    /// Accept a WebSocket handshake, or refuse the request if it is not one.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request.
+    ///
+    /// * `on_open` - Run (in the client's thread) with the connection once the handshake is done.
+    pub fn websocket(request: &Request, on_open: Box<dyn FnOnce(WebSocket) + Send>) -> Response {
+        let upgrade: bool = request.headers.get("upgrade").map_or(false, |upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+        match (upgrade, request.headers.get("sec-websocket-key")) {
+            (true, Some(key)) => {
+                let mut response = Response::new(101, "", Vec::new());
+                response.headers.push(("Upgrade", "websocket".to_string()));
+                response.headers.push(("Sec-WebSocket-Accept", websocket::accept_key(key)));
+                response.upgrade = Some(on_open);
+                response
+            }
+            _ => Response::error(400, "This is a WebSocket endpoint.")
+        }
+    }
+
+    /// Create an error response, with the message in a JSON body as {"error": "..."}.
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::json(status, format!("{{\"error\":{}}}", crate::json::string(message)))
+    }
+}
+
+/// Get the standard reason phrase of a status code.
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        _ => ""
+    }
+}
+
+/// Decode %XX escapes and '+' (space) in part of a URL.
+fn url_decode(text: &str) -> String {
+    let bytes: &[u8] = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index: usize = 0;
+    while index < bytes.len() {
+        let escaped: Option<u8> = if bytes[index] == b'%' && index + 2 < bytes.len() {
+            std::str::from_utf8(&bytes[index + 1..index + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        }
+        else {
+            None
+        };
+        match (bytes[index], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                index += 2;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte)
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Read a line of the request head, so that a client cannot make it grow without limit. Returns
+/// the number of bytes read, which is 0 if the client closed the connection.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    let count: usize = reader.by_ref().take(MAX_LINE_LENGTH as u64).read_line(line)?;
+    if count == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(count)
+}
+
+/// Read a request from a client. Returns None if the client closed the connection first.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if read_line(reader, &mut line)? == 0 {
+        return Ok(None);
+    }
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.len() != 3 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed request line"));
+    }
+    let method: String = words[0].to_string();
+    let (path, query_string) = match words[1].find('?') {
+        Some(index) => (&words[1][..index], &words[1][index + 1..]),
+        None => (words[1], "")
+    };
+    let query: HashMap<String, String> = query_string.split('&').filter(|pair| !pair.is_empty()).map(|pair| match pair.find('=') {
+        Some(index) => (url_decode(&pair[..index]), url_decode(&pair[index + 1..])),
+        None => (url_decode(pair), String::new())
+    }).collect();
+    let path: String = url_decode(path);
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    loop {
+        line.clear();
+        if read_line(reader, &mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the headers"));
+        }
+        let header: &str = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too many headers"));
+        }
+        if let Some(index) = header.find(':') {
+            headers.insert(header[..index].trim().to_lowercase(), header[index + 1..].trim().to_string());
+        }
+    }
+
+    let length: usize = headers.get("content-length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body: Vec<u8> = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(Request { method: method, path: path, query: query, headers: headers, body: String::from_utf8_lossy(&body).into_owned() }))
+}
+
+/// Send a response to a client.
+fn write_response(stream: &mut TcpStream, response: &Response, keep_alive: bool) -> io::Result<()> {
+    let mut head: String = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    if response.upgrade.is_some() {
+        head.push_str("Connection: Upgrade\r\n");
+    }
+    else {
+        head.push_str(&format!("Content-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: {}\r\n", response.content_type, response.body.len(), if keep_alive { "keep-alive" } else { "close" }));
+    }
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+/// Answer requests from one client until it disconnects, or hand the connection over if it becomes a WebSocket.
+fn handle_client(handler: Arc<dyn Fn(&Request) -> Response + Send + Sync>, stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer: TcpStream = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader)? {
+        let keep_alive: bool = request.headers.get("connection").map_or(true, |connection| !connection.eq_ignore_ascii_case("close"));
+        let mut response: Response = handler(&request);
+        write_response(&mut writer, &response, keep_alive)?;
+        if let Some(on_open) = response.upgrade.take() {
+            on_open(WebSocket::new(writer)?);
+            return Ok(());
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Serve HTTP in a background thread, with a thread for each client.
+///
+/// # Arguments
+///
+/// * `name` - Name of the server, used in messages.
+///
+/// * `address` - Address and port to listen on, e.g. "0.0.0.0:8080".
+///
+/// * `handler` - Turns each request into a response.
+pub fn serve(name: &'static str, address: &str, handler: Arc<dyn Fn(&Request) -> Response + Send + Sync>) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("{}: ERROR, failed to listen on {}: {}.", name, address, error);
+            return;
+        }
+    };
+    println!("{}: Listening on http://{}/.", name, address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handler_ref = Arc::clone(&handler);
+                    thread::spawn(move || {
+                        // Clients dropping or timing out are routine, so only failures to make sense of a request are reported.
+                        if let Err(error) = handle_client(handler_ref, stream) {
+                            if error.kind() == io::ErrorKind::InvalidData {
+                                println!("{}: ERROR, bad request: {}.", name, error);
+                            }
+                        }
+                    });
+                }
+                Err(error) => println!("{}: ERROR, failed to accept connection: {}.", name, error)
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> io::Result<Option<Request>> {
+        read_request(&mut text.as_bytes())
+    }
+
+    #[test]
+    fn reads_a_request() {
+        let request: Request = parse("PUT /api/target HTTP/1.1\r\nHost: rotator\r\nContent-Type: application/json\r\nContent-Length: 15\r\n\r\n{\"altitude\":10}").unwrap().unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/api/target");
+        assert!(request.query.is_empty());
+        assert_eq!(request.headers.get("content-type").map(String::as_str), Some("application/json"));
+        assert_eq!(request.headers.get("host").map(String::as_str), Some("rotator"));
+        assert_eq!(request.body, "{\"altitude\":10}");
+    }
+
+    #[test]
+    fn decodes_the_path_and_query() {
+        let request: Request = parse("GET /a%20b/c?count=3&name=ISS+%28ZARYA%29&flag&=x HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.path, "/a b/c");
+        assert_eq!(request.query.get("count").map(String::as_str), Some("3"));
+        assert_eq!(request.query.get("name").map(String::as_str), Some("ISS (ZARYA)"));
+        assert_eq!(request.query.get("flag").map(String::as_str), Some(""));
+        assert_eq!(request.query.get("").map(String::as_str), Some("x"));
+    }
+
+    #[test]
+    fn leaves_bad_escapes_alone() {
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%4"), "%4");
+        assert_eq!(url_decode("%zz%41"), "%zzA");
+        assert_eq!(url_decode("%C3%A9"), "\u{e9}");
+    }
+
+    #[test]
+    fn returns_none_when_the_client_closes_first() {
+        assert!(parse("").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(parse("GET /\r\n\r\n").err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse("GET / HTTP/1.1\r\nHost: rotator\r\n").err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(parse("PUT / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_long_lines() {
+        let request: String = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert_eq!(parse(&request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let request: String = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        assert_eq!(parse(&request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_large_bodies() {
+        let request: String = format!("PUT / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        assert_eq!(parse(&request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn limits_the_number_of_headers() {
+        let headers: String = (0..MAX_HEADERS).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+        assert_eq!(parse(&format!("GET / HTTP/1.1\r\n{}\r\n", headers)).unwrap().unwrap().headers.len(), MAX_HEADERS);
+        let request: String = format!("GET / HTTP/1.1\r\n{}X-One-Too-Many: 1\r\n\r\n", headers);
+        assert_eq!(parse(&request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,644 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fs::{ self, File };
+use std::io::Write;
+use std::thread;
+use std::time::{ Duration, Instant };
+use crate::analysis::{ self, PidLogRow };
+use crate::config::Config;
+use crate::motors::Motors;
+use crate::replay::Controller;
+use crate::telemetry;
+
+/// Length (in seconds) of an excitation run.
+const EXCITATION_TIME: f64 = 20.0;
+/// Motor power level in the middle of the excitation, and how far the excitation swings either side of it. The motor is kept turning one way, so that the gear backlash does not get in the way.
+const EXCITATION_BIAS: f64 = 0.5;
+const EXCITATION_AMPLITUDE: f64 = 0.2;
+/// Number of seconds to hold the bias power before the excitation starts.
+const EXCITATION_SETTLE_TIME: f64 = 2.0;
+/// Number of seconds that each PRBS bit is held for.
+const PRBS_BIT_TIME: f64 = 0.05;
+/// Frequency range (in Hz) of the chirp.
+const CHIRP_START_FREQUENCY: f64 = 0.1;
+const CHIRP_END_FREQUENCY: f64 = 5.0;
+
+/// Longest dead time (in seconds) that the fit considers.
+const MAX_DEAD_TIME: f64 = 0.5;
+/// Range of time constants (in seconds) that the fit considers.
+const MIN_TIME_CONSTANT: f64 = 0.002;
+const MAX_TIME_CONSTANT: f64 = 10.0;
+/// Number of time constants tried, log-spaced, in the first (coarse) search.
+const TIME_CONSTANT_STEPS: usize = 40;
+/// Limit on the number of steps of the pattern search that refines the fit.
+const MAX_REFINE_ITERATIONS: usize = 500;
+
+/// Smallest closed loop time constants (in seconds) used when suggesting gains. The SIMC rules set
+/// the closed loop time constant to the dead time, which can be fitted as almost nothing.
+const MIN_SPEED_CLOSED_LOOP_TIME: f64 = 0.05;
+const MIN_POSITION_CLOSED_LOOP_TIME: f64 = 0.5;
+
+/// Seconds between updates of the simulated speed and position PIDs, as in the motor and position loops.
+const SIMULATED_SPEED_PERIOD: f64 = 0.005;
+const SIMULATED_POSITION_PERIOD: f64 = 0.01;
+
+/// Logged excitation and response, e.g. motor power and motor speed.
+pub struct Samples {
+    /// Seconds since the first sample.
+    pub time: Vec<f64>,
+    /// What was driving the process.
+    pub excitation: Vec<f64>,
+    /// How the process responded.
+    pub response: Vec<f64>
+}
+
+impl Samples {
+    /// Read samples from a CSV file with Time (in milliseconds), Output and Input columns, such as a
+    /// PID log or an excitation run. The Output column is the excitation and the Input column the
+    /// response.
+    pub fn read(path: &str) -> Result<Samples, String> {
+        let contents: String = fs::read_to_string(path).map_err(|error| format!("failed to read {}: {}", path, error))?;
+        let mut lines = contents.lines();
+        let header: Vec<&str> = lines.next().unwrap_or("").split(',').map(|name| name.trim()).collect();
+        let column = |name: &str| header.iter().position(|&column| column == name).ok_or(format!("{} has no {} column", path, name));
+        let (time_column, output_column, input_column) = (column("Time")?, column("Output")?, column("Input")?);
+
+        let mut samples = Samples { time: Vec::new(), excitation: Vec::new(), response: Vec::new() };
+        let mut first_time: Option<f64> = None;
+        for (index, line) in lines.enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let values: Vec<&str> = line.split(',').collect();
+            let value = |column: usize| values.get(column).and_then(|value| value.trim().parse::<f64>().ok()).ok_or(format!("line {} of {} is malformed: {}", index + 2, path, line));
+            let time_ms: f64 = value(time_column)?;
+            let start: f64 = *first_time.get_or_insert(time_ms);
+            samples.time.push((time_ms - start) / 1000.0);
+            samples.excitation.push(value(output_column)?);
+            samples.response.push(value(input_column)?);
+        }
+        if samples.time.len() < 10 {
+            return Err(format!("{} has too few samples to identify from", path));
+        }
+        Ok(samples)
+    }
+
+    /// Replace the response with its rate of change, e.g. to turn logged positions into speeds.
+    pub fn differentiate_response(&mut self) {
+        let count: usize = self.time.len();
+        let mut rate: Vec<f64> = vec![0.0; count];
+        // Difference over a few samples either side, since single encoder steps make the position very coarse.
+        let span: usize = 2;
+        for index in 0..count {
+            let before: usize = index.saturating_sub(span);
+            let after: usize = (index + span).min(count - 1);
+            let dt: f64 = self.time[after] - self.time[before];
+            rate[index] = if dt > 0.0 { (self.response[after] - self.response[before]) / dt } else { 0.0 };
+        }
+        self.response = rate;
+    }
+
+    /// Median time between samples.
+    fn sample_time(&self) -> f64 {
+        let mut steps: Vec<f64> = self.time.windows(2).map(|pair| pair[1] - pair[0]).filter(|&step| step > 0.0).collect();
+        if steps.is_empty() {
+            return 0.01;
+        }
+        steps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        steps[steps.len() / 2]
+    }
+
+    /// Write the samples in the same format that read() takes.
+    fn write(&self, path: &str) -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        writeln!(file, "Time,Output,Input")?;
+        for index in 0..self.time.len() {
+            writeln!(file, "{},{},{}", (self.time[index] * 1000.0).round(), self.excitation[index], self.response[index])?;
+        }
+        Ok(())
+    }
+}
+
+/// First or second order plus dead time model of a process:
+/// `response = gain * excitation(t - dead_time) / ((time_constant s + 1)(time_constant_2 s + 1)) + offset`.
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessModel {
+    /// Steady-state change in response per unit change in excitation.
+    pub gain: f64,
+    /// Slower time constant, in seconds.
+    pub time_constant: f64,
+    /// Faster time constant, in seconds. Zero for a first order model.
+    pub time_constant_2: f64,
+    /// Dead time, in seconds.
+    pub dead_time: f64,
+    /// Response with no excitation (e.g. from friction).
+    pub offset: f64
+}
+
+/// Names of the model parameters in the config file.
+const MODEL_PARAMETERS: [&str; 5] = ["gain", "time_constant", "time_constant_2", "dead_time", "offset"];
+
+impl ProcessModel {
+    /// Simulate the model's response to an excitation.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - Time of each sample, in seconds.
+    ///
+    /// * `excitation` - Excitation at each sample. The process is assumed to be settled at the first excitation before the first sample.
+    pub fn simulate(&self, time: &[f64], excitation: &[f64]) -> Vec<f64> {
+        lag_response(time, excitation, self.time_constant, self.time_constant_2, self.dead_time).iter().map(|state| self.gain * state + self.offset).collect()
+    }
+
+    /// Suggested (p, i, d) gains, in the physical units used by Pid, for a PID controlling this
+    /// process directly (e.g. motor power to motor speed). Uses the SIMC rules.
+    pub fn pid_gains(&self) -> (f64, f64, f64) {
+        let closed_loop_time: f64 = self.dead_time.max(MIN_SPEED_CLOSED_LOOP_TIME);
+        let p: f64 = self.time_constant / (self.gain * (closed_loop_time + self.dead_time));
+        let integral_time: f64 = self.time_constant.min(4.0 * (closed_loop_time + self.dead_time));
+        (p, p / integral_time, p * self.time_constant_2)
+    }
+
+    /// Suggested (p, i, d) gains for a PID controlling the integral of this process's response
+    /// (e.g. commanded speed to position, when the model is of commanded speed to measured speed).
+    /// Uses the SIMC rules for an integrating process, with the faster time constant folded into
+    /// the dead time.
+    pub fn integrating_pid_gains(&self) -> (f64, f64, f64) {
+        let dead_time: f64 = self.dead_time + self.time_constant_2 / 2.0;
+        let lag: f64 = self.time_constant + self.time_constant_2 / 2.0;
+        let closed_loop_time: f64 = dead_time.max(MIN_POSITION_CLOSED_LOOP_TIME);
+        let p: f64 = 1.0 / (self.gain * (closed_loop_time + dead_time));
+        let integral_time: f64 = 4.0 * (closed_loop_time + dead_time);
+        (p, p / integral_time, p * lag)
+    }
+
+    /// Save the model to the config under `name` (e.g. `motor_1_model.gain`).
+    pub fn save(&self, config: &mut Config, name: &str) {
+        let values: [f64; 5] = [self.gain, self.time_constant, self.time_constant_2, self.dead_time, self.offset];
+        for (parameter, value) in MODEL_PARAMETERS.iter().zip(values.iter()) {
+            config.set_f64(&format!("{}.{}", name, parameter), *value);
+        }
+    }
+
+    /// Load a model saved by save(), or None if there is no model called `name` in the config.
+    pub fn load(config: &Config, name: &str) -> Option<ProcessModel> {
+        let values: Vec<f64> = MODEL_PARAMETERS.iter().map(|parameter| config.get_f64(&format!("{}.{}", name, parameter), f64::NAN)).collect();
+        if values.iter().any(|value| value.is_nan()) {
+            return None;
+        }
+        Some(ProcessModel { gain: values[0], time_constant: values[1], time_constant_2: values[2], dead_time: values[3], offset: values[4] })
+    }
+}
+
+/// Steps a process model through time one sample at a time, so that it can stand in for the
+/// hardware in a closed loop.
+pub struct ProcessSimulator {
+    model: ProcessModel,
+    /// Time (in seconds) of the latest step.
+    time: f64,
+    /// Excitations that have not yet been overtaken by a later one dead_time ago, as (time applied, excitation), oldest first.
+    pending: VecDeque<(f64, f64)>,
+    /// Excitation from dead_time ago, which is what the lags are being driven by.
+    delayed: f64,
+    /// Outputs of the slower and faster lags (before the gain and offset).
+    state_1: f64,
+    state_2: f64
+}
+
+impl ProcessSimulator {
+    /// Create a simulator of a process that has settled at an excitation.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The process model.
+    ///
+    /// * `time` - Time (in seconds) that the simulation starts at.
+    ///
+    /// * `excitation` - Excitation that the process has settled at.
+    pub fn new(model: ProcessModel, time: f64, excitation: f64) -> ProcessSimulator {
+        ProcessSimulator { model: model, time: time, pending: VecDeque::new(), delayed: excitation, state_1: excitation, state_2: excitation }
+    }
+
+    /// Get the response of the process at the latest step.
+    pub fn response(&self) -> f64 {
+        self.model.gain * self.state_2 + self.model.offset
+    }
+
+    /// Advance the process to a time, with an excitation held since the latest step. Returns the response at that time.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - Time (in seconds) to advance to.
+    ///
+    /// * `excitation` - Excitation applied from the latest step until `time`.
+    pub fn step(&mut self, time: f64, excitation: f64) -> f64 {
+        self.pending.push_back((self.time, excitation));
+        let dt: f64 = time - self.time;
+        self.time = time;
+        // Excitation from dead_time ago (held from the last step before then).
+        while let Some(&(applied, excitation)) = self.pending.front() {
+            if applied > time - self.model.dead_time {
+                break;
+            }
+            self.delayed = excitation;
+            self.pending.pop_front();
+        }
+        let (time_constant, time_constant_2) = (self.model.time_constant, self.model.time_constant_2);
+        self.state_1 += if time_constant > 0.0 { (1.0 - (-dt / time_constant).exp()) * (self.delayed - self.state_1) } else { self.delayed - self.state_1 };
+        self.state_2 += if time_constant_2 > 0.0 { (1.0 - (-dt / time_constant_2).exp()) * (self.state_1 - self.state_2) } else { self.state_1 - self.state_2 };
+        self.response()
+    }
+}
+
+/// Response of unit gain lags and a dead time to an excitation.
+fn lag_response(time: &[f64], excitation: &[f64], time_constant: f64, time_constant_2: f64, dead_time: f64) -> Vec<f64> {
+    let lags = ProcessModel { gain: 1.0, time_constant: time_constant, time_constant_2: time_constant_2, dead_time: dead_time, offset: 0.0 };
+    let mut simulator = ProcessSimulator::new(lags, time[0], excitation[0]);
+    let mut output: Vec<f64> = Vec::with_capacity(time.len());
+    output.push(simulator.response());
+    for index in 1..time.len() {
+        output.push(simulator.step(time[index], excitation[index - 1]));
+    }
+    output
+}
+
+/// Least squares fit of `response = gain * states + offset`. Returns (gain, offset, RMS error).
+fn fit_gain(states: &[f64], response: &[f64]) -> (f64, f64, f64) {
+    let count: f64 = states.len() as f64;
+    let mean_state: f64 = states.iter().sum::<f64>() / count;
+    let mean_response: f64 = response.iter().sum::<f64>() / count;
+    let mut covariance: f64 = 0.0;
+    let mut variance: f64 = 0.0;
+    for (state, value) in states.iter().zip(response.iter()) {
+        covariance += (state - mean_state) * (value - mean_response);
+        variance += (state - mean_state) * (state - mean_state);
+    }
+    let gain: f64 = if variance > 0.0 { covariance / variance } else { 0.0 };
+    let offset: f64 = mean_response - gain * mean_state;
+    let squared_error: f64 = states.iter().zip(response.iter()).map(|(state, value)| (gain * state + offset - value).powi(2)).sum::<f64>();
+    (gain, offset, (squared_error / count).sqrt())
+}
+
+/// Fit a model to logged samples. Returns the model and its RMS error.
+///
+/// # Arguments
+///
+/// * `samples` - The logged excitation and response.
+///
+/// * `second_order` - Fit a second order model instead of a first order one.
+pub fn fit(samples: &Samples, second_order: bool) -> (ProcessModel, f64) {
+    let evaluate = |time_constant: f64, time_constant_2: f64, dead_time: f64| {
+        let states: Vec<f64> = lag_response(&samples.time, &samples.excitation, time_constant, time_constant_2, dead_time);
+        let (gain, offset, error) = fit_gain(&states, &samples.response);
+        (ProcessModel { gain: gain, time_constant: time_constant, time_constant_2: time_constant_2, dead_time: dead_time, offset: offset }, error)
+    };
+
+    // Coarse search of a first order model over log-spaced time constants and dead times of whole samples.
+    let sample_time: f64 = samples.sample_time();
+    let dead_time_steps: usize = (MAX_DEAD_TIME / sample_time).ceil() as usize;
+    let mut best: (ProcessModel, f64) = evaluate(MIN_TIME_CONSTANT, 0.0, 0.0);
+    for time_constant_step in 0..TIME_CONSTANT_STEPS {
+        let time_constant: f64 = MIN_TIME_CONSTANT * (MAX_TIME_CONSTANT / MIN_TIME_CONSTANT).powf(time_constant_step as f64 / (TIME_CONSTANT_STEPS - 1) as f64);
+        for dead_time_step in 0..=dead_time_steps {
+            let candidate = evaluate(time_constant, 0.0, dead_time_step as f64 * sample_time);
+            if candidate.1 < best.1 {
+                best = candidate;
+            }
+        }
+    }
+
+    // Refine with a pattern search: try moving each parameter both ways, and shrink the moves when nothing improves.
+    let mut parameters: [f64; 3] = [best.0.time_constant, if second_order { best.0.time_constant / 4.0 } else { 0.0 }, best.0.dead_time];
+    if second_order {
+        best = evaluate(parameters[0], parameters[1], parameters[2]);
+    }
+    let mut time_constant_factor: f64 = 2.0;
+    let mut dead_time_move: f64 = 4.0 * sample_time;
+    for _ in 0..MAX_REFINE_ITERATIONS {
+        let mut improved: bool = false;
+        for parameter in 0..3 {
+            if parameter == 1 && !second_order {
+                continue;
+            }
+            for &direction in [1.0, -1.0].iter() {
+                let mut candidate_parameters: [f64; 3] = parameters;
+                if parameter == 2 {
+                    candidate_parameters[2] = (parameters[2] + direction * dead_time_move).max(0.0).min(MAX_DEAD_TIME);
+                }
+                else {
+                    candidate_parameters[parameter] = (parameters[parameter] * time_constant_factor.powf(direction)).max(MIN_TIME_CONSTANT).min(MAX_TIME_CONSTANT);
+                }
+                let candidate = evaluate(candidate_parameters[0], candidate_parameters[1], candidate_parameters[2]);
+                if candidate.1 < best.1 {
+                    best = candidate;
+                    parameters = candidate_parameters;
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            time_constant_factor = time_constant_factor.sqrt();
+            dead_time_move /= 2.0;
+            if time_constant_factor < 1.001 && dead_time_move < sample_time / 100.0 {
+                break;
+            }
+        }
+    }
+
+    // Keep the slower time constant first.
+    let mut model: ProcessModel = best.0;
+    if model.time_constant_2 > model.time_constant {
+        std::mem::swap(&mut model.time_constant, &mut model.time_constant_2);
+    }
+    (model, best.1)
+}
+
+/// Fit first and second order models, print them, save the better one to the config under `model_name`, and print the gains that it suggests.
+///
+/// # Arguments
+///
+/// * `samples` - The logged excitation and response.
+///
+/// * `model_name` - Name that the model is saved to the config under.
+///
+/// * `pid_name` - Name of the PID that the gains are for.
+///
+/// * `integrating` - True if the PID controls the integral of the response (e.g. position, when the response is speed).
+///
+/// * `config` - Config that the model is saved to.
+fn identify(samples: &Samples, model_name: &str, pid_name: &str, integrating: bool, config: &mut Config) {
+    let (first_order, first_order_error) = fit(samples, false);
+    let (second_order, second_order_error) = fit(samples, true);
+    let describe = |model: &ProcessModel| format!("gain {:.5}, time constants {:.4} s and {:.4} s, dead time {:.4} s, offset {:.5}", model.gain, model.time_constant, model.time_constant_2, model.dead_time, model.offset);
+    println!("Identify: First order model:  {} (RMS error {:.5}).", describe(&first_order), first_order_error);
+    println!("Identify: Second order model: {} (RMS error {:.5}).", describe(&second_order), second_order_error);
+
+    // The second order model always fits at least as well, so only prefer it if it is clearly better.
+    let model: ProcessModel = if second_order_error < 0.9 * first_order_error { second_order } else { first_order };
+    let largest_error: f64 = model.simulate(&samples.time, &samples.excitation).iter().zip(samples.response.iter()).map(|(simulated, response)| (simulated - response).abs()).fold(0.0, f64::max);
+    println!("Identify: The chosen model is at most {:.5} away from the log.", largest_error);
+    let gains = if integrating { model.integrating_pid_gains() } else { model.pid_gains() };
+    println!("Identify: Suggested gains for {}: p = {:.5}, i = {:.5}, d = {:.5}.", pid_name, gains.0, gains.1, gains.2);
+    println!("Identify: Try them with \"pid set {} p {:.5}\" etc. while the rotator runs.", pid_name, gains.0);
+
+    model.save(config, model_name);
+    match config.save() {
+        Ok(()) => println!("Identify: Saved the model to the config file as {}.", model_name),
+        Err(error) => println!("Identify: ERROR, failed to save the config file: {}.", error)
+    }
+}
+
+/// Run the offline identify subcommands, which fit a model to a log:
+///
+/// * `identify speed <1|2> <log.csv>` - Model motor power to motor speed, from a speed PID log or an excitation run.
+///
+/// * `identify position <altitude|azimuth> <log.csv>` - Model commanded motor speed to measured motor speed, from a position PID log.
+pub fn run(args: &[String], config: &mut Config) {
+    let (model_name, pid_name, integrating) = match (args.get(0).map(|arg| arg.as_str()), args.get(1).map(|arg| arg.as_str())) {
+        (Some("speed"), Some(motor @ "1")) | (Some("speed"), Some(motor @ "2")) => (format!("motor_{}_model", motor), format!("motor_{}_speed", motor), false),
+        (Some("position"), Some(axis @ "altitude")) | (Some("position"), Some(axis @ "azimuth")) => (format!("{}_model", axis), format!("{}_position", axis), true),
+        _ => {
+            println!("Identify: ERROR, expected \"speed <1|2> <log.csv>\" or \"position <altitude|azimuth> <log.csv>\".");
+            return;
+        }
+    };
+    let path: &str = match args.get(2) {
+        Some(path) => path,
+        None => {
+            println!("Identify: ERROR, no log file given.");
+            return;
+        }
+    };
+
+    let mut samples: Samples = match Samples::read(path) {
+        Ok(samples) => samples,
+        Err(error) => {
+            println!("Identify: ERROR, {}.", error);
+            return;
+        }
+    };
+    // Position logs have the motor position as their input, and the model is of its speed.
+    if integrating {
+        samples.differentiate_response();
+    }
+    identify(&samples, &model_name, &pid_name, integrating, config);
+}
+
+/// Drive one motor's power with an excitation signal, log its speed, and identify a model of it.
+///
+/// # Arguments
+///
+/// * `motors` - The motors. The other motor is left stopped.
+///
+/// * `motor` - Which motor to excite (1 or 2).
+///
+/// * `signal` - "prbs" for a pseudo-random binary sequence, or "chirp" for a sine wave sweeping up in frequency.
+///
+/// * `config` - Config that the model is saved to.
+pub fn excite(motors: &mut Motors, motor: u8, signal: &str, config: &mut Config) {
+    let period = Duration::from_millis(5);
+    let set_power = |motors: &mut Motors, power: Option<f64>| if motor == 1 { motors.set_power_override_1(power) } else { motors.set_power_override_2(power) };
+    let get_speed = |motors: &mut Motors| if motor == 1 { motors.get_speed_1() } else { motors.get_speed_2() };
+
+    println!("Identify: Exciting motor {} with a {} for {} seconds.", motor, signal, EXCITATION_TIME);
+    set_power(motors, Some(EXCITATION_BIAS));
+    thread::sleep(Duration::from_secs_f64(EXCITATION_SETTLE_TIME));
+
+    let mut samples = Samples { time: Vec::new(), excitation: Vec::new(), response: Vec::new() };
+    let mut lfsr: u16 = 0xACE1;
+    let mut prbs_bit_start: f64 = 0.0;
+    let start = Instant::now();
+    loop {
+        let time: f64 = start.elapsed().as_secs_f64();
+        if time > EXCITATION_TIME {
+            break;
+        }
+        let swing: f64 = if signal == "chirp" {
+            // Exponential sweep, so that each octave gets the same time.
+            let rate: f64 = (CHIRP_END_FREQUENCY / CHIRP_START_FREQUENCY).ln() / EXCITATION_TIME;
+            let phase: f64 = 2.0 * PI * CHIRP_START_FREQUENCY * ((rate * time).exp() - 1.0) / rate;
+            phase.sin()
+        }
+        else {
+            if time - prbs_bit_start >= PRBS_BIT_TIME {
+                // 16 bit maximal length Fibonacci LFSR (taps 16, 14, 13, 11).
+                let bit: u16 = (lfsr ^ (lfsr >> 2) ^ (lfsr >> 3) ^ (lfsr >> 5)) & 1;
+                lfsr = (lfsr >> 1) | (bit << 15);
+                prbs_bit_start = time;
+            }
+            if lfsr & 1 == 1 { 1.0 } else { -1.0 }
+        };
+        let power: f64 = EXCITATION_BIAS + EXCITATION_AMPLITUDE * swing;
+        set_power(motors, Some(power));
+        samples.time.push(time);
+        samples.excitation.push(power);
+        samples.response.push(get_speed(motors));
+        thread::sleep(period);
+    }
+    set_power(motors, Some(0.0));
+    thread::sleep(Duration::from_millis(500));
+    set_power(motors, None);
+
+    let path: String = format!("identify_motor_{}_{}.csv", motor, telemetry::now_ms());
+    match samples.write(&path) {
+        Ok(()) => println!("Identify: Saved the excitation run to {}.", path),
+        Err(error) => println!("Identify: ERROR, failed to save the excitation run: {}.", error)
+    }
+    identify(&samples, &format!("motor_{}_model", motor), &format!("motor_{}_speed", motor), false, config);
+}
+
+/// Drive a model of a process with a controller in a closed loop, through a step of the target from
+/// rest, as the hardware would be driven. The process starts settled with no excitation, and the
+/// target steps after the first update. Returns the updates as a PID log, for analysis.
+///
+/// # Arguments
+///
+/// * `model` - Model of the process, e.g. motor power to motor speed.
+///
+/// * `controller` - The controller, e.g. a speed PID as it is set up in the config file. Its output is the excitation.
+///
+/// * `integrating` - True if the controller controls the integral of the response (e.g. position, when the model is of speed).
+///
+/// * `step` - Size of the step of the target.
+///
+/// * `period` - Number of seconds between updates of the controller.
+///
+/// * `duration` - Number of seconds to simulate.
+pub fn simulate_step(model: ProcessModel, controller: &mut dyn Controller, integrating: bool, step: f64, period: f64, duration: f64) -> Vec<PidLogRow> {
+    let mut simulator = ProcessSimulator::new(model, 0.0, 0.0);
+    let mut integral: f64 = 0.0;
+    let mut output: f64 = 0.0;
+    let mut rows: Vec<PidLogRow> = Vec::new();
+    for index in 0..=(duration / period).round() as usize {
+        let time: f64 = index as f64 * period;
+        if index > 0 {
+            let previous_response: f64 = simulator.response();
+            let response: f64 = simulator.step(time, output);
+            integral += 0.5 * (previous_response + response) * period;
+        }
+        let value: f64 = if integrating { integral } else { simulator.response() };
+        let target: f64 = if index == 0 { 0.0 } else { step };
+        let dt: f64 = if index == 0 { 0.0 } else { period };
+        output = controller.compute(value, target, dt);
+        // The terms inside the controller are not visible from here.
+        rows.push(PidLogRow { time: time, error: target - value, p: f64::NAN, i_accumulator: f64::NAN, d: f64::NAN, output: output, input: value, target: target, dt: Some(dt) });
+    }
+    rows
+}
+
+/// Simulate a PID against the model identified for what it controls, and print its step response,
+/// so that gains can be tried before they are taken to the rotator.
+///
+/// # Arguments
+///
+/// * `config` - Config that the model was saved to by identify.
+///
+/// * `model_name` - Name of the model in the config, e.g. "motor_1_model" or "altitude_model".
+///
+/// * `controller` - The PID, as it is set up in the config file.
+///
+/// * `integrating` - True for a position PID, which controls the integral of the modelled speed.
+///
+/// * `step` - Size of the step of the target, in the PID's units (motor revolutions per second, or motor revolutions).
+///
+/// * `duration` - Number of seconds to simulate.
+pub fn simulate(config: &Config, model_name: &str, controller: &mut dyn Controller, integrating: bool, step: f64, duration: f64) {
+    let model: ProcessModel = match ProcessModel::load(config, model_name) {
+        Some(model) => model,
+        None => {
+            println!("Simulate: ERROR, there is no {} in the config file. Identify it first.", model_name);
+            return;
+        }
+    };
+    let period: f64 = if integrating { SIMULATED_POSITION_PERIOD } else { SIMULATED_SPEED_PERIOD };
+    let rows: Vec<PidLogRow> = simulate_step(model, controller, integrating, step, period, duration);
+    let saturated: usize = rows.iter().filter(|row| row.output.abs() >= 1.0).count();
+    println!("Simulate: {} with gain {:.5}, time constants {:.4} s and {:.4} s, dead time {:.4} s. The output was saturated for {:.2} s.", model_name, model.gain, model.time_constant, model.time_constant_2, model.dead_time, saturated as f64 * period);
+    analysis::print_step_responses("Simulate", &rows, analysis::DEFAULT_SETTLING_BAND, 0.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::Pid;
+
+    const MODEL: ProcessModel = ProcessModel { gain: 2.0, time_constant: 0.1, time_constant_2: 0.02, dead_time: 0.015, offset: 0.1 };
+
+    /// Times 5 ms apart with some jitter, and a square wave excitation.
+    fn excitation_run() -> (Vec<f64>, Vec<f64>) {
+        let time: Vec<f64> = (0..400).map(|index| index as f64 * 0.005 + if index % 3 == 0 { 0.001 } else { 0.0 }).collect();
+        let excitation: Vec<f64> = (0..400).map(|index| if (index / 50) % 2 == 0 { 0.3 } else { 0.7 }).collect();
+        (time, excitation)
+    }
+
+    #[test]
+    fn simulator_matches_simulate() {
+        let (time, excitation) = excitation_run();
+        let expected: Vec<f64> = MODEL.simulate(&time, &excitation);
+        let mut simulator = ProcessSimulator::new(MODEL, time[0], excitation[0]);
+        assert!((simulator.response() - expected[0]).abs() < 1e-12);
+        for index in 1..time.len() {
+            assert!((simulator.step(time[index], excitation[index - 1]) - expected[index]).abs() < 1e-12, "sample {}", index);
+        }
+    }
+
+    #[test]
+    fn simulator_delays_and_settles() {
+        let mut simulator = ProcessSimulator::new(MODEL, 0.0, 0.0);
+        assert_eq!(simulator.response(), 0.1);
+        // Nothing happens until the dead time has passed.
+        assert_eq!(simulator.step(0.005, 1.0), 0.1);
+        assert_eq!(simulator.step(0.010, 1.0), 0.1);
+        assert!(simulator.step(0.020, 1.0) > 0.1);
+        let mut time: f64 = 0.020;
+        while time < 2.0 {
+            time += 0.005;
+            simulator.step(time, 1.0);
+        }
+        assert!((simulator.response() - 2.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fits_a_simulated_excitation_run() {
+        let (time, excitation) = excitation_run();
+        let samples = Samples { response: MODEL.simulate(&time, &excitation), time: time, excitation: excitation };
+        let (model, error) = fit(&samples, true);
+        // The faster lag is close enough to a dead time that the fit may swap one for the other.
+        assert!(error < 0.02, "RMS error {} for {:?}", error, model);
+        assert!((model.gain - MODEL.gain).abs() < 0.02, "{:?}", model);
+        assert!((model.offset - MODEL.offset).abs() < 0.02, "{:?}", model);
+    }
+
+    #[test]
+    fn saves_and_loads_models() {
+        // A missing file gives an empty config.
+        let mut config = Config::load("/nonexistent/rotator.conf");
+        assert!(ProcessModel::load(&config, "motor_1_model").is_none());
+        MODEL.save(&mut config, "motor_1_model");
+        let model: ProcessModel = ProcessModel::load(&config, "motor_1_model").unwrap();
+        assert_eq!((model.gain, model.time_constant, model.time_constant_2, model.dead_time, model.offset), (2.0, 0.1, 0.02, 0.015, 0.1));
+    }
+
+    #[test]
+    fn closed_loop_speed_reaches_the_target() {
+        let mut pid = Pid::new(0.5, 5.0, 0.0, -1.0, 1.0);
+        let rows: Vec<PidLogRow> = simulate_step(MODEL, &mut pid, false, 1.0, 0.005, 3.0);
+        assert_eq!(rows.len(), 601);
+        assert_eq!((rows[0].target, rows[1].target), (0.0, 1.0));
+        assert!((rows[0].input - 0.1).abs() < 1e-12);
+        assert!((rows.last().unwrap().input - 1.0).abs() < 0.01);
+        // Holding 1.0 takes a power of (1.0 - offset) / gain.
+        assert!((rows.last().unwrap().output - 0.45).abs() < 0.01);
+    }
+
+    #[test]
+    fn closed_loop_position_integrates_the_speed() {
+        let model = ProcessModel { gain: 1.0, time_constant: 0.05, time_constant_2: 0.0, dead_time: 0.0, offset: 0.0 };
+        let mut pid = Pid::new(2.0, 0.0, 0.0, -1.0, 1.0);
+        let rows: Vec<PidLogRow> = simulate_step(model, &mut pid, true, 3.0, 0.01, 5.0);
+        // The speed is limited to 1 revolution per second, so the move takes about 3 seconds.
+        let halfway: &PidLogRow = rows.iter().find(|row| row.input >= 1.5).unwrap();
+        assert!(halfway.time > 1.4 && halfway.time < 1.7, "halfway at {}", halfway.time);
+        assert!((rows.last().unwrap().input - 3.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs::{ self, File };
+use std::io::{ self, Read };
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crate::config::Config;
+use crate::state::{ RotatorState, Mode, JOG_SPEEDS, DEFAULT_JOG_SPEED };
+
+/// Where udev puts links to the event devices of joysticks and gamepads.
+const INPUT_BY_ID: &str = "/dev/input/by-id";
+/// How long a jog from the stick lasts. The stick is read at least every POLL_INTERVAL, so this
+/// only runs out if the joystick stops being read, e.g. because it was unplugged.
+const STICK_JOG_TIMEOUT: Duration = Duration::from_millis(300);
+/// Longest time to wait for joystick events before refreshing the jog.
+const POLL_INTERVAL_MS: i32 = 100;
+/// Time between looking for a joystick when there is none.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Fraction of the stick's travel around the centre that is ignored, as sticks do not centre exactly.
+const DEFAULT_DEADZONE: f64 = 0.15;
+
+// Event types and codes, from linux/input-event-codes.h.
+const EV_KEY: u16 = 0x01;
+const EV_ABS: u16 = 0x03;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+const BTN_SOUTH: u16 = 0x130;
+const BTN_TL: u16 = 0x136;
+const BTN_TR: u16 = 0x137;
+const BTN_START: u16 = 0x13b;
+
+/// Range of values that an axis reports.
+#[derive(Clone, Copy)]
+struct AxisRange {
+    minimum: i32,
+    maximum: i32
+}
+
+impl AxisRange {
+    /// Ask the device for the range of an axis (the EVIOCGABS ioctl). Hats and sticks that are not
+    /// there are assumed to run from -1 to 1.
+    fn query(device: &File, axis: u16) -> AxisRange {
+        let mut info: libc::input_absinfo = unsafe { std::mem::zeroed() };
+        // _IOR('E', 0x40 + axis, struct input_absinfo)
+        let request: u64 = (2 << 30) | ((std::mem::size_of::<libc::input_absinfo>() as u64) << 16) | ((b'E' as u64) << 8) | (0x40 + axis as u64);
+        let result: i32 = unsafe { libc::ioctl(device.as_raw_fd(), request as _, &mut info) };
+        if result < 0 || info.maximum <= info.minimum {
+            AxisRange { minimum: -1, maximum: 1 }
+        }
+        else {
+            AxisRange { minimum: info.minimum, maximum: info.maximum }
+        }
+    }
+
+    /// Scale a value to between -1 and 1.
+    fn normalise(&self, value: i32) -> f64 {
+        let fraction: f64 = (value - self.minimum) as f64 / (self.maximum - self.minimum) as f64;
+        (fraction * 2.0 - 1.0).max(-1.0).min(1.0)
+    }
+}
+
+/// Apply the deadzone to a stick position, so that it is 0 near the centre and rises smoothly to 1
+/// at full deflection.
+fn apply_deadzone(position: f64, deadzone: f64) -> f64 {
+    if position.abs() <= deadzone {
+        0.0
+    }
+    else {
+        position.signum() * (position.abs() - deadzone) / (1.0 - deadzone)
+    }
+}
+
+/// Direction that a d-pad axis is pressed in: -1, 0 or 1.
+fn hat_direction(position: f64) -> f64 {
+    if position.abs() > 0.5 { position.signum() } else { 0.0 }
+}
+
+/// Find the event device of the first joystick plugged in.
+fn find_joystick() -> Option<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(INPUT_BY_ID).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.to_string_lossy().ends_with("-event-joystick"))
+        .collect();
+    // The same joystick is picked on each run.
+    paths.sort();
+    paths.into_iter().next()
+}
+
+/// Wait up to a time for a device to have something to read. Returns whether it has.
+fn wait_readable(device: &File, timeout_ms: i32) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd { fd: device.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+        result if result < 0 => {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(error) }
+        }
+        0 => Ok(false),
+        _ => Ok(true)
+    }
+}
+
+/// Jogs the rotator from a joystick or gamepad. The left stick moves the axes at speeds in
+/// proportion to how far it is pushed (or the d-pad at full speed), the shoulder buttons step the
+/// full speed down and up through JOG_SPEEDS, and start or the bottom face button stops the rotator.
+/// The rotator stops when the stick is let go or the joystick is unplugged.
+struct Joystick {
+    state: Arc<RotatorState>,
+    deadzone: f64,
+    /// Index into JOG_SPEEDS of the speed at full deflection.
+    jog_speed: usize,
+    x_range: AxisRange,
+    y_range: AxisRange,
+    hat_x_range: AxisRange,
+    hat_y_range: AxisRange,
+    /// Stick and d-pad positions, from -1 to 1 (right and down are positive).
+    x: f64,
+    y: f64,
+    hat_x: f64,
+    hat_y: f64,
+    /// Whether the last update moved the rotator.
+    jogging: bool
+}
+
+impl Joystick {
+    fn new(state: Arc<RotatorState>, deadzone: f64) -> Joystick {
+        let default_range = AxisRange { minimum: -1, maximum: 1 };
+        Joystick { state: state, deadzone: deadzone, jog_speed: DEFAULT_JOG_SPEED, x_range: default_range, y_range: default_range, hat_x_range: default_range, hat_y_range: default_range, x: 0.0, y: 0.0, hat_x: 0.0, hat_y: 0.0, jogging: false }
+    }
+
+    /// Read events from a joystick and jog the rotator until the joystick fails (e.g. is unplugged).
+    fn run(&mut self, device: &mut File) -> io::Result<()> {
+        self.x_range = AxisRange::query(device, ABS_X);
+        self.y_range = AxisRange::query(device, ABS_Y);
+        self.hat_x_range = AxisRange::query(device, ABS_HAT0X);
+        self.hat_y_range = AxisRange::query(device, ABS_HAT0Y);
+        self.x = 0.0;
+        self.y = 0.0;
+        self.hat_x = 0.0;
+        self.hat_y = 0.0;
+
+        let event_size: usize = std::mem::size_of::<libc::input_event>();
+        let mut buffer: Vec<u8> = vec![0; event_size * 64];
+        loop {
+            if wait_readable(device, POLL_INTERVAL_MS)? {
+                let count: usize = device.read(&mut buffer)?;
+                if count == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "device closed"));
+                }
+                // The type, code and value are the last fields of each event, after the time.
+                for event in buffer[..count].chunks_exact(event_size) {
+                    let fields: &[u8] = &event[event_size - 8..];
+                    let event_type: u16 = u16::from_ne_bytes([fields[0], fields[1]]);
+                    let code: u16 = u16::from_ne_bytes([fields[2], fields[3]]);
+                    let value: i32 = i32::from_ne_bytes([fields[4], fields[5], fields[6], fields[7]]);
+                    self.handle_event(event_type, code, value);
+                }
+            }
+            self.update();
+        }
+    }
+
+    fn handle_event(&mut self, event_type: u16, code: u16, value: i32) {
+        match (event_type, code) {
+            (EV_ABS, ABS_X) => self.x = self.x_range.normalise(value),
+            (EV_ABS, ABS_Y) => self.y = self.y_range.normalise(value),
+            (EV_ABS, ABS_HAT0X) => self.hat_x = self.hat_x_range.normalise(value),
+            (EV_ABS, ABS_HAT0Y) => self.hat_y = self.hat_y_range.normalise(value),
+            // Buttons act when pressed (1), not when released (0) or repeated (2).
+            (EV_KEY, _) if value == 1 => match code {
+                BTN_TL | BTN_TR => {
+                    self.jog_speed = if code == BTN_TL { self.jog_speed.saturating_sub(1) } else { (self.jog_speed + 1).min(JOG_SPEEDS.len() - 1) };
+                    println!("Joystick: Jog speed {} degrees per second.", JOG_SPEEDS[self.jog_speed]);
+                }
+                BTN_START | BTN_SOUTH => {
+                    self.state.set_mode(Mode::Stop);
+                    self.jogging = false;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Jog the rotator from the stick and d-pad positions. While either is pushed the jog is
+    /// refreshed, and when both are let go the rotator is stopped once. A centred stick leaves the
+    /// rotator alone, so that it can still be driven by other means.
+    fn update(&mut self) {
+        let speed: f64 = JOG_SPEEDS[self.jog_speed];
+        let (hat_x, hat_y) = (hat_direction(self.hat_x), hat_direction(self.hat_y));
+        let (stick_x, stick_y) = (apply_deadzone(self.x, self.deadzone), apply_deadzone(self.y, self.deadzone));
+        // The d-pad wins over the stick. Pushing up raises the antenna.
+        let azimuth_speed: f64 = if hat_x != 0.0 { hat_x * speed } else { stick_x * speed };
+        let altitude_speed: f64 = if hat_y != 0.0 { -hat_y * speed } else { -stick_y * speed };
+        if altitude_speed != 0.0 || azimuth_speed != 0.0 {
+            if let Err(error) = self.state.jog_for(altitude_speed, azimuth_speed, STICK_JOG_TIMEOUT) {
+                println!("Joystick: ERROR, {}", error);
+            }
+            self.jogging = true;
+        }
+        else if self.jogging {
+            if self.state.get_mode() == Mode::Manual {
+                // Stopping is always allowed.
+                let _ = self.state.jog(0.0, 0.0);
+            }
+            self.jogging = false;
+        }
+    }
+}
+
+/// Jog the rotator from a joystick or gamepad in a background thread, as set up in the config file:
+///
+/// * `joystick.device` - Event device of the joystick (e.g. /dev/input/event3), "auto" to use the
+///   first one found in /dev/input/by-id, or "off".
+///
+/// * `joystick.deadzone` - Fraction of the stick's travel around the centre that is ignored.
+///
+/// Joysticks can be plugged in and unplugged at any time.
+///
+/// # Arguments
+///
+/// * `state` - The rotator state to jog.
+///
+/// * `config` - The config.
+pub fn start(state: Arc<RotatorState>, config: &Config) {
+    let device: String = config.get_str("joystick.device", "auto").to_string();
+    if device == "off" {
+        return;
+    }
+    let deadzone: f64 = config.get_f64("joystick.deadzone", DEFAULT_DEADZONE).max(0.0).min(0.9);
+
+    thread::spawn(move || {
+        let mut joystick = Joystick::new(state, deadzone);
+        let mut reported_missing: bool = false;
+        loop {
+            let path: Option<PathBuf> = if device == "auto" { find_joystick() } else { Some(PathBuf::from(&device)) };
+            match path.as_ref().map(|path| File::open(path).map(|file| (path, file))) {
+                Some(Ok((path, mut file))) => {
+                    println!("Joystick: Using {}.", path.display());
+                    reported_missing = false;
+                    if let Err(error) = joystick.run(&mut file) {
+                        println!("Joystick: ERROR, lost {}: {}.", path.display(), error);
+                    }
+                    // Stop anything that the joystick started.
+                    if joystick.jogging && joystick.state.get_mode() == Mode::Manual {
+                        let _ = joystick.state.jog(0.0, 0.0);
+                    }
+                    joystick.jogging = false;
+                }
+                Some(Err(error)) if !reported_missing => {
+                    println!("Joystick: ERROR, failed to open {}: {}.", device, error);
+                    reported_missing = true;
+                }
+                _ => {}
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+/// A value in a flat JSON object.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String)
+}
+
+impl Value {
+    /// Get the value as a number, if it is one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None
+        }
+    }
+
+    /// Get the value as a string, if it is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string),
+            _ => None
+        }
+    }
+}
+
+/// Quote and escape a string for JSON.
+pub fn string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write a number for JSON. JSON has no NaN or infinity, so those are written as null.
+pub fn number(value: f64) -> String {
+    if value.is_finite() { format!("{}", value) } else { "null".to_string() }
+}
+
+/// Reads JSON text one character at a time.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map_or(false, |c| c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(format!("expected '{}' but found '{}'", expected, c)),
+            None => Err(format!("expected '{}' but the text ended", expected))
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => string.push('\n'),
+                    Some('r') => string.push('\r'),
+                    Some('t') => string.push('\t'),
+                    Some('b') => string.push('\u{8}'),
+                    Some('f') => string.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                        let code: u32 = u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\u{}", hex))?;
+                        string.push(std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    Some(c) => string.push(c),
+                    None => return Err("unterminated string".to_string())
+                },
+                Some(c) => string.push(c),
+                None => return Err("unterminated string".to_string())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c == ',' || c == '}' || c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    self.chars.next();
+                }
+                match word.as_str() {
+                    "null" => Ok(Value::Null),
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    word => word.parse::<f64>().map(Value::Number).map_err(|_| format!("'{}' is not a string, number, true, false or null", word))
+                }
+            }
+            None => Err("expected a value but the text ended".to_string())
+        }
+    }
+}
+
+/// Parse a JSON object whose values are all strings, numbers, booleans or null (no nested objects or arrays).
+pub fn parse_object(text: &str) -> Result<HashMap<String, Value>, String> {
+    let mut parser = Parser { chars: text.chars().peekable() };
+    let mut object: HashMap<String, Value> = HashMap::new();
+    parser.expect('{')?;
+    parser.skip_whitespace();
+    if parser.chars.peek() == Some(&'}') {
+        parser.chars.next();
+    }
+    else {
+        loop {
+            let key: String = parser.string()?;
+            parser.expect(':')?;
+            object.insert(key, parser.value()?);
+            parser.skip_whitespace();
+            match parser.chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                Some(c) => return Err(format!("expected ',' or '}}' but found '{}'", c)),
+                None => return Err("expected ',' or '}' but the text ended".to_string())
+            }
+        }
+    }
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        None => Ok(object),
+        Some(c) => Err(format!("unexpected '{}' after the object", c))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flat_objects() {
+        let object: HashMap<String, Value> = parse_object(" { \"a\" : 1.5, \"b\":\"text\", \"c\": true, \"d\": false, \"e\": null, \"f\": -2e3 } ").unwrap();
+        assert_eq!(object.len(), 6);
+        assert_eq!(object["a"], Value::Number(1.5));
+        assert_eq!(object["b"], Value::String("text".to_string()));
+        assert_eq!(object["c"], Value::Bool(true));
+        assert_eq!(object["d"], Value::Bool(false));
+        assert_eq!(object["e"], Value::Null);
+        assert_eq!(object["f"], Value::Number(-2000.0));
+        assert!(parse_object("{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        let object: HashMap<String, Value> = parse_object(r#"{"s": "a\"b\\c\/d\n\r\t\b\f", "u": "\u00e9\u0041\u20AC"}"#).unwrap();
+        assert_eq!(object["s"].as_str(), Some("a\"b\\c/d\n\r\t\u{8}\u{c}"));
+        assert_eq!(object["u"].as_str(), Some("\u{e9}A\u{20ac}"));
+    }
+
+    #[test]
+    fn round_trips_escaped_strings() {
+        let text: &str = "quote \" backslash \\ newline \n bell \u{7} \u{e9}";
+        let object: HashMap<String, Value> = parse_object(&format!("{{\"s\":{}}}", string(text))).unwrap();
+        assert_eq!(object["s"].as_str(), Some(text));
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        assert!(parse_object(r#"{"u": "\u00zz"}"#).is_err());
+        assert!(parse_object(r#"{"u": "\u00"}"#).is_err());
+        assert!(parse_object(r#"{"s": "unterminated\"#).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_garbage() {
+        assert!(parse_object("{\"a\": 1} x").is_err());
+        assert!(parse_object("{\"a\": 1}}").is_err());
+        assert!(parse_object("{\"a\": 1} ").is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_objects() {
+        assert!(parse_object("").is_err());
+        assert!(parse_object("[1]").is_err());
+        assert!(parse_object("{\"a\": 1").is_err());
+        assert!(parse_object("{\"a\" 1}").is_err());
+        assert!(parse_object("{\"a\": 1,}").is_err());
+        assert!(parse_object("{a: 1}").is_err());
+        assert!(parse_object("{\"a\": nope}").is_err());
+        assert!(parse_object("{\"a\": {\"b\": 1}}").is_err());
+    }
+
+    #[test]
+    fn writes_non_finite_numbers_as_null() {
+        assert_eq!(number(1.5), "1.5");
+        assert_eq!(number(f64::NAN), "null");
+        assert_eq!(number(f64::INFINITY), "null");
+    }
+}
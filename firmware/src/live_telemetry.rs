@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::Duration;
+use crate::http::{ Request, Response };
+use crate::json;
+use crate::state::RotatorState;
+use crate::telemetry::{ self, Telemetry, Record };
+use crate::websocket::{ WebSocket, Message };
+
+/// Fastest rate (in updates per second) that a client may ask for.
+const MAX_RATE: f64 = 50.0;
+/// Slowest rate (in updates per second) that a client may ask for.
+const MIN_RATE: f64 = 0.1;
+
+/// Streams the latest telemetry over WebSocket, so that a dashboard can plot the control loops
+/// live. Each update is a JSON object:
+///
+/// * `time` - Milliseconds since the UNIX epoch.
+///
+/// * `mode` - Rotator mode.
+///
+/// * `rotator` - Position and target, as logged by the position loop (null until the first update).
+///
+/// * `motors` - Motor power levels and battery voltage (null until the first update).
+///
+/// * `pids` - Latest update of each PID, by name, with the same columns as its telemetry file.
+///
+/// * `faults` - Descriptions of the current hardware faults.
+pub struct LiveTelemetry {
+    telemetry: Telemetry,
+    state: Arc<RotatorState>,
+    /// Updates per second sent to clients that do not ask for a rate.
+    default_rate: f64
+}
+
+impl LiveTelemetry {
+    /// Create the live telemetry stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `telemetry` - Telemetry that the control loops log to.
+    ///
+    /// * `state` - The rotator state, for the mode and faults.
+    ///
+    /// * `default_rate` - Updates per second sent to clients that do not ask for a rate.
+    pub fn new(telemetry: Telemetry, state: Arc<RotatorState>, default_rate: f64) -> LiveTelemetry {
+        LiveTelemetry { telemetry: telemetry, state: state, default_rate: default_rate.max(MIN_RATE).min(MAX_RATE) }
+    }
+
+    /// Get the latest telemetry as a JSON object.
+    pub fn snapshot(&self) -> String {
+        let mut latest: HashMap<&'static str, Record> = self.telemetry.latest();
+        let rotator: String = latest.remove("rotator").map_or("null".to_string(), |record| record.to_json());
+        let motors: String = latest.remove("motors").map_or("null".to_string(), |record| record.to_json());
+        // Everything else is a PID. Sort them so that the order is the same in every update.
+        let mut pids: Vec<(&'static str, Record)> = latest.into_iter().collect();
+        pids.sort_by_key(|&(name, _)| name);
+        let pids: Vec<String> = pids.iter().map(|(name, record)| format!("{}:{}", json::string(name), record.to_json())).collect();
+        let faults: Vec<String> = self.state.faults.active().iter().map(|fault| json::string(fault)).collect();
+        format!("{{\"time\":{},\"mode\":{},\"rotator\":{},\"motors\":{},\"pids\":{{{}}},\"faults\":[{}]}}", telemetry::now_ms(), json::string(self.state.get_mode().name()), rotator, motors, pids.join(","), faults.join(","))
+    }
+
+    /// Answer a WebSocket handshake and stream telemetry to the client until it disconnects. The
+    /// client may choose the number of updates per second with a `rate` query parameter.
+    pub fn handle(live: &Arc<LiveTelemetry>, request: &Request) -> Response {
+        let rate: f64 = match request.query.get("rate").map(|rate| rate.parse::<f64>()) {
+            None => live.default_rate,
+            Some(Ok(rate)) if rate.is_finite() => rate.max(MIN_RATE).min(MAX_RATE),
+            Some(_) => return Response::error(400, "rate must be a number of updates per second.")
+        };
+        let live_ref = Arc::clone(live);
+        Response::websocket(request, Box::new(move |socket| live_ref.stream(socket, rate)))
+    }
+
+    /// Send updates to a client until it disconnects.
+    fn stream(&self, socket: WebSocket, rate: f64) {
+        // Watch for the client closing the connection. Anything else that it sends is ignored.
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_ref = Arc::clone(&closed);
+        let receiver: WebSocket = socket.clone();
+        thread::spawn(move || {
+            loop {
+                match receiver.receive() {
+                    Ok(Message::Text(_)) | Ok(Message::Binary(_)) => {}
+                    Ok(Message::Close) | Err(_) => break
+                }
+            }
+            closed_ref.store(true, Ordering::Relaxed);
+        });
+
+        let interval = Duration::from_secs_f64(1.0 / rate);
+        while !closed.load(Ordering::Relaxed) {
+            if socket.send_text(&self.snapshot()).is_err() {
+                break;
+            }
+            thread::sleep(interval);
+        }
+        socket.close();
+    }
+}
@@ -2,6 +2,35 @@ mod thunderborg;
 mod encoder;
 mod pid;
 mod motors;
+mod output_shaper;
+mod absolute_encoder;
+mod position;
+mod config;
+mod autotune;
+mod gain_schedule;
+mod state;
+mod tuning;
+mod telemetry;
+mod analysis;
+mod identify;
+mod replay;
+mod serial;
+mod control_port;
+mod gs232;
+mod easycomm;
+mod rot2prog;
+mod json;
+mod http;
+mod tracking;
+mod api;
+mod websocket;
+mod live_telemetry;
+mod dashboard;
+mod tui;
+mod joystick;
+mod mqtt;
+mod mqtt_bridge;
+mod metrics;
 
 extern crate gpredict;
 
@@ -11,12 +40,29 @@ extern crate text_io;
 use rppal::system::DeviceInfo;
 use rppal::gpio::Gpio;
 use encoder::Encoder;
-use pid::Pid;
-use motors::Motors;
+use pid::{ Pid, PidParameters, AntiWindup };
+use motors::{ Motors, DEFAULT_SPEED_PARAMETERS, new_speed_pid };
+use output_shaper::OutputShaper;
+use config::Config;
+use gain_schedule::{ ScheduledPid, ScheduleVariable };
+use absolute_encoder::{ AbsoluteEncoder, AbsoluteEncoderError, As5600, As5048 };
+use position::PositionEstimator;
+use state::{ RotatorState, Mode, Satellite };
+use tuning::{ Tuning, PidHandle };
+use telemetry::{ Telemetry, Record };
+use gs232::Gs232;
+use easycomm::EasyComm;
+use rot2prog::Rot2Prog;
+use http::Response;
+use api::Api;
+use live_telemetry::LiveTelemetry;
+use tui::Tui;
+use metrics::Metrics;
+use rppal::spi::{ Bus, SlaveSelect };
 use std::sync::Arc;
-use std::sync::atomic::{ AtomicBool, AtomicI16, Ordering };
+use std::sync::atomic::{ AtomicBool, Ordering };
 use std::thread;
-use gpredict::{ Predict, Location, Tle };
+use gpredict::{ Predict, Location };
 
 const DRIVING_ALTITUDE_GEAR_TEETH: f64 = 7.0;
 const MAIN_ALTITUDE_GEAR_TEETH: f64 = 32.0;
@@ -25,8 +71,75 @@ const MAIN_AZIMUTH_GEAR_TEETH: f64 = 32.0;
 const ALTITUDE_GEAR_RATIO: f64 = MAIN_ALTITUDE_GEAR_TEETH / DRIVING_ALTITUDE_GEAR_TEETH;
 const AZIMUTH_GEAR_RATIO: f64 = MAIN_AZIMUTH_GEAR_TEETH / DRIVING_AZIMUTH_GEAR_TEETH;
 
-const ALTITUDE_ENCODER_STEPS_PER_REVOLUTION: f64 = 897.96;
-const AZIMUTH_ENCODER_STEPS_PER_REVOLUTION: f64 = 897.96;
+// The encoders are decoded at 4x resolution (both edges of both channels), so there are four steps per encoder line.
+const ALTITUDE_ENCODER_STEPS_PER_REVOLUTION: f64 = 4.0 * 897.96;
+const AZIMUTH_ENCODER_STEPS_PER_REVOLUTION: f64 = 4.0 * 897.96;
+
+// Angles (in degrees) of the home position. Without absolute encoders, the antenna must be at home when the firmware starts. Park mode returns here.
+const HOME_ALTITUDE: f64 = 0.0;
+const HOME_AZIMUTH: f64 = 0.0;
+
+// Settings (such as PID gains) that can be changed without recompiling.
+const CONFIG_FILE: &str = "rotator.conf";
+
+// Directory that telemetry is logged to, and the defaults for how big each file may grow (in megabytes) and how many files of each stream are kept.
+const TELEMETRY_DIRECTORY: &str = "telemetry";
+const DEFAULT_TELEMETRY_MAX_FILE_SIZE: f64 = 10.0;
+const DEFAULT_TELEMETRY_MAX_FILES: f64 = 20.0;
+
+// Address and port that PID tuning commands are accepted on, unless the config file sets tuning.address. Tuning
+// commands can change the live control loops and rewrite the config file, so only local clients are accepted by default.
+const TUNING_ADDRESS: &str = "127.0.0.1:4540";
+
+// Address and port that EasyComm commands are accepted on, unless the config file sets easycomm.address.
+const EASYCOMM_ADDRESS: &str = "0.0.0.0:4535";
+
+// Pulses per degree of Rot2Prog set commands, unless the config file sets rot2prog.resolution. It must match the setting in the controlling software.
+const DEFAULT_ROT2PROG_RESOLUTION: f64 = 1.0;
+
+// Address and port of the web server (dashboard and control API), unless the config file sets http.address.
+const HTTP_ADDRESS: &str = "0.0.0.0:8080";
+
+// Updates per second of the live telemetry WebSocket (ws://<address>/ws/telemetry), unless the config file sets websocket.rate_hz or the client asks for a rate.
+const DEFAULT_LIVE_TELEMETRY_RATE: f64 = 10.0;
+
+// Where the rotator is, for predicting where satellites are.
+// HOME:
+const LOCATION: Location = Location { lat_deg: 37.649250, lon_deg: -121.875070, alt_m: 105.0 };
+// HILL:
+//const LOCATION: Location = Location { lat_deg: 37.650444, lon_deg: -121.866836, alt_m: 171.0 };
+
+// Satellite that Track mode follows at startup, unless the config file sets track.satellite and track.tle_file.
+const DEFAULT_SATELLITE: &str = "ISS (ZARYA)";
+const DEFAULT_TLE_FILE: &str = "iss.tle";
+//const DEFAULT_SATELLITE: &str = "JUGNU";
+//const DEFAULT_TLE_FILE: &str = "jugnu.tle";
+//const DEFAULT_SATELLITE: &str = "LUSAT (LO-19)";
+//const DEFAULT_TLE_FILE: &str = "amateur.tle";
+
+// Frequencies (in MHz) of the satellite's downlink and uplink, which the TUI shows Doppler-corrected, unless the config file sets track.downlink_mhz and track.uplink_mhz. 0 hides a frequency.
+const DEFAULT_DOWNLINK_MHZ: f64 = 145.8;
+const DEFAULT_UPLINK_MHZ: f64 = 0.0;
+
+// Outputs of a replayed PID may differ from the recorded ones by this much by default (logs from
+// before the time step was logged only have times to the millisecond).
+const DEFAULT_REPLAY_TOLERANCE: f64 = 0.001;
+// Number of seconds that a step of a PID is simulated for by default.
+const DEFAULT_SIMULATION_TIME: f64 = 5.0;
+
+// Default settings for the position PIDs, used when the config file does not set them. The
+// tracking gains are used for small errors, and the slew gains once the error is over
+// SLEW_ERROR_THRESHOLD degrees (so that gotos are fast without tracking oscillating). The D term
+// acts on the measurement only, so that target steps do not kick the output.
+const DEFAULT_POSITION_PARAMETERS: PidParameters = PidParameters { p: 2.0, i: 0.5, d: 0.2, min: -1.0, max: 1.0, d_filter_time: 0.05, p_weight: 1.0, d_weight: 0.0, anti_windup: AntiWindup::BackCalculation, tracking_time: 0.5 };
+const DEFAULT_POSITION_SLEW_PARAMETERS: PidParameters = PidParameters { p: 3.0, i: 0.0, d: 0.3, min: -1.0, max: 1.0, d_filter_time: 0.05, p_weight: 1.0, d_weight: 0.0, anti_windup: AntiWindup::BackCalculation, tracking_time: 0.5 };
+const SLEW_ERROR_THRESHOLD: f64 = 5.0;
+
+// Reading of each absolute encoder (in degrees) when its axis is at 0 degrees, and whether its reading increases (1.0) or decreases (-1.0) with the axis angle.
+const ALTITUDE_ABSOLUTE_ENCODER_ZERO: f64 = 0.0;
+const ALTITUDE_ABSOLUTE_ENCODER_DIRECTION: f64 = 1.0;
+const AZIMUTH_ABSOLUTE_ENCODER_ZERO: f64 = 0.0;
+const AZIMUTH_ABSOLUTE_ENCODER_DIRECTION: f64 = 1.0;
 
 // Convert an altitude angle in degrees into a number of revolutions of the driving motor.
 fn altitude_angle_to_driving_revs(altitude_angle: f64) -> f64 {
@@ -38,27 +151,302 @@ fn azimuth_angle_to_driving_revs(azimuth_angle: f64) -> f64 {
     -(azimuth_angle / 360.0) * AZIMUTH_GEAR_RATIO
 }
 
+// Create the position estimator of an axis ("altitude" or "azimuth"), fusing its motor encoder with
+// an absolute encoder on its main gear if there is one.
+fn new_position_estimator(axis: &str, absolute_encoder: Option<Box<dyn AbsoluteEncoder + Send>>) -> PositionEstimator {
+    if axis == "altitude" {
+        PositionEstimator::new(absolute_encoder, altitude_angle_to_driving_revs(1.0), ALTITUDE_ABSOLUTE_ENCODER_ZERO, ALTITUDE_ABSOLUTE_ENCODER_DIRECTION, HOME_ALTITUDE)
+    }
+    else {
+        PositionEstimator::new(absolute_encoder, azimuth_angle_to_driving_revs(1.0), AZIMUTH_ABSOLUTE_ENCODER_ZERO, AZIMUTH_ABSOLUTE_ENCODER_DIRECTION, HOME_AZIMUTH)
+    }
+}
+
+// Create the gain-scheduled position PID of an axis. It switches from the tracking to the slew gains
+// once the error is over slew_threshold revolutions of the driving motor. The other settings come
+// from the tracking parameters.
+fn new_position_pid(tracking: PidParameters, slew: PidParameters, slew_threshold: f64) -> ScheduledPid {
+    let mut pid = Pid::new(0.0, 0.0, 0.0, -1.0, 1.0);
+    pid.set_parameters(&tracking);
+    let schedule = vec![(0.0, (tracking.p, tracking.i, tracking.d)), (slew_threshold, (slew.p, slew.i, slew.d))];
+    // The schedule always has two entries, and slew_threshold comes from a constant.
+    ScheduledPid::new(pid, ScheduleVariable::ErrorMagnitude, schedule).expect("Invalid position PID schedule!")
+}
+
+fn print_usage() {
+    println!("Usage:");
+    println!("    firmware [--console]            Run the rotator, with the full-screen interface on a terminal, or");
+    println!("                                    with line-by-line commands if --console is given.");
+    println!("    firmware autotune speed <1|2>   Relay-autotune the speed PID of motor 1 or 2.");
+    println!("    firmware autotune <altitude|azimuth>");
+    println!("                                    Relay-autotune the position PID of an axis.");
+    println!("    firmware identify speed <1|2> <log.csv>");
+    println!("                                    Fit a model of a motor's speed to a speed PID log or excitation run.");
+    println!("    firmware identify position <altitude|azimuth> <log.csv>");
+    println!("                                    Fit a model of an axis' speed to a position PID log.");
+    println!("    firmware identify excite <1|2> [prbs|chirp]");
+    println!("                                    Drive a motor with an excitation signal and fit a model of its speed.");
+    println!("    firmware replay <motor_1_speed|motor_2_speed|altitude_position|azimuth_position> <log.csv> [<tolerance>]");
+    println!("                                    Re-run a PID log through the PID as currently configured and compare the outputs.");
+    println!("    firmware replay rotator <log.csv> [<tolerance>]");
+    println!("                                    Re-run a rotator log through the position estimators and compare the angles.");
+    println!("    firmware simulate speed <1|2> <step> [<seconds>]");
+    println!("                                    Step the speed PID of a motor (in revolutions per second) against its identified model.");
+    println!("    firmware simulate position <altitude|azimuth> <step> [<seconds>]");
+    println!("                                    Step the position PID of an axis (in degrees) against its identified model.");
+    println!("    firmware analyze <log.csv> [<settling band %>] [<min step>]");
+    println!("                                    Report the step response metrics of a PID telemetry file.");
+}
+
+// Open the absolute encoder on the main gear of an axis, as named in the config file (absolute_encoder.altitude
+// and absolute_encoder.azimuth): "as5048a" for an AS5048A on SPI0, "as5600" for an AS5600 on I2C, or "off" (the
+// default). Without one, the antenna must start at its home position.
+fn open_absolute_encoder(axis: &str, sensor: &str) -> Option<Box<dyn AbsoluteEncoder + Send>> {
+    let encoder: Result<Box<dyn AbsoluteEncoder + Send>, AbsoluteEncoderError> = match sensor {
+        "off" => {
+            println!("No {} absolute encoder, assuming the antenna starts at its home position.", axis);
+            return None;
+        }
+        "as5048a" => As5048::new(Bus::Spi0, SlaveSelect::Ss0).map(|encoder| Box::new(encoder) as Box<dyn AbsoluteEncoder + Send>),
+        "as5600" => As5600::new().map(|encoder| Box::new(encoder) as Box<dyn AbsoluteEncoder + Send>),
+        _ => {
+            println!("Config: ERROR, unknown {} absolute encoder \"{}\" (expected as5048a, as5600 or off), assuming the antenna starts at its home position.", axis, sensor);
+            return None;
+        }
+    };
+    match encoder {
+        Ok(encoder) => Some(encoder),
+        Err(error) => {
+            println!("Absolute encoder: ERROR, {} {} failed its startup check ({}), assuming the antenna starts at its home position.", axis, sensor, error);
+            None
+        }
+    }
+}
+
+// Run a relay autotune as chosen by the command line arguments, then stop the motors.
+fn run_autotune(args: &[String], gpio: Arc<Gpio>, config: &mut Config) {
+    let speed_pid_1 = PidHandle::new("motor_1_speed", config.get_pid_parameters("motor_1_speed", DEFAULT_SPEED_PARAMETERS));
+    let speed_pid_2 = PidHandle::new("motor_2_speed", config.get_pid_parameters("motor_2_speed", DEFAULT_SPEED_PARAMETERS));
+    let mut motors = Motors::new(gpio, 4, 17, 18, 23, speed_pid_1, speed_pid_2, OutputShaper::from_config(config, "motor_1_shaper"), OutputShaper::from_config(config, "motor_2_shaper"), None);
+    match (args.get(0).map(|arg| arg.as_str()), args.get(1).map(|arg| arg.as_str())) {
+        (Some("speed"), Some("1")) => autotune::autotune_speed(&mut motors, 1, config),
+        (Some("speed"), Some("2")) => autotune::autotune_speed(&mut motors, 2, config),
+        (Some("altitude"), None) => autotune::autotune_position(&mut motors, 1, "altitude_position", config),
+        (Some("azimuth"), None) => autotune::autotune_position(&mut motors, 2, "azimuth_position", config),
+        _ => print_usage()
+    }
+    motors.finish();
+}
+
+// Run a system identification excitation run as chosen by the command line arguments, then stop the motors.
+fn run_excitation(args: &[String], gpio: Arc<Gpio>, config: &mut Config) {
+    let speed_pid_1 = PidHandle::new("motor_1_speed", config.get_pid_parameters("motor_1_speed", DEFAULT_SPEED_PARAMETERS));
+    let speed_pid_2 = PidHandle::new("motor_2_speed", config.get_pid_parameters("motor_2_speed", DEFAULT_SPEED_PARAMETERS));
+    let mut motors = Motors::new(gpio, 4, 17, 18, 23, speed_pid_1, speed_pid_2, OutputShaper::from_config(config, "motor_1_shaper"), OutputShaper::from_config(config, "motor_2_shaper"), None);
+    let signal: &str = args.get(1).map(|arg| arg.as_str()).unwrap_or("prbs");
+    match (args.get(0).map(|arg| arg.as_str()), signal) {
+        (Some("1"), "prbs") | (Some("1"), "chirp") => identify::excite(&mut motors, 1, signal, config),
+        (Some("2"), "prbs") | (Some("2"), "chirp") => identify::excite(&mut motors, 2, signal, config),
+        _ => print_usage()
+    }
+    motors.finish();
+}
+
+// Replay a PID log through the PID chosen by the command line arguments, as it is set up in the
+// config file, or a rotator log through the position estimators. Exits with status 1 if the
+// outputs differ, so that it can be scripted.
+fn run_replay(args: &[String], config: &Config) {
+    let tolerance: f64 = match args.get(2).map(|arg| arg.parse::<f64>()) {
+        None => DEFAULT_REPLAY_TOLERANCE,
+        Some(Ok(tolerance)) if tolerance >= 0.0 => tolerance,
+        Some(_) => {
+            println!("Replay: ERROR, the tolerance must be a number of at least 0.");
+            std::process::exit(1);
+        }
+    };
+    let path: &str = match args.get(1) {
+        Some(path) => path,
+        None => {
+            print_usage();
+            std::process::exit(1);
+        }
+    };
+
+    let passed: bool = match args.get(0).map(|arg| arg.as_str()) {
+        Some(name @ "motor_1_speed") | Some(name @ "motor_2_speed") => {
+            let mut pid = new_speed_pid(&config.get_pid_parameters(name, DEFAULT_SPEED_PARAMETERS));
+            replay::run(path, tolerance, &mut pid)
+        }
+        Some("altitude_position") => {
+            let mut pid = new_position_pid(config.get_pid_parameters("altitude_position", DEFAULT_POSITION_PARAMETERS), config.get_pid_parameters("altitude_position_slew", DEFAULT_POSITION_SLEW_PARAMETERS), altitude_angle_to_driving_revs(SLEW_ERROR_THRESHOLD).abs());
+            replay::run(path, tolerance, &mut pid)
+        }
+        Some("azimuth_position") => {
+            let mut pid = new_position_pid(config.get_pid_parameters("azimuth_position", DEFAULT_POSITION_PARAMETERS), config.get_pid_parameters("azimuth_position_slew", DEFAULT_POSITION_SLEW_PARAMETERS), azimuth_angle_to_driving_revs(SLEW_ERROR_THRESHOLD).abs());
+            replay::run(path, tolerance, &mut pid)
+        }
+        Some("rotator") => replay::run_position(path, tolerance, &new_position_estimator),
+        _ => {
+            print_usage();
+            false
+        }
+    };
+    if !passed {
+        std::process::exit(1);
+    }
+}
+
+// Simulate a step of the PID chosen by the command line arguments, as it is set up in the config
+// file, against the model that identify fitted for what it controls.
+fn run_simulation(args: &[String], config: &Config) {
+    let step: f64 = match args.get(2).map(|arg| arg.parse::<f64>()) {
+        Some(Ok(step)) if step.is_finite() && step != 0.0 => step,
+        _ => {
+            print_usage();
+            return;
+        }
+    };
+    let duration: f64 = match args.get(3).map(|arg| arg.parse::<f64>()) {
+        None => DEFAULT_SIMULATION_TIME,
+        Some(Ok(duration)) if duration > 0.0 && duration.is_finite() => duration,
+        Some(_) => {
+            println!("Simulate: ERROR, the time must be a number of seconds more than 0.");
+            return;
+        }
+    };
+
+    match (args.get(0).map(|arg| arg.as_str()), args.get(1).map(|arg| arg.as_str())) {
+        (Some("speed"), Some(motor @ "1")) | (Some("speed"), Some(motor @ "2")) => {
+            let name: String = format!("motor_{}_speed", motor);
+            let mut pid = new_speed_pid(&config.get_pid_parameters(&name, DEFAULT_SPEED_PARAMETERS));
+            identify::simulate(config, &format!("motor_{}_model", motor), &mut pid, false, step, duration);
+        }
+        (Some("position"), Some("altitude")) => {
+            let mut pid = new_position_pid(config.get_pid_parameters("altitude_position", DEFAULT_POSITION_PARAMETERS), config.get_pid_parameters("altitude_position_slew", DEFAULT_POSITION_SLEW_PARAMETERS), altitude_angle_to_driving_revs(SLEW_ERROR_THRESHOLD).abs());
+            identify::simulate(config, "altitude_model", &mut pid, true, altitude_angle_to_driving_revs(step), duration);
+        }
+        (Some("position"), Some("azimuth")) => {
+            let mut pid = new_position_pid(config.get_pid_parameters("azimuth_position", DEFAULT_POSITION_PARAMETERS), config.get_pid_parameters("azimuth_position_slew", DEFAULT_POSITION_SLEW_PARAMETERS), azimuth_angle_to_driving_revs(SLEW_ERROR_THRESHOLD).abs());
+            identify::simulate(config, "azimuth_model", &mut pid, true, azimuth_angle_to_driving_revs(step), duration);
+        }
+        _ => print_usage()
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut config = Config::load(CONFIG_FILE);
+
+    // Subcommands that work on logs run anywhere (e.g. on a laptop), so they must not touch the hardware.
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("analyze") => {
+            analysis::run(&args[2..]);
+            return;
+        }
+        Some("replay") => {
+            run_replay(&args[2..], &config);
+            return;
+        }
+        Some("simulate") => {
+            run_simulation(&args[2..], &config);
+            return;
+        }
+        Some("identify") if args.get(2).map(|arg| arg.as_str()) != Some("excite") => {
+            identify::run(&args[2..], &mut config);
+            return;
+        }
+        _ => {}
+    }
+
     println!("Running on a {}.", DeviceInfo::new().unwrap().model());
-   
+
     let finish = Arc::new(AtomicBool::new(false));
-    let go_home = Arc::new(AtomicBool::new(false));
-    // let target_altitude = Arc::new(AtomicI16::new(90));
-    let target_altitude = Arc::new(AtomicI16::new(0));
-    let target_azimuth = Arc::new(AtomicI16::new(0));
 
     let gpio = Arc::new(Gpio::new().unwrap());
 
-    let mut motors = Motors::new(Arc::clone(&gpio), 4, 17, 18, 23);
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("autotune") => {
+            run_autotune(&args[2..], gpio, &mut config);
+            return;
+        }
+        Some("identify") => {
+            run_excitation(&args[3..], gpio, &mut config);
+            return;
+        }
+        Some("--console") | None => {}
+        Some(_) => {
+            print_usage();
+            return;
+        }
+    }
+
+    let telemetry_max_file_size: u64 = (config.get_f64("telemetry.max_file_size_mb", DEFAULT_TELEMETRY_MAX_FILE_SIZE) * 1.0e6) as u64;
+    let telemetry_max_files: usize = config.get_f64("telemetry.max_files", DEFAULT_TELEMETRY_MAX_FILES) as usize;
+    let telemetry = Telemetry::start(TELEMETRY_DIRECTORY, telemetry_max_file_size, telemetry_max_files);
+
+    // Every PID can be tuned while the rotator runs, from the console or over TCP.
+    let mut tuning = Tuning::new(config);
+    let speed_pid_1 = tuning.add("motor_1_speed", DEFAULT_SPEED_PARAMETERS);
+    let speed_pid_2 = tuning.add("motor_2_speed", DEFAULT_SPEED_PARAMETERS);
+    let altitude_tracking_pid = tuning.add("altitude_position", DEFAULT_POSITION_PARAMETERS);
+    let altitude_slew_pid = tuning.add_schedule_entry("altitude_position_slew", DEFAULT_POSITION_SLEW_PARAMETERS);
+    let azimuth_tracking_pid = tuning.add("azimuth_position", DEFAULT_POSITION_PARAMETERS);
+    let azimuth_slew_pid = tuning.add_schedule_entry("azimuth_position_slew", DEFAULT_POSITION_SLEW_PARAMETERS);
+    let tuning = Arc::new(tuning);
+    let tuning_address: String = tuning.config().get_str("tuning.address", TUNING_ADDRESS).to_string();
+    tuning::serve(Arc::clone(&tuning), &tuning_address);
+
+    let (output_shaper_1, output_shaper_2) = {
+        let config = tuning.config();
+        (OutputShaper::from_config(&config, "motor_1_shaper"), OutputShaper::from_config(&config, "motor_2_shaper"))
+    };
+    let mut motors = Motors::new(Arc::clone(&gpio), 4, 17, 18, 23, speed_pid_1, speed_pid_2, output_shaper_1, output_shaper_2, Some(telemetry.clone()));
+
+    let altitude_absolute_encoder = open_absolute_encoder("altitude", tuning.config().get_str("absolute_encoder.altitude", "off"));
+    let azimuth_absolute_encoder = open_absolute_encoder("azimuth", tuning.config().get_str("absolute_encoder.azimuth", "off"));
+    let mut altitude_position = new_position_estimator("altitude", altitude_absolute_encoder);
+    let mut azimuth_position = new_position_estimator("azimuth", azimuth_absolute_encoder);
+    println!("Starting at altitude {:.1} degrees, azimuth {:.1} degrees.", altitude_position.get_angle(), azimuth_position.get_angle());
+    if !altitude_position.has_absolute_encoder() || !azimuth_position.has_absolute_encoder() {
+        println!("Gear slip detection is only active on axes with an absolute encoder.");
+    }
+
+    let satellite = {
+        let config = tuning.config();
+        Satellite { name: config.get_str("track.satellite", DEFAULT_SATELLITE).to_string(), tle_file: config.get_str("track.tle_file", DEFAULT_TLE_FILE).to_string() }
+    };
+    let state = Arc::new(RotatorState::new(altitude_position.get_angle(), azimuth_position.get_angle(), satellite));
+    let metrics = Arc::new(Metrics::new(Arc::clone(&state), telemetry.clone(), motors.get_loop_timing()));
+
+    // Tracking and logging software can drive the rotator by talking to it as if it were a common rotator controller.
+    {
+        let config = tuning.config();
+        let gs232_variant = if config.get_str("gs232.variant", "B") == "A" { gs232::Variant::A } else { gs232::Variant::B };
+        control_port::serve_serial("GS-232", "gs232", &config, 9600, Box::new(Gs232::new(Arc::clone(&state), gs232_variant)));
+        control_port::serve_serial("EasyComm", "easycomm", &config, 9600, Box::new(EasyComm::new(Arc::clone(&state))));
+        let easycomm_state = Arc::clone(&state);
+        control_port::serve_tcp("EasyComm", "easycomm", &config, EASYCOMM_ADDRESS, Box::new(move || Box::new(EasyComm::new(Arc::clone(&easycomm_state)))));
+        let api = Api::new(Arc::clone(&state), &LOCATION, HOME_ALTITUDE, HOME_AZIMUTH);
+        let live_telemetry = Arc::new(LiveTelemetry::new(telemetry.clone(), Arc::clone(&state), config.get_f64("websocket.rate_hz", DEFAULT_LIVE_TELEMETRY_RATE)));
+        let http_metrics = Arc::clone(&metrics);
+        http::serve("HTTP", config.get_str("http.address", HTTP_ADDRESS), Arc::new(move |request| match request.path.as_str() {
+            "/ws/telemetry" => LiveTelemetry::handle(&live_telemetry, request),
+            _ => api.handle(request).or_else(|| http_metrics.handle(request)).or_else(|| dashboard::handle(request)).unwrap_or_else(|| Response::error(404, "Not found."))
+        }));
+        let rot2prog_resolution: u8 = config.get_f64("rot2prog.resolution", DEFAULT_ROT2PROG_RESOLUTION).max(1.0).min(10.0) as u8;
+        control_port::serve_serial("Rot2Prog", "rot2prog", &config, 600, Box::new(Rot2Prog::new(Arc::clone(&state), rot2prog_resolution)));
+        joystick::start(Arc::clone(&state), &config);
+        mqtt_bridge::start(Api::new(Arc::clone(&state), &LOCATION, HOME_ALTITUDE, HOME_AZIMUTH), &config);
+    }
 
     let finish_ref = Arc::clone(&finish);
-    let go_home_ref = Arc::clone(&go_home);
+    let state_ref = Arc::clone(&state);
     let mut control_c_presses: u8 = 0;
     ctrlc::set_handler(move || {
         control_c_presses += 1;
         if control_c_presses == 1 {
             println!("\rControl-C pressed once, returning to home position. Press again for emergency stop.");
-	        go_home_ref.store(true, Ordering::Relaxed);
+            state_ref.set_mode(Mode::Park);
         }
         else {
             println!("\rControl-C pressed twice! Stopping motors and exiting.");
@@ -66,73 +454,195 @@ fn main() {
         }
     }).expect("Failed to set Control-C handler!");
 
-    let target_altitude_ref = Arc::clone(&target_altitude);
-    let target_azimuth_ref = Arc::clone(&target_azimuth);
+    let tui_telemetry: Telemetry = telemetry.clone();
+    let state_ref = Arc::clone(&state);
     thread::spawn(move || {
         //*(altitude_encoder.steps.lock().unwrap()) = (altitude_angle_to_driving_revs(90.0) * ALTITUDE_ENCODER_STEPS_PER_REVOLUTION) as i64;
 
-        //let mut azimuth_pid = Pid::new(-2.0, -0.005, -20.0, -1.0, 1.0);
-        //let mut altitude_pid = Pid::new(-2.0, -0.005, -20.0, -1.0, 1.0);
-        let mut azimuth_pid = Pid::new(2.0, 0.005, 20.0, -1.0, 1.0);
-        let mut altitude_pid = Pid::new(2.0, 0.005, 20.0, -1.0, 1.0);
-        azimuth_pid.set_logfile("azimuth_encoder.csv");
-        altitude_pid.set_logfile("altitude_encoder.csv");
+        // Schedule entry 0 holds the tracking gains and entry 1 the slew gains.
+        let mut azimuth_pid = new_position_pid(azimuth_tracking_pid.get(), azimuth_slew_pid.get(), azimuth_angle_to_driving_revs(SLEW_ERROR_THRESHOLD).abs());
+        let mut altitude_pid = new_position_pid(altitude_tracking_pid.get(), altitude_slew_pid.get(), altitude_angle_to_driving_revs(SLEW_ERROR_THRESHOLD).abs());
+        azimuth_pid.pid_mut().set_telemetry(telemetry.clone(), "azimuth_position");
+        altitude_pid.pid_mut().set_telemetry(telemetry.clone(), "altitude_position");
+        // Motor target speeds from the previous loop, and whether each axis has been re-homed since,
+        // so that the position PIDs carry on from those speeds after the target jumps with a re-home.
+        let mut previous_altitude_motor_target_speed: f64 = 0.0;
+        let mut previous_azimuth_motor_target_speed: f64 = 0.0;
+        let mut altitude_rehomed: bool = false;
+        let mut azimuth_rehomed: bool = false;
 
         while !finish.load(Ordering::Relaxed) {
+            metrics.position_loop.tick();
             let altitude_revs: f64 = motors.get_revs_1();
             let azimuth_revs: f64 = motors.get_revs_2();
+            let altitude_angle: f64 = altitude_position.update(altitude_revs);
+            let azimuth_angle: f64 = azimuth_position.update(azimuth_revs);
+            state_ref.altitude.store(altitude_angle, Ordering::Relaxed);
+            state_ref.azimuth.store(azimuth_angle, Ordering::Relaxed);
+            if altitude_position.rehome_requested() {
+                println!("\rAltitude gears slipped by {:.1} degrees, re-homing from the absolute encoder.", altitude_position.get_discrepancy());
+                altitude_position.rehome(altitude_revs);
+                altitude_rehomed = true;
+            }
+            if azimuth_position.rehome_requested() {
+                println!("\rAzimuth gears slipped by {:.1} degrees, re-homing from the absolute encoder.", azimuth_position.get_discrepancy());
+                azimuth_position.rehome(azimuth_revs);
+                azimuth_rehomed = true;
+            }
 
-            let target_revs_driving_altitude = altitude_angle_to_driving_revs(target_altitude_ref.load(Ordering::Relaxed) as f64);
-            let altitude_motor_target_speed: f64 = altitude_pid.compute(altitude_revs, target_revs_driving_altitude);
+            state_ref.faults.battery_voltage.store(motors.get_battery_voltage(), Ordering::Relaxed);
+            state_ref.faults.altitude_encoder_errors.store(motors.get_encoder_errors_1(), Ordering::Relaxed);
+            state_ref.faults.azimuth_encoder_errors.store(motors.get_encoder_errors_2(), Ordering::Relaxed);
+            state_ref.faults.i2c_errors.store(motors.get_i2c_errors(), Ordering::Relaxed);
+            metrics.altitude_step_rate.store(motors.get_speed_1() * ALTITUDE_ENCODER_STEPS_PER_REVOLUTION, Ordering::Relaxed);
+            metrics.azimuth_step_rate.store(motors.get_speed_2() * AZIMUTH_ENCODER_STEPS_PER_REVOLUTION, Ordering::Relaxed);
+            state_ref.faults.altitude_slip_events.store(altitude_position.get_slip_events(), Ordering::Relaxed);
+            state_ref.faults.azimuth_slip_events.store(azimuth_position.get_slip_events(), Ordering::Relaxed);
+            state_ref.faults.altitude_encoder_discrepancy.store(altitude_position.get_discrepancy(), Ordering::Relaxed);
+            state_ref.faults.azimuth_encoder_discrepancy.store(azimuth_position.get_discrepancy(), Ordering::Relaxed);
+            state_ref.faults.altitude_slip.store(altitude_position.get_slip(), Ordering::Relaxed);
+            state_ref.faults.azimuth_slip.store(azimuth_position.get_slip(), Ordering::Relaxed);
+            state_ref.faults.altitude_absolute_encoder_failing.store(altitude_position.is_absolute_encoder_failing(), Ordering::Relaxed);
+            state_ref.faults.azimuth_absolute_encoder_failing.store(azimuth_position.is_absolute_encoder_failing(), Ordering::Relaxed);
 
-            let target_revs_driving_azimuth = azimuth_angle_to_driving_revs(target_azimuth_ref.load(Ordering::Relaxed) as f64);
-            let azimuth_motor_target_speed: f64 = azimuth_pid.compute(azimuth_revs, target_revs_driving_azimuth);
+            altitude_tracking_pid.apply_scheduled(&mut altitude_pid, 0);
+            altitude_slew_pid.apply_scheduled(&mut altitude_pid, 1);
+            azimuth_tracking_pid.apply_scheduled(&mut azimuth_pid, 0);
+            azimuth_slew_pid.apply_scheduled(&mut azimuth_pid, 1);
+
+            // There is no target angle while jogging or stopped, which is logged as NaN.
+            let mode: Mode = state_ref.get_mode();
+            let (altitude_motor_target_speed, azimuth_motor_target_speed, target_altitude, target_azimuth) = match mode {
+                mode @ Mode::Stop | mode @ Mode::Manual => {
+                    let (altitude_speed, azimuth_speed) = if mode == Mode::Manual {
+                        let (altitude_jog_speed, azimuth_jog_speed) = state_ref.get_jog_speeds();
+                        (altitude_angle_to_driving_revs(altitude_jog_speed), azimuth_angle_to_driving_revs(azimuth_jog_speed))
+                    }
+                    else {
+                        (0.0, 0.0)
+                    };
+                    // Keep the position PIDs following the speeds being applied, so that they take over without a jerk when a target is set again.
+                    altitude_pid.pid_mut().track(altitude_revs, altitude_revs, altitude_speed);
+                    azimuth_pid.pid_mut().track(azimuth_revs, azimuth_revs, azimuth_speed);
+                    (altitude_speed, azimuth_speed, f64::NAN, f64::NAN)
+                }
+                mode @ Mode::Goto | mode @ Mode::Track | mode @ Mode::Park => {
+                    let (target_altitude, target_azimuth) = if mode == Mode::Park {
+                        (HOME_ALTITUDE, HOME_AZIMUTH)
+                    }
+                    else {
+                        (state_ref.target_altitude.load(Ordering::Relaxed), state_ref.target_azimuth.load(Ordering::Relaxed))
+                    };
+
+                    let target_revs_driving_altitude = altitude_position.angle_to_motor_revs(target_altitude);
+                    if altitude_rehomed {
+                        altitude_pid.pid_mut().track(altitude_revs, target_revs_driving_altitude, previous_altitude_motor_target_speed);
+                    }
+                    let altitude_motor_target_speed: f64 = altitude_pid.compute(altitude_revs, target_revs_driving_altitude);
+
+                    let target_revs_driving_azimuth = azimuth_position.angle_to_motor_revs(target_azimuth);
+                    if azimuth_rehomed {
+                        azimuth_pid.pid_mut().track(azimuth_revs, target_revs_driving_azimuth, previous_azimuth_motor_target_speed);
+                    }
+                    let azimuth_motor_target_speed: f64 = azimuth_pid.compute(azimuth_revs, target_revs_driving_azimuth);
+
+                    (altitude_motor_target_speed, azimuth_motor_target_speed, target_altitude, target_azimuth)
+                }
+            };
+            telemetry.log(Record::Rotator { time: telemetry::now_ms(), source: mode.name(), target_altitude: target_altitude, target_azimuth: target_azimuth, altitude: altitude_angle, azimuth: azimuth_angle, altitude_revs: altitude_revs, azimuth_revs: azimuth_revs, altitude_absolute: altitude_position.get_absolute_reading(), azimuth_absolute: azimuth_position.get_absolute_reading() });
 
             motors.set_target_speed_1(altitude_motor_target_speed);
             motors.set_target_speed_2(azimuth_motor_target_speed);
+            previous_altitude_motor_target_speed = altitude_motor_target_speed;
+            previous_azimuth_motor_target_speed = azimuth_motor_target_speed;
+            altitude_rehomed = false;
+            azimuth_rehomed = false;
 
             std::thread::sleep(std::time::Duration::from_millis(10));
         }
 
+        // The process exits from here, so the TUI does not get to put the terminal back itself.
+        tui::restore_terminal();
+        println!("Altitude gears slipped {} times ({:.1} degrees since homing), azimuth gears slipped {} times ({:.1} degrees since homing).", altitude_position.get_slip_events(), altitude_position.get_slip(), azimuth_position.get_slip_events(), azimuth_position.get_slip());
         motors.finish();
+        telemetry.sync();
         std::process::exit(0);
     });
 
-    //let tle: Tle = Tle::from_file("JUGNU", "jugnu.tle").unwrap();
-    let tle: Tle = Tle::from_file("ISS (ZARYA)", "iss.tle").unwrap();
-    //let tle: Tle = Tle::from_file("LUSAT (LO-19)", "amateur.tle").unwrap();
-    // HOME:
-    let location: Location = Location { lat_deg: 37.649250, lon_deg: -121.875070, alt_m: 105.0 };
-    // HILL:
-    //let location: Location = Location { lat_deg: 37.650444, lon_deg: -121.866836, alt_m: 171.0 };
-    let mut predict: Predict = Predict::new(&tle, &location);
+    // Follow the satellite while in Track mode.
+    let state_ref = Arc::clone(&state);
+    thread::spawn(move || {
+        let mut satellite: Satellite = state_ref.get_satellite();
+        let mut predict: Option<Predict> = match tracking::load(&satellite, &LOCATION) {
+            Ok(predict) => Some(predict),
+            Err(error) => {
+                println!("Tracking: ERROR, {}.", error);
+                None
+            }
+        };
+
+        loop {
+            // Switch to a newly chosen satellite.
+            let chosen_satellite: Satellite = state_ref.get_satellite();
+            if chosen_satellite != satellite {
+                satellite = chosen_satellite;
+                predict = match tracking::load(&satellite, &LOCATION) {
+                    Ok(predict) => {
+                        println!("Tracking: Now following {}.", satellite.name);
+                        Some(predict)
+                    }
+                    Err(error) => {
+                        println!("Tracking: ERROR, {}.", error);
+                        None
+                    }
+                };
+            }
 
-    //predict.update(None);
-    //println!("{:#?}", predict);
+            if let (Mode::Track, Some(predict)) = (state_ref.get_mode(), predict.as_mut()) {
+                predict.update(None);
+                // Never point the antenna below the horizon, even while the satellite is.
+                state_ref.target_altitude.store(predict.sat.el_deg.max(0.0), Ordering::Relaxed);
+                state_ref.target_azimuth.store(predict.sat.az_deg, Ordering::Relaxed);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        }
+    });
 
+    // The full-screen interface runs on a terminal unless --console is given. Quitting it drops back to line-by-line commands.
+    let mut tui = {
+        let config = tuning.config();
+        Tui::new(Arc::clone(&state), tui_telemetry, &LOCATION, config.get_f64("track.downlink_mhz", DEFAULT_DOWNLINK_MHZ) * 1.0e6, config.get_f64("track.uplink_mhz", DEFAULT_UPLINK_MHZ) * 1.0e6)
+    };
+    let mut run_tui: bool = tui::available() && args.get(1).map(|arg| arg.as_str()) != Some("--console");
     loop {
-        println!("Target altitude?");
-        let target_altitude_input: i16 = read!();
-        target_altitude.store(target_altitude_input, Ordering::Relaxed);
-        println!("Target azimuth?");
-        let target_azimuth_input: i16 = read!();
-        target_azimuth.store(target_azimuth_input, Ordering::Relaxed);
-        /*
-        if go_home.load(Ordering::Relaxed) {
-            target_altitude.store(90, Ordering::Relaxed);
-	    target_azimuth.store(0, Ordering::Relaxed);
+        if run_tui {
+            if let Err(error) = tui.run() {
+                println!("TUI: ERROR, failed to set up the terminal: {}.", error);
+            }
+            run_tui = false;
+        }
+        println!("Command? (<altitude> <azimuth>, jog <altitude speed> <azimuth speed>, track, park, stop, tui or pid <tuning command>)");
+        let command: String = read!("{}\n");
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["track"] => state.set_mode(Mode::Track),
+            ["park"] => state.set_mode(Mode::Park),
+            ["stop"] => state.set_mode(Mode::Stop),
+            ["tui"] => run_tui = true,
+            ["pid", tuning_command @ ..] => println!("{}", tuning.command(&tuning_command.join(" "))),
+            ["jog", altitude_speed, azimuth_speed] => match (altitude_speed.parse::<f64>(), azimuth_speed.parse::<f64>()) {
+                (Ok(altitude_speed), Ok(azimuth_speed)) => if let Err(error) = state.jog(altitude_speed, azimuth_speed) {
+                    println!("{}", error);
+                },
+                _ => println!("Jog speeds must be numbers (degrees per second).")
+            },
+            [altitude, azimuth] => match (altitude.parse::<f64>(), azimuth.parse::<f64>()) {
+                (Ok(altitude), Ok(azimuth)) => if let Err(error) = state.goto(altitude, azimuth) {
+                    println!("{}", error);
+                },
+                _ => println!("Target altitude and azimuth must be numbers (degrees).")
+            },
+            _ => println!("Unknown command.")
         }
-        else {
-            predict.update(None);
-            println!("Altitude: {}", predict.sat.el_deg);
-            println!("Azimuth: {}", predict.sat.az_deg);
-            println!("Range rate: {} km/s", predict.sat.range_rate_km_sec);
-            let frequency_shifted: f64 = (145.800 * 1.0e6) + ((predict.sat.range_rate_km_sec * 1000.0) / 2.998e8) * (145.800 * 1.0e6);
-            println!("Doppler-shifted frequency: {} MHz", frequency_shifted / 1.0e6);
-            target_altitude.store(predict.sat.el_deg as i16, Ordering::Relaxed);
-            target_azimuth.store(predict.sat.az_deg as i16, Ordering::Relaxed);
-        }
-        std::thread::sleep(std::time::Duration::from_millis(500));
-        */
     }
 }
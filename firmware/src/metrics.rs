@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use atomicfloat::AtomicF64;
+use crate::http::{ Request, Response };
+use crate::state::{ RotatorState, Mode };
+use crate::telemetry::{ Telemetry, Record };
+
+/// Upper bounds (in seconds) of the loop period histogram buckets. The motor loop aims for 5 ms and
+/// the position loop for 10 ms, plus the time that their work takes.
+const PERIOD_BUCKETS: [f64; 12] = [0.005, 0.006, 0.0075, 0.01, 0.0125, 0.015, 0.02, 0.03, 0.05, 0.1, 0.25, 1.0];
+/// Weight of each new period in the running mean and variance, so that the jitter reflects about the last 100 loops.
+const JITTER_SMOOTHING: f64 = 0.01;
+
+struct Timing {
+    last_tick: Option<Instant>,
+    /// Number of periods in each bucket of PERIOD_BUCKETS, and then longer ones (not cumulative).
+    buckets: [u64; PERIOD_BUCKETS.len() + 1],
+    count: u64,
+    /// Sum of all periods, in seconds.
+    sum: f64,
+    /// Running mean and variance of the period, in seconds and seconds squared.
+    mean: f64,
+    variance: f64
+}
+
+impl Timing {
+    /// Add a loop period (in seconds) to the histogram and the jitter.
+    fn record(&mut self, period: f64) {
+        let bucket: usize = PERIOD_BUCKETS.iter().position(|&bound| period <= bound).unwrap_or(PERIOD_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += period;
+        if self.count == 1 {
+            self.mean = period;
+        }
+        else {
+            let difference: f64 = period - self.mean;
+            self.mean += JITTER_SMOOTHING * difference;
+            self.variance = (1.0 - JITTER_SMOOTHING) * (self.variance + JITTER_SMOOTHING * difference * difference);
+        }
+    }
+}
+
+/// Times the iterations of a control loop, so that late or irregular loops show up in the metrics.
+pub struct LoopTiming {
+    timing: Mutex<Timing>
+}
+
+impl LoopTiming {
+    pub fn new() -> LoopTiming {
+        LoopTiming { timing: Mutex::new(Timing { last_tick: None, buckets: [0; PERIOD_BUCKETS.len() + 1], count: 0, sum: 0.0, mean: 0.0, variance: 0.0 }) }
+    }
+
+    /// Call once per loop iteration.
+    pub fn tick(&self) {
+        let now: Instant = Instant::now();
+        let mut timing = self.timing.lock().unwrap();
+        if let Some(last_tick) = timing.last_tick {
+            timing.record((now - last_tick).as_secs_f64());
+        }
+        timing.last_tick = Some(now);
+    }
+
+    /// Write the histogram lines of the loop period and the jitter gauge line, labelled with the loop's name.
+    fn write(&self, name: &str, histogram: &mut String, jitter: &mut String) {
+        let timing = self.timing.lock().unwrap();
+        let mut cumulative: u64 = 0;
+        for (bound, count) in PERIOD_BUCKETS.iter().zip(timing.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(histogram, "rotator_loop_period_seconds_bucket{{loop=\"{}\",le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let _ = writeln!(histogram, "rotator_loop_period_seconds_bucket{{loop=\"{}\",le=\"+Inf\"}} {}", name, timing.count);
+        let _ = writeln!(histogram, "rotator_loop_period_seconds_sum{{loop=\"{}\"}} {}", name, value(timing.sum));
+        let _ = writeln!(histogram, "rotator_loop_period_seconds_count{{loop=\"{}\"}} {}", name, timing.count);
+        let _ = writeln!(jitter, "rotator_loop_jitter_seconds{{loop=\"{}\"}} {}", name, value(timing.variance.sqrt()));
+    }
+}
+
+/// Format a number the way Prometheus expects.
+fn value(number: f64) -> String {
+    if number.is_nan() {
+        "NaN".to_string()
+    }
+    else if number.is_infinite() {
+        if number > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    }
+    else {
+        number.to_string()
+    }
+}
+
+/// Add a metric to the exposition, with its HELP and TYPE lines.
+fn metric(output: &mut String, name: &str, kind: &str, help: &str, samples: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+    output.push_str(samples);
+}
+
+/// One sample for each axis.
+fn per_axis(name: &str, altitude: f64, azimuth: f64) -> String {
+    format!("{}{{axis=\"altitude\"}} {}\n{}{{axis=\"azimuth\"}} {}\n", name, value(altitude), name, value(azimuth))
+}
+
+/// Health of the rotator in the Prometheus text format, for long-term graphs of unattended operation.
+/// Served at `GET /metrics`.
+pub struct Metrics {
+    state: Arc<RotatorState>,
+    telemetry: Telemetry,
+    /// Speeds (in encoder steps per second) of the altitude and azimuth motor encoders.
+    pub altitude_step_rate: AtomicF64,
+    pub azimuth_step_rate: AtomicF64,
+    /// Timing of the position control loop.
+    pub position_loop: LoopTiming,
+    /// Timing of the motor speed control loop.
+    motor_loop: Arc<LoopTiming>
+}
+
+impl Metrics {
+    /// Create the metrics.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The rotator state, for the angles, mode and faults.
+    ///
+    /// * `telemetry` - Telemetry that the control loops log to, for the target errors and motor power levels.
+    ///
+    /// * `motor_loop` - Timing of the motor speed control loop.
+    pub fn new(state: Arc<RotatorState>, telemetry: Telemetry, motor_loop: Arc<LoopTiming>) -> Metrics {
+        Metrics { state: state, telemetry: telemetry, altitude_step_rate: AtomicF64::new(0.0), azimuth_step_rate: AtomicF64::new(0.0), position_loop: LoopTiming::new(), motor_loop: motor_loop }
+    }
+
+    /// Answer a request. Returns None if it is not for the metrics.
+    pub fn handle(&self, request: &Request) -> Option<Response> {
+        if request.path != "/metrics" {
+            return None;
+        }
+        if request.method != "GET" {
+            return Some(Response::error(405, "Only GET is supported."));
+        }
+        Some(Response::new(200, "text/plain; version=0.0.4", self.render().into_bytes()))
+    }
+
+    /// Get every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let state = &self.state;
+        let faults = &state.faults;
+        let latest: HashMap<&'static str, Record> = self.telemetry.latest();
+        // There is no target while stopped or jogging, which the telemetry holds as NaN.
+        let (target_altitude, target_azimuth, altitude, azimuth) = match latest.get("rotator") {
+            Some(Record::Rotator { target_altitude, target_azimuth, altitude, azimuth, .. }) => (*target_altitude, *target_azimuth, *altitude, *azimuth),
+            _ => (f64::NAN, f64::NAN, state.altitude.load(Ordering::Relaxed), state.azimuth.load(Ordering::Relaxed))
+        };
+        let (power_1, power_2) = match latest.get("motors") {
+            Some(Record::Motors { power_1, power_2, .. }) => (*power_1, *power_2),
+            _ => (0.0, 0.0)
+        };
+        let mode: Mode = state.get_mode();
+        let modes: String = Mode::ALL.iter()
+            .map(|&each| format!("rotator_mode{{mode=\"{}\"}} {}\n", each.name(), (each == mode) as u8))
+            .collect();
+
+        let mut output = String::new();
+        metric(&mut output, "rotator_angle_degrees", "gauge", "Angle that the antenna points at.", &per_axis("rotator_angle_degrees", state.altitude.load(Ordering::Relaxed), state.azimuth.load(Ordering::Relaxed)));
+        metric(&mut output, "rotator_target_angle_degrees", "gauge", "Angle that the antenna is being driven to (NaN when there is no target).", &per_axis("rotator_target_angle_degrees", target_altitude, target_azimuth));
+        metric(&mut output, "rotator_target_error_degrees", "gauge", "Target angle minus antenna angle (NaN when there is no target).", &per_axis("rotator_target_error_degrees", target_altitude - altitude, target_azimuth - azimuth));
+        metric(&mut output, "rotator_motor_power", "gauge", "Power level (from -1 to 1) sent to the motor of each axis.", &per_axis("rotator_motor_power", power_1, power_2));
+        metric(&mut output, "rotator_encoder_steps_per_second", "gauge", "Speed of the motor encoder of each axis.", &per_axis("rotator_encoder_steps_per_second", self.altitude_step_rate.load(Ordering::Relaxed), self.azimuth_step_rate.load(Ordering::Relaxed)));
+        metric(&mut output, "rotator_encoder_errors_total", "counter", "Illegal transitions seen by the motor encoder of each axis.", &per_axis("rotator_encoder_errors_total", faults.altitude_encoder_errors.load(Ordering::Relaxed) as f64, faults.azimuth_encoder_errors.load(Ordering::Relaxed) as f64));
+        metric(&mut output, "rotator_gear_slips_total", "counter", "Times that the gears of each axis have slipped and been re-homed.", &per_axis("rotator_gear_slips_total", faults.altitude_slip_events.load(Ordering::Relaxed) as f64, faults.azimuth_slip_events.load(Ordering::Relaxed) as f64));
+        metric(&mut output, "rotator_encoder_discrepancy_degrees", "gauge", "Filtered difference between the absolute and motor encoders of each axis since it was last homed.", &per_axis("rotator_encoder_discrepancy_degrees", faults.altitude_encoder_discrepancy.load(Ordering::Relaxed), faults.azimuth_encoder_discrepancy.load(Ordering::Relaxed)));
+        metric(&mut output, "rotator_gear_slip_degrees", "gauge", "How far the gears of each axis have slipped since it was last homed.", &per_axis("rotator_gear_slip_degrees", faults.altitude_slip.load(Ordering::Relaxed), faults.azimuth_slip.load(Ordering::Relaxed)));
+        metric(&mut output, "rotator_absolute_encoder_failing", "gauge", "Whether the absolute encoder of each axis is failing to read.", &per_axis("rotator_absolute_encoder_failing", faults.altitude_absolute_encoder_failing.load(Ordering::Relaxed) as u8 as f64, faults.azimuth_absolute_encoder_failing.load(Ordering::Relaxed) as u8 as f64));
+        metric(&mut output, "rotator_i2c_errors_total", "counter", "Failed I2C transfers to the motor controller.", &format!("rotator_i2c_errors_total {}\n", faults.i2c_errors.load(Ordering::Relaxed)));
+        metric(&mut output, "rotator_battery_voltage_volts", "gauge", "Voltage of the battery powering the motors (0 until the first reading).", &format!("rotator_battery_voltage_volts {}\n", value(faults.battery_voltage.load(Ordering::Relaxed))));
+        metric(&mut output, "rotator_faults", "gauge", "Number of hardware faults currently reported.", &format!("rotator_faults {}\n", faults.active().len()));
+        metric(&mut output, "rotator_mode", "gauge", "1 for the mode that the rotator is in, 0 for the others.", &modes);
+
+        let mut histogram = String::new();
+        let mut jitter = String::new();
+        self.position_loop.write("position", &mut histogram, &mut jitter);
+        self.motor_loop.write("motor", &mut histogram, &mut jitter);
+        metric(&mut output, "rotator_loop_period_seconds", "histogram", "Time between iterations of each control loop.", &histogram);
+        metric(&mut output, "rotator_loop_jitter_seconds", "gauge", "Standard deviation of the period of each control loop over about the last 100 iterations.", &jitter);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Satellite;
+
+    /// Get the value of a sample from an exposition.
+    fn sample(output: &str, name: &str) -> f64 {
+        let line: &str = output.lines().find(|line| line.starts_with(name) && line[name.len()..].starts_with(' ')).unwrap_or_else(|| panic!("no sample {}", name));
+        line[name.len() + 1..].parse::<f64>().unwrap()
+    }
+
+    /// Create a loop timing that has seen some periods (in seconds).
+    fn loop_timing(periods: &[f64]) -> LoopTiming {
+        let loop_timing = LoopTiming::new();
+        for &period in periods.iter() {
+            loop_timing.timing.lock().unwrap().record(period);
+        }
+        loop_timing
+    }
+
+    /// Create metrics for a new, stopped rotator, with telemetry written to a directory for the test.
+    fn new_metrics(test: &str) -> Metrics {
+        let state = Arc::new(RotatorState::new(45.0, 90.0, Satellite { name: "ISS".to_string(), tle_file: "iss.tle".to_string() }));
+        let directory = std::env::temp_dir().join(format!("rotator_metrics_{}_{}", test, std::process::id()));
+        let telemetry = Telemetry::start(directory.to_str().unwrap(), 1024 * 1024, 1);
+        Metrics::new(state, telemetry, Arc::new(loop_timing(&[0.005, 0.005])))
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let periods: [f64; 6] = [0.004, 0.005, 0.009, 0.02, 0.021, 2.0];
+        let mut histogram = String::new();
+        let mut jitter = String::new();
+        loop_timing(&periods).write("motor", &mut histogram, &mut jitter);
+
+        let mut previous: f64 = 0.0;
+        for bound in PERIOD_BUCKETS.iter() {
+            let count: f64 = sample(&histogram, &format!("rotator_loop_period_seconds_bucket{{loop=\"motor\",le=\"{}\"}}", bound));
+            assert!(count >= previous, "bucket {} went down", bound);
+            assert_eq!(count, periods.iter().filter(|&&period| period <= *bound).count() as f64, "bucket {}", bound);
+            previous = count;
+        }
+        assert_eq!(sample(&histogram, "rotator_loop_period_seconds_bucket{loop=\"motor\",le=\"+Inf\"}"), 6.0);
+        assert_eq!(sample(&histogram, "rotator_loop_period_seconds_count{loop=\"motor\"}"), 6.0);
+        assert!((sample(&histogram, "rotator_loop_period_seconds_sum{loop=\"motor\"}") - periods.iter().sum::<f64>()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn jitter_is_the_spread_of_the_period() {
+        let mut histogram = String::new();
+        let mut jitter = String::new();
+        loop_timing(&[0.005; 50]).write("steady", &mut histogram, &mut jitter);
+        loop_timing(&[0.005, 0.015].repeat(25)).write("irregular", &mut histogram, &mut jitter);
+        assert_eq!(sample(&jitter, "rotator_loop_jitter_seconds{loop=\"steady\"}"), 0.0);
+        let irregular: f64 = sample(&jitter, "rotator_loop_jitter_seconds{loop=\"irregular\"}");
+        assert!(irregular > 0.001 && irregular < 0.005, "jitter {}", irregular);
+        // With nothing timed yet there is no jitter either.
+        loop_timing(&[]).write("idle", &mut histogram, &mut jitter);
+        assert_eq!(sample(&jitter, "rotator_loop_jitter_seconds{loop=\"idle\"}"), 0.0);
+        assert_eq!(sample(&histogram, "rotator_loop_period_seconds_count{loop=\"idle\"}"), 0.0);
+    }
+
+    #[test]
+    fn formats_special_values() {
+        assert_eq!(value(f64::NAN), "NaN");
+        assert_eq!(value(f64::INFINITY), "+Inf");
+        assert_eq!(value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(value(0.25), "0.25");
+    }
+
+    #[test]
+    fn renders_every_metric_once() {
+        let metrics: Metrics = new_metrics("render");
+        let output: String = metrics.render();
+        for line in output.lines() {
+            if let Some(name) = line.strip_prefix("# TYPE ") {
+                let name: &str = name.split(' ').next().unwrap();
+                assert_eq!(output.matches(&format!("# TYPE {} ", name)).count(), 1, "{}", name);
+                assert!(output.contains(&format!("# HELP {} ", name)), "{}", name);
+            }
+            else if !line.starts_with('#') {
+                let (_, value) = line.rsplit_once(' ').unwrap();
+                assert!(value == "NaN" || value == "+Inf" || value == "-Inf" || value.parse::<f64>().is_ok(), "{}", line);
+            }
+        }
+        assert_eq!(sample(&output, "rotator_angle_degrees{axis=\"altitude\"}"), 45.0);
+        assert_eq!(sample(&output, "rotator_mode{mode=\"stop\"}"), 1.0);
+        assert_eq!(sample(&output, "rotator_mode{mode=\"track\"}"), 0.0);
+        assert_eq!(sample(&output, "rotator_loop_period_seconds_count{loop=\"motor\"}"), 2.0);
+        assert_eq!(sample(&output, "rotator_loop_period_seconds_count{loop=\"position\"}"), 0.0);
+    }
+
+    #[test]
+    fn renders_a_missing_target_as_nan() {
+        let metrics: Metrics = new_metrics("no_target");
+        let output: String = metrics.render();
+        assert!(output.contains("rotator_target_angle_degrees{axis=\"altitude\"} NaN\n"));
+        assert!(output.contains("rotator_target_angle_degrees{axis=\"azimuth\"} NaN\n"));
+        assert!(output.contains("rotator_target_error_degrees{axis=\"altitude\"} NaN\n"));
+
+        metrics.telemetry.log(Record::Rotator { time: 0, source: "goto", target_altitude: 30.0, target_azimuth: 120.0, altitude: 29.0, azimuth: 125.0, altitude_revs: 0.0, azimuth_revs: 0.0, altitude_absolute: f64::NAN, azimuth_absolute: f64::NAN });
+        metrics.telemetry.sync();
+        let output: String = metrics.render();
+        assert_eq!(sample(&output, "rotator_target_angle_degrees{axis=\"altitude\"}"), 30.0);
+        assert_eq!(sample(&output, "rotator_target_error_degrees{axis=\"altitude\"}"), 1.0);
+        assert_eq!(sample(&output, "rotator_target_error_degrees{axis=\"azimuth\"}"), -5.0);
+    }
+}
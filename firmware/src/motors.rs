@@ -1,15 +1,28 @@
 use std::thread;
-use crate::Pid;
+use crate::pid::{ Pid, PidParameters, AntiWindup };
+use crate::tuning::PidHandle;
+use crate::telemetry::{ self, Telemetry, Record };
 use crate::thunderborg::Thunderborg;
 use crate::Encoder;
+use crate::output_shaper::OutputShaper;
+use crate::metrics::LoopTiming;
 use atomicfloat::AtomicF64;
 use std::sync::Arc;
 use rppal::gpio::Gpio;
-use std::time::{ SystemTime, UNIX_EPOCH };
-use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
 
-const STEPS_CHANGED_BUFFER_SIZE: usize = 5;
-const STEPS_PER_REVOLUTION: f64 = 897.96;
+/// The encoders are decoded at 4x resolution (both edges of both channels), so there are four steps per encoder line.
+const STEPS_PER_REVOLUTION: f64 = 4.0 * 897.96;
+
+/// Number of control loops between battery voltage readings (the loop runs every 5 ms).
+const BATTERY_READ_INTERVAL: u32 = 200;
+
+/// Create a motor speed PID with the given settings, set up the way the motor control loop runs it.
+pub fn new_speed_pid(parameters: &PidParameters) -> Pid {
+    let mut pid = Pid::new(0.0, 0.0, 0.0, -1.0, 1.0);
+    pid.set_parameters(parameters);
+    pid
+}
 
 /// Represents a single speed-controlled motor.
 struct Motor {
@@ -17,14 +30,8 @@ struct Motor {
     encoder: Encoder,
     /// PID used to control the speed of this motor.
     pid: Pid,
-    /// Number of encoder steps from the previous time that update() was called. Used to calculate speed.
-    prev_steps: i64,
-    /// Time since Unix epoch (in microseconds) since the last time that update() was called. Used to calculate speed.
-    prev_time: u128,
-    /// Buffer of steps_changed (number of steps between calls to update()) values. Used to smooth out noise introduced by quantization error.
-    steps_changed_buffer: [i64; STEPS_CHANGED_BUFFER_SIZE],
-    /// Index to write to in steps_changed_buffer. Circles around back to 0 when it reaches STEPS_CHANGED_BUFFER_SIZE so that the buffer is "circular."
-    steps_changed_buffer_index: usize
+    /// Handle through which the speed PID's settings are changed while it runs.
+    pid_handle: Arc::<PidHandle>
 }
 
 impl Motor {
@@ -37,11 +44,13 @@ impl Motor {
     /// * `encoder_channel_a_pin` - GPIO pin number for channel A of the quadrature encoder.
     ///
     /// * `encoder_channel_b_pin` - GPIO pin number for channel B of the quadrature encoder.
-    pub fn new(gpio: Arc::<Gpio>, encoder_channel_a_pin: u8, encoder_channel_b_pin: u8) -> Motor {
+    ///
+    /// * `speed_pid` - Handle holding the settings of the speed PID.
+    pub fn new(gpio: Arc::<Gpio>, encoder_channel_a_pin: u8, encoder_channel_b_pin: u8, speed_pid: Arc::<PidHandle>) -> Motor {
         let encoder = Encoder::new(gpio, encoder_channel_a_pin, encoder_channel_b_pin);
-        let mut pid = Pid::new(-2.0, -0.025, -1.8, -1.0, 1.0);
+        let pid = new_speed_pid(&speed_pid.get());
 
-        Motor { encoder: encoder, pid: pid, prev_steps: 0, prev_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros(), steps_changed_buffer: [0_i64; STEPS_CHANGED_BUFFER_SIZE], steps_changed_buffer_index: 0 }
+        Motor { encoder: encoder, pid: pid, pid_handle: speed_pid }
     }
 
     /// Update the state of the motor.
@@ -50,40 +59,49 @@ impl Motor {
     ///
     /// * `target_speed` - The target speed for the motor PID, given in revolutions per second.
     ///
-    /// Returns a tuple of two floats. The first float is the power level (from -1.0 to 1.0) that
-    /// should be sent to the motor. The second float is the current motor speed in revolutions per
-    /// second.
-    pub fn update(&mut self, target_speed: f64) -> (f64, f64) {
-        let time: u128 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros();
-        let time_elapsed: u128 = time - self.prev_time;
+    /// Returns a tuple of three floats. The first float is the power level (from -1.0 to 1.0) that
+    /// should be sent to the motor. The second float is the total number of revolutions done by the
+    /// motor. The third float is the current motor speed in revolutions per second.
+    pub fn update(&mut self, target_speed: f64) -> (f64, f64, f64) {
+        // The encoder measures speed from the time between its edges, which tracks low speeds far
+        // better than counting the steps that happened since the last update.
+        let speed: f64 = self.encoder.get_speed() / STEPS_PER_REVOLUTION;
+        let steps: i64 = self.encoder.get_steps();
 
-        let steps: i64 = *self.encoder.steps.lock().unwrap();
-        let steps_changed: i64 = steps - self.prev_steps;
-        self.steps_changed_buffer[self.steps_changed_buffer_index] = steps_changed;
-        self.steps_changed_buffer_index += 1;
-        if self.steps_changed_buffer_index == STEPS_CHANGED_BUFFER_SIZE {
-            self.steps_changed_buffer_index = 0;
-        }
+        self.pid_handle.apply(&mut self.pid);
+        let power: f64 = self.pid.compute(speed, target_speed);
 
-        let mut steps_changed_averaged: f64 = 0.0;
-        for i in 0..STEPS_CHANGED_BUFFER_SIZE {
-            steps_changed_averaged += self.steps_changed_buffer[i] as f64;
-        }
-        steps_changed_averaged /= STEPS_CHANGED_BUFFER_SIZE as f64;
+        let revs: f64 = steps as f64 / STEPS_PER_REVOLUTION;
 
-        let speed: f64 = (steps_changed_averaged / (time_elapsed as f64 / 1.0e6)) / STEPS_PER_REVOLUTION;
+        (power, revs, speed)
+    }
 
-        self.prev_time = time;
-        self.prev_steps = steps;
+    /// Make the speed PID follow a power level that something else is driving the motor at, so that it takes back control without a jerk.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_speed` - The target speed for the motor PID, given in revolutions per second.
+    ///
+    /// * `power` - The power level (from -1.0 to 1.0) being sent to the motor.
+    pub fn track(&mut self, target_speed: f64, power: f64) {
+        let speed: f64 = self.encoder.get_speed() / STEPS_PER_REVOLUTION;
+        self.pid.track(speed, target_speed, power);
+    }
 
-        let power: f64 = self.pid.compute(speed, target_speed);
-        
-        let revs: f64 = steps as f64 / STEPS_PER_REVOLUTION;
+    /// Log every update of the speed PID to telemetry under `name`.
+    pub fn set_telemetry(&mut self, telemetry: Telemetry, name: &'static str) {
+        self.pid.set_telemetry(telemetry, name);
+    }
 
-        (power, revs)
+    /// Get the number of illegal transitions seen by this motor's encoder.
+    pub fn get_encoder_errors(&self) -> u64 {
+        self.encoder.get_errors()
     }
 }
 
+/// Default settings for the motor speed PIDs, used when the config file does not set them.
+pub const DEFAULT_SPEED_PARAMETERS: PidParameters = PidParameters { p: -2.0, i: -5.0, d: -0.009, min: -1.0, max: 1.0, d_filter_time: 0.02, p_weight: 1.0, d_weight: 1.0, anti_windup: AntiWindup::Clamping, tracking_time: 0.5 };
+
 /// Represents two speed-controlled motors. Does NOT handle position control, only speed. This struct's job is to keep the two motors running as close to their target speeds as possible.
 pub struct Motors {
     /// Set this to true to stop the motors.
@@ -96,6 +114,24 @@ pub struct Motors {
     revs_1: Arc::<AtomicF64>,
     /// Total number of revolutions done by motor 2 (decreases if the motor turns backwards, increases if it turns forwards).
     revs_2: Arc::<AtomicF64>,
+    /// Current speed (in revolutions per second) of motor 1.
+    speed_1: Arc::<AtomicF64>,
+    /// Current speed (in revolutions per second) of motor 2.
+    speed_2: Arc::<AtomicF64>,
+    /// Power level sent straight to motor 1, bypassing its speed PID. NaN when the speed PID is in control.
+    power_override_1: Arc::<AtomicF64>,
+    /// Power level sent straight to motor 2, bypassing its speed PID. NaN when the speed PID is in control.
+    power_override_2: Arc::<AtomicF64>,
+    /// Number of illegal transitions seen by the encoder of motor 1.
+    encoder_errors_1: Arc::<AtomicU64>,
+    /// Number of illegal transitions seen by the encoder of motor 2.
+    encoder_errors_2: Arc::<AtomicU64>,
+    /// Number of failed I2C transfers to the Thunderborg.
+    i2c_errors: Arc::<AtomicU64>,
+    /// Latest battery voltage reading.
+    battery_voltage: Arc::<AtomicF64>,
+    /// Timing of the motor control loop.
+    loop_timing: Arc::<LoopTiming>,
     /// Handle for the thread that runs the motor speed PIDs.
     control_thread: thread::JoinHandle::<()>
 }
@@ -114,40 +150,110 @@ impl Motors {
     /// * `encoder_2_channel_a_pin` - GPIO pin number for channel A of the encoder for motor 2.
     ///
     /// * `encoder_2_channel_b_pin` - GPIO pin number for channel B of the encoder for motor 2.
-    pub fn new(gpio: Arc::<Gpio>, encoder_1_channel_a_pin: u8, encoder_1_channel_b_pin: u8, encoder_2_channel_a_pin: u8, encoder_2_channel_b_pin: u8) -> Motors {
+    ///
+    /// * `speed_pid_1` - Handle holding the settings of the speed PID of motor 1.
+    ///
+    /// * `speed_pid_2` - Handle holding the settings of the speed PID of motor 2.
+    ///
+    /// * `output_shaper_1` - Shapes the power levels sent to motor 1.
+    ///
+    /// * `output_shaper_2` - Shapes the power levels sent to motor 2.
+    ///
+    /// * `telemetry` - Telemetry that the speed PIDs, motor power levels and battery voltage are logged to, if any.
+    pub fn new(gpio: Arc::<Gpio>, encoder_1_channel_a_pin: u8, encoder_1_channel_b_pin: u8, encoder_2_channel_a_pin: u8, encoder_2_channel_b_pin: u8, speed_pid_1: Arc::<PidHandle>, speed_pid_2: Arc::<PidHandle>, mut output_shaper_1: OutputShaper, mut output_shaper_2: OutputShaper, telemetry: Option<Telemetry>) -> Motors {
         let finish = Arc::new(AtomicBool::new(false));
         let target_speed_1 = Arc::new(AtomicF64::new(0.0));
         let target_speed_2 = Arc::new(AtomicF64::new(0.0));
         let revs_1 = Arc::new(AtomicF64::new(0.0));
         let revs_2 = Arc::new(AtomicF64::new(0.0));
+        let speed_1 = Arc::new(AtomicF64::new(0.0));
+        let speed_2 = Arc::new(AtomicF64::new(0.0));
+        let power_override_1 = Arc::new(AtomicF64::new(f64::NAN));
+        let power_override_2 = Arc::new(AtomicF64::new(f64::NAN));
+        let encoder_errors_1 = Arc::new(AtomicU64::new(0));
+        let encoder_errors_2 = Arc::new(AtomicU64::new(0));
+        let i2c_errors = Arc::new(AtomicU64::new(0));
+        let battery_voltage = Arc::new(AtomicF64::new(0.0));
+        let loop_timing = Arc::new(LoopTiming::new());
 
         let finish_ref = Arc::clone(&finish);
         let target_speed_1_ref = Arc::clone(&target_speed_1);
         let target_speed_2_ref = Arc::clone(&target_speed_2);
         let revs_1_ref = Arc::clone(&revs_1);
         let revs_2_ref = Arc::clone(&revs_2);
+        let speed_1_ref = Arc::clone(&speed_1);
+        let speed_2_ref = Arc::clone(&speed_2);
+        let power_override_1_ref = Arc::clone(&power_override_1);
+        let power_override_2_ref = Arc::clone(&power_override_2);
+        let encoder_errors_1_ref = Arc::clone(&encoder_errors_1);
+        let encoder_errors_2_ref = Arc::clone(&encoder_errors_2);
+        let i2c_errors_ref = Arc::clone(&i2c_errors);
+        let battery_voltage_ref = Arc::clone(&battery_voltage);
+        let loop_timing_ref = Arc::clone(&loop_timing);
         let control_thread = thread::spawn(move || {
             let mut thunderborg = Thunderborg::new(0x19);
-            let mut motor_1 = Motor::new(Arc::clone(&gpio), encoder_1_channel_a_pin, encoder_1_channel_b_pin);
-            let mut motor_2 = Motor::new(Arc::clone(&gpio), encoder_2_channel_a_pin, encoder_2_channel_b_pin);
+            let mut motor_1 = Motor::new(Arc::clone(&gpio), encoder_1_channel_a_pin, encoder_1_channel_b_pin, speed_pid_1);
+            let mut motor_2 = Motor::new(Arc::clone(&gpio), encoder_2_channel_a_pin, encoder_2_channel_b_pin, speed_pid_2);
+            if let Some(ref telemetry) = telemetry {
+                motor_1.set_telemetry(telemetry.clone(), "motor_1_speed");
+                motor_2.set_telemetry(telemetry.clone(), "motor_2_speed");
+            }
+            let mut loops_since_battery_read: u32 = BATTERY_READ_INTERVAL;
 
             while !finish_ref.load(Ordering::Relaxed) {
-                let (power_1, revs_1) = motor_1.update(target_speed_1_ref.load(Ordering::Relaxed));
-                let (power_2, revs_2) = motor_2.update(target_speed_2_ref.load(Ordering::Relaxed));
+                loop_timing_ref.tick();
+                let target_speed_1 = target_speed_1_ref.load(Ordering::Relaxed);
+                let target_speed_2 = target_speed_2_ref.load(Ordering::Relaxed);
+                let (mut power_1, revs_1, speed_1) = motor_1.update(target_speed_1);
+                let (mut power_2, revs_2, speed_2) = motor_2.update(target_speed_2);
+
+                let power_override_1 = power_override_1_ref.load(Ordering::Relaxed);
+                if !power_override_1.is_nan() {
+                    power_1 = power_override_1;
+                    motor_1.track(target_speed_1, power_1);
+                }
+                let power_override_2 = power_override_2_ref.load(Ordering::Relaxed);
+                if !power_override_2.is_nan() {
+                    power_2 = power_override_2;
+                    motor_2.track(target_speed_2, power_2);
+                }
+
+                let shaped_power_1: f64 = output_shaper_1.shape(power_1);
+                let shaped_power_2: f64 = output_shaper_2.shape(power_2);
+                thunderborg.set_motor_1(shaped_power_1);
+                thunderborg.set_motor_2(shaped_power_2);
+
+                loops_since_battery_read += 1;
+                if loops_since_battery_read >= BATTERY_READ_INTERVAL {
+                    // Keep the last good reading if this one failed.
+                    let battery_voltage: f64 = thunderborg.get_battery_voltage();
+                    if !battery_voltage.is_nan() {
+                        battery_voltage_ref.store(battery_voltage, Ordering::Relaxed);
+                    }
+                    loops_since_battery_read = 0;
+                }
+                if let Some(ref telemetry) = telemetry {
+                    telemetry.log(Record::Motors { time: telemetry::now_ms(), power_1: shaped_power_1, power_2: shaped_power_2, battery_voltage: battery_voltage_ref.load(Ordering::Relaxed) });
+                }
 
-                thunderborg.set_motor_1(power_1);
-                thunderborg.set_motor_2(power_2);
                 revs_1_ref.store(revs_1, Ordering::Relaxed);
                 revs_2_ref.store(revs_2, Ordering::Relaxed);
+                speed_1_ref.store(speed_1, Ordering::Relaxed);
+                speed_2_ref.store(speed_2, Ordering::Relaxed);
+                encoder_errors_1_ref.store(motor_1.get_encoder_errors(), Ordering::Relaxed);
+                encoder_errors_2_ref.store(motor_2.get_encoder_errors(), Ordering::Relaxed);
+                i2c_errors_ref.store(thunderborg.get_errors(), Ordering::Relaxed);
 
                 std::thread::sleep(std::time::Duration::from_millis(5));
             }
 
             thunderborg.set_motor_1(0.0);
             thunderborg.set_motor_2(0.0);
+
+            println!("Motors: Encoder 1 saw {} illegal transitions, encoder 2 saw {}.", motor_1.get_encoder_errors(), motor_2.get_encoder_errors());
         });
 
-        Motors { finish: finish, target_speed_1: target_speed_1, target_speed_2: target_speed_2, revs_1: revs_1, revs_2: revs_2, control_thread: control_thread }
+        Motors { finish: finish, target_speed_1: target_speed_1, target_speed_2: target_speed_2, revs_1: revs_1, revs_2: revs_2, speed_1: speed_1, speed_2: speed_2, power_override_1: power_override_1, power_override_2: power_override_2, encoder_errors_1: encoder_errors_1, encoder_errors_2: encoder_errors_2, i2c_errors: i2c_errors, battery_voltage: battery_voltage, loop_timing: loop_timing, control_thread: control_thread }
     }
 
     /// Set the target speed (in revolutions per second) for the speed PID of motor 1.
@@ -170,6 +276,51 @@ impl Motors {
         return self.revs_2.load(Ordering::Relaxed);
     }
 
+    /// Get the current speed (in revolutions per second) of motor 1.
+    pub fn get_speed_1(&mut self) -> f64 {
+        return self.speed_1.load(Ordering::Relaxed);
+    }
+
+    /// Get the current speed (in revolutions per second) of motor 2.
+    pub fn get_speed_2(&mut self) -> f64 {
+        return self.speed_2.load(Ordering::Relaxed);
+    }
+
+    /// Send a power level (from -1.0 to 1.0) straight to motor 1, bypassing its speed PID. Pass None to hand control back to the speed PID.
+    pub fn set_power_override_1(&mut self, power: Option<f64>) {
+        self.power_override_1.store(power.unwrap_or(f64::NAN), Ordering::Relaxed);
+    }
+
+    /// Send a power level (from -1.0 to 1.0) straight to motor 2, bypassing its speed PID. Pass None to hand control back to the speed PID.
+    pub fn set_power_override_2(&mut self, power: Option<f64>) {
+        self.power_override_2.store(power.unwrap_or(f64::NAN), Ordering::Relaxed);
+    }
+
+    /// Get the number of illegal transitions seen by the encoder of motor 1. A count that keeps rising points to noisy encoder wiring.
+    pub fn get_encoder_errors_1(&mut self) -> u64 {
+        return self.encoder_errors_1.load(Ordering::Relaxed);
+    }
+
+    /// Get the number of illegal transitions seen by the encoder of motor 2. A count that keeps rising points to noisy encoder wiring.
+    pub fn get_encoder_errors_2(&mut self) -> u64 {
+        return self.encoder_errors_2.load(Ordering::Relaxed);
+    }
+
+    /// Get the number of failed I2C transfers to the Thunderborg. The motor control loop carries on through them, but a count that keeps rising points to a bad I2C connection.
+    pub fn get_i2c_errors(&mut self) -> u64 {
+        return self.i2c_errors.load(Ordering::Relaxed);
+    }
+
+    /// Get the timing of the motor control loop.
+    pub fn get_loop_timing(&self) -> Arc<LoopTiming> {
+        Arc::clone(&self.loop_timing)
+    }
+
+    /// Get the latest reading (in volts) of the battery powering the motors. Read about once a second.
+    pub fn get_battery_voltage(&mut self) -> f64 {
+        return self.battery_voltage.load(Ordering::Relaxed);
+    }
+
     /// Stop the motors and end the motor control thread.
     pub fn finish(self) {
         self.finish.store(true, Ordering::Relaxed);
@@ -0,0 +1,411 @@
+use std::io::{ self, Read, Write };
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::time::{ Duration, Instant };
+
+/// Longest time to wait for the broker to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest packet accepted from the broker, in bytes. Commands are short.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+// Packet types (MQTT 3.1.1, section 2.2.1), shifted into the top of the first byte.
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xC0;
+
+/// Append a string, prefixed with its length.
+fn push_string(packet: &mut Vec<u8>, text: &[u8]) {
+    packet.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    packet.extend_from_slice(text);
+}
+
+/// Make a packet from its first byte and the rest of it, adding the remaining length in between.
+fn packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet: Vec<u8> = vec![first_byte];
+    let mut length: usize = body.len();
+    loop {
+        let mut byte: u8 = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// A message published to a topic that the client subscribed to.
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>
+}
+
+/// A minimal MQTT 3.1.1 client. Everything is sent at QoS 0, which is all that status updates and
+/// rotator commands need: a lost update is replaced by the next one.
+pub struct Client {
+    stream: TcpStream,
+    /// Bytes received that do not yet make a whole packet.
+    buffer: Vec<u8>,
+    /// Identifier of the next SUBSCRIBE packet.
+    next_packet_id: u16,
+    keep_alive: Duration,
+    /// When a packet was last sent, so that the broker can be pinged before it gives up on the client.
+    last_sent: Instant
+}
+
+impl Client {
+    /// Connect to a broker. The session is clean, so subscriptions must be made again after reconnecting.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Host and port of the broker, e.g. "localhost:1883".
+    ///
+    /// * `client_id` - Name that the client is known to the broker by.
+    ///
+    /// * `username` - User name to log in with, or "" to not log in.
+    ///
+    /// * `password` - Password to log in with, or "" for none.
+    ///
+    /// * `keep_alive` - Longest time between packets, after which the broker assumes that the client has gone.
+    ///
+    /// * `will` - Topic and payload of a retained message that the broker publishes if the client goes without disconnecting.
+    pub fn connect(address: &str, client_id: &str, username: &str, password: &str, keep_alive: Duration, will: Option<(&str, &str)>) -> io::Result<Client> {
+        let socket_address = address.to_socket_addrs()?.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the broker"))?;
+        let stream = TcpStream::connect_timeout(&socket_address, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        // Sends are not allowed to hang forever on a broker that has gone away.
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+
+        let mut flags: u8 = 0x02; // Clean session
+        let mut payload: Vec<u8> = Vec::new();
+        push_string(&mut payload, client_id.as_bytes());
+        if let Some((topic, message)) = will {
+            flags |= 0x04 | 0x20; // Will, retained
+            push_string(&mut payload, topic.as_bytes());
+            push_string(&mut payload, message.as_bytes());
+        }
+        if !username.is_empty() {
+            flags |= 0x80;
+            push_string(&mut payload, username.as_bytes());
+            if !password.is_empty() {
+                flags |= 0x40;
+                push_string(&mut payload, password.as_bytes());
+            }
+        }
+        let mut body: Vec<u8> = Vec::new();
+        push_string(&mut body, b"MQTT");
+        body.push(4); // Protocol level 3.1.1
+        body.push(flags);
+        body.extend_from_slice(&(keep_alive.as_secs().min(u16::MAX as u64) as u16).to_be_bytes());
+        body.extend_from_slice(&payload);
+
+        let mut client = Client { stream: stream, buffer: Vec::new(), next_packet_id: 1, keep_alive: keep_alive, last_sent: Instant::now() };
+        client.send(&packet(CONNECT, &body))?;
+        match client.read_packet(CONNECT_TIMEOUT)? {
+            Some((first_byte, body)) if first_byte & 0xF0 == CONNACK && body.len() >= 2 => match body[1] {
+                0 => Ok(client),
+                1 => Err(io::Error::new(io::ErrorKind::Other, "broker does not speak MQTT 3.1.1")),
+                2 => Err(io::Error::new(io::ErrorKind::Other, "client ID refused")),
+                3 => Err(io::Error::new(io::ErrorKind::Other, "broker unavailable")),
+                4 | 5 => Err(io::Error::new(io::ErrorKind::PermissionDenied, "not authorised")),
+                code => Err(io::Error::new(io::ErrorKind::Other, format!("connection refused (code {})", code)))
+            },
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "expected CONNACK")),
+            None => Err(io::Error::new(io::ErrorKind::TimedOut, "no CONNACK from the broker"))
+        }
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.stream.flush()?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Publish a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `topic` - Topic to publish to.
+    ///
+    /// * `payload` - The message.
+    ///
+    /// * `retain` - Whether the broker keeps the message for clients that subscribe later.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body: Vec<u8> = Vec::with_capacity(topic.len() + payload.len() + 2);
+        push_string(&mut body, topic.as_bytes());
+        body.extend_from_slice(payload);
+        self.send(&packet(PUBLISH | retain as u8, &body))
+    }
+
+    /// Subscribe to a topic filter (which may hold + and # wildcards).
+    pub fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+        let mut body: Vec<u8> = Vec::new();
+        body.extend_from_slice(&self.next_packet_id.to_be_bytes());
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        push_string(&mut body, filter.as_bytes());
+        body.push(0); // QoS 0
+        self.send(&packet(SUBSCRIBE, &body))
+    }
+
+    /// Wait up to a time for a message on a subscribed topic, keeping the connection alive. Returns
+    /// None if none arrived. Other packets from the broker are dealt with along the way.
+    pub fn receive(&mut self, timeout: Duration) -> io::Result<Option<Message>> {
+        let deadline: Instant = Instant::now() + timeout;
+        loop {
+            if self.last_sent.elapsed() >= self.keep_alive / 2 {
+                self.send(&[PINGREQ, 0])?;
+            }
+            let now: Instant = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let (first_byte, body) = match self.read_packet(deadline - now)? {
+                Some(packet) => packet,
+                None => return Ok(None)
+            };
+            if first_byte & 0xF0 != PUBLISH {
+                // SUBACK, PINGRESP and anything else need no answer.
+                continue;
+            }
+            if body.len() < 2 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "short PUBLISH"));
+            }
+            let topic_length: usize = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let qos: u8 = (first_byte >> 1) & 0x03;
+            let payload_start: usize = 2 + topic_length + if qos > 0 { 2 } else { 0 };
+            if body.len() < payload_start {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "short PUBLISH"));
+            }
+            if qos == 1 {
+                self.send(&[PUBACK, 2, body[payload_start - 2], body[payload_start - 1]])?;
+            }
+            return Ok(Some(Message { topic: String::from_utf8_lossy(&body[2..2 + topic_length]).into_owned(), payload: body[payload_start..].to_vec() }));
+        }
+    }
+
+    /// Read the next whole packet, waiting up to a time for it. Returns its first byte and the rest
+    /// of it after the remaining length, or None if it did not all arrive in time.
+    fn read_packet(&mut self, timeout: Duration) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let deadline: Instant = Instant::now() + timeout;
+        loop {
+            // Work out the remaining length, if enough of it has arrived.
+            let mut length: usize = 0;
+            let mut header_length: Option<usize> = None;
+            for (i, &byte) in self.buffer.iter().enumerate().skip(1).take(4) {
+                length |= ((byte & 0x7F) as usize) << (7 * (i - 1));
+                if byte & 0x80 == 0 {
+                    header_length = Some(i + 1);
+                    break;
+                }
+            }
+            if header_length.is_none() && self.buffer.len() >= 5 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "bad remaining length"));
+            }
+            if length > MAX_PACKET_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large"));
+            }
+            if let Some(header_length) = header_length {
+                if self.buffer.len() >= header_length + length {
+                    let first_byte: u8 = self.buffer[0];
+                    let body: Vec<u8> = self.buffer[header_length..header_length + length].to_vec();
+                    self.buffer.drain(..header_length + length);
+                    return Ok(Some((first_byte, body)));
+                }
+            }
+
+            let now: Instant = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+            let mut chunk: [u8; 1024] = [0; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "broker closed the connection")),
+                Ok(count) => self.buffer.extend_from_slice(&chunk[..count]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(error) => return Err(error)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{ self, JoinHandle };
+
+    /// Read one whole packet, as the broker would.
+    fn read_whole_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let mut packet: Vec<u8> = vec![0];
+        stream.read_exact(&mut packet[..1]).unwrap();
+        let mut length: usize = 0;
+        for i in 0..4 {
+            let mut byte: [u8; 1] = [0];
+            stream.read_exact(&mut byte).unwrap();
+            packet.push(byte[0]);
+            length |= ((byte[0] & 0x7F) as usize) << (7 * i);
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let start: usize = packet.len();
+        packet.resize(start + length, 0);
+        stream.read_exact(&mut packet[start..]).unwrap();
+        packet
+    }
+
+    /// Start a broker that takes one connection and answers its CONNECT with a return code. Returns
+    /// the broker's address, and a thread that gives back the CONNECT packet and the connection.
+    fn start_broker(return_code: u8) -> (String, JoinHandle<(Vec<u8>, TcpStream)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: String = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let connect: Vec<u8> = read_whole_packet(&mut stream);
+            stream.write_all(&[CONNACK, 2, 0, return_code]).unwrap();
+            (connect, stream)
+        });
+        (address, broker)
+    }
+
+    /// Connect a client to a new broker that accepts it. Returns the client and the broker's side of the connection.
+    fn connect() -> (Client, TcpStream) {
+        let (address, broker) = start_broker(0);
+        let client: Client = Client::connect(&address, "rotator", "", "", Duration::from_secs(60), None).unwrap();
+        (client, broker.join().unwrap().1)
+    }
+
+    #[test]
+    fn encodes_remaining_lengths() {
+        assert_eq!(packet(PINGREQ, &[]), vec![PINGREQ, 0]);
+        assert_eq!(&packet(PUBLISH, &[7; 127])[..3], &[PUBLISH, 0x7F, 7]);
+        assert_eq!(&packet(PUBLISH, &[7; 128])[..4], &[PUBLISH, 0x80, 0x01, 7]);
+        assert_eq!(&packet(PUBLISH, &[7; 16383])[..4], &[PUBLISH, 0xFF, 0x7F, 7]);
+        assert_eq!(&packet(PUBLISH, &[7; 16384])[..5], &[PUBLISH, 0x80, 0x80, 0x01, 7]);
+        assert_eq!(packet(PUBLISH, &[7; 16384]).len(), 16384 + 4);
+    }
+
+    #[test]
+    fn connects_with_a_will_and_login() {
+        let (address, broker) = start_broker(0);
+        Client::connect(&address, "rotator", "user", "secret", Duration::from_secs(30), Some(("rotator/online", "false"))).unwrap();
+        let (connect, _) = broker.join().unwrap();
+        let mut expected: Vec<u8> = vec![0, 4, b'M', b'Q', b'T', b'T', 4, 0x02 | 0x04 | 0x20 | 0x80 | 0x40, 0, 30];
+        for text in ["rotator", "rotator/online", "false", "user", "secret"].iter() {
+            push_string(&mut expected, text.as_bytes());
+        }
+        assert_eq!(connect, packet(CONNECT, &expected));
+    }
+
+    #[test]
+    fn connects_without_a_will_or_login() {
+        let (address, broker) = start_broker(0);
+        Client::connect(&address, "rotator", "", "ignored", Duration::from_secs(100000), None).unwrap();
+        let (connect, _) = broker.join().unwrap();
+        // The password is only sent with a user name, and the keep alive is capped to what fits.
+        assert_eq!(connect, vec![CONNECT, 19, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0xFF, 0xFF, 0, 7, b'r', b'o', b't', b'a', b't', b'o', b'r']);
+    }
+
+    #[test]
+    fn connects_with_a_user_name_and_no_password() {
+        let (address, broker) = start_broker(0);
+        Client::connect(&address, "rotator", "user", "", Duration::from_secs(30), None).unwrap();
+        let (connect, _) = broker.join().unwrap();
+        assert_eq!(connect[9], 0x02 | 0x80);
+        assert!(connect.ends_with(&[0, 4, b'u', b's', b'e', b'r']));
+    }
+
+    #[test]
+    fn reports_refused_connections() {
+        let (address, broker) = start_broker(5);
+        let error: io::Error = Client::connect(&address, "rotator", "user", "wrong", Duration::from_secs(30), None).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        broker.join().unwrap();
+    }
+
+    #[test]
+    fn reads_packets_that_arrive_in_pieces() {
+        let (mut client, mut broker) = connect();
+        // A 200 byte body needs two bytes of remaining length. Send it a few bytes at a time.
+        let mut body: Vec<u8> = Vec::new();
+        push_string(&mut body, b"rotator/command");
+        body.extend_from_slice(&[b'x'; 183]);
+        let publish: Vec<u8> = packet(PUBLISH, &body);
+        assert_eq!(&publish[..3], &[PUBLISH, 0xC8, 0x01]);
+        let sender = thread::spawn(move || {
+            for piece in publish.chunks(2) {
+                broker.write_all(piece).unwrap();
+                broker.flush().unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+            broker
+        });
+        let message: Message = client.receive(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(message.topic, "rotator/command");
+        assert_eq!(message.payload, vec![b'x'; 183]);
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn skips_other_packets_and_times_out() {
+        let (mut client, mut broker) = connect();
+        // SUBACK and PINGRESP.
+        broker.write_all(&[0x90, 3, 0, 1, 0, 0xD0, 0]).unwrap();
+        assert!(client.receive(Duration::from_millis(50)).unwrap().is_none());
+        assert!(client.buffer.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_remaining_lengths() {
+        let (mut client, mut broker) = connect();
+        broker.write_all(&[PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01]).unwrap();
+        assert_eq!(client.receive(Duration::from_secs(5)).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let (mut client, mut broker) = connect();
+        broker.write_all(&packet(PUBLISH, &[0; MAX_PACKET_SIZE + 1])[..4]).unwrap();
+        assert_eq!(client.receive(Duration::from_secs(5)).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn acknowledges_qos_1_messages() {
+        let (mut client, mut broker) = connect();
+        let mut body: Vec<u8> = Vec::new();
+        push_string(&mut body, b"rotator/mode");
+        body.extend_from_slice(&[0x12, 0x34]);
+        body.extend_from_slice(b"park");
+        broker.write_all(&packet(PUBLISH | 0x02, &body)).unwrap();
+        let message: Message = client.receive(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(message.topic, "rotator/mode");
+        assert_eq!(message.payload, b"park".to_vec());
+        assert_eq!(read_whole_packet(&mut broker), vec![PUBACK, 2, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn rejects_short_publishes() {
+        let (mut client, mut broker) = connect();
+        // QoS 1, but with no room for the packet identifier after the topic.
+        let mut body: Vec<u8> = Vec::new();
+        push_string(&mut body, b"rotator/mode");
+        broker.write_all(&packet(PUBLISH | 0x02, &body)).unwrap();
+        assert_eq!(client.receive(Duration::from_secs(5)).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn publishes_and_subscribes() {
+        let (mut client, mut broker) = connect();
+        client.publish("rotator/status", b"{}", true).unwrap();
+        assert_eq!(read_whole_packet(&mut broker), vec![PUBLISH | 0x01, 18, 0, 14, b'r', b'o', b't', b'a', b't', b'o', b'r', b'/', b's', b't', b'a', b't', b'u', b's', b'{', b'}']);
+        client.subscribe("a/#").unwrap();
+        client.subscribe("b").unwrap();
+        assert_eq!(read_whole_packet(&mut broker), vec![SUBSCRIBE, 8, 0, 1, 0, 3, b'a', b'/', b'#', 0]);
+        assert_eq!(read_whole_packet(&mut broker), vec![SUBSCRIBE, 6, 0, 2, 0, 1, b'b', 0]);
+    }
+}
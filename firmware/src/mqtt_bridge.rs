@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::io;
+use std::thread;
+use std::time::{ Duration, Instant };
+use crate::api::Api;
+use crate::config::Config;
+use crate::http::{ Request, Response };
+use crate::json;
+use crate::mqtt::{ Client, Message };
+
+/// Keep-alive interval agreed with the broker.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// Time between attempts to connect to the broker.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Seconds between status updates when the config does not say.
+const DEFAULT_STATUS_INTERVAL: f64 = 1.0;
+/// Mode names, as offered by the Home Assistant mode selector.
+const MODES: [&str; 5] = ["stop", "manual", "goto", "track", "park"];
+
+/// Connects the rotator to an MQTT broker, so that station automation can watch and drive it
+/// alongside the radios and power relays. Under the base topic (`rotator` by default):
+///
+/// * `<base>/availability` - "online", or "offline" once the rotator has gone (retained).
+///
+/// * `<base>/status` - The same JSON object as GET /api/status, published regularly (retained).
+///
+/// * `<base>/<command>/set` - Commands, where `<command>` is `mode`, `target`, `jog` or `satellite`
+///   and the payload is the same JSON object as a PUT to /api/<command>. For simple clients,
+///   `mode/set` and `satellite/set` also take a bare mode or satellite name, and `altitude/set` and
+///   `azimuth/set` take a bare angle to go to.
+///
+/// * `<base>/error` - Why the last command failed.
+///
+/// Home Assistant discovery messages are published too, so that the rotator shows up there as a
+/// device with position sensors, a mode selector, target angles and park and stop buttons.
+struct MqttBridge {
+    api: Api,
+    /// Base topic.
+    topic: String,
+    /// Prefix of Home Assistant discovery topics, or "" to not publish them.
+    discovery_prefix: String,
+    /// Client ID, which also identifies the rotator to Home Assistant.
+    client_id: String,
+    status_interval: Duration
+}
+
+impl MqttBridge {
+    /// Publish status and handle commands until the connection fails.
+    fn run(&self, client: &mut Client) -> io::Result<()> {
+        client.publish(&format!("{}/availability", self.topic), b"online", true)?;
+        if !self.discovery_prefix.is_empty() {
+            for (component, object_id, config) in self.discovery() {
+                client.publish(&format!("{}/{}/{}/{}/config", self.discovery_prefix, component, self.client_id, object_id), config.as_bytes(), true)?;
+            }
+        }
+        client.subscribe(&format!("{}/+/set", self.topic))?;
+
+        let mut next_status: Instant = Instant::now();
+        loop {
+            let now: Instant = Instant::now();
+            if now >= next_status {
+                client.publish(&format!("{}/status", self.topic), self.api.status().as_bytes(), true)?;
+                next_status = now + self.status_interval;
+                continue;
+            }
+            if let Some(message) = client.receive(next_status - now)? {
+                if let Err(error) = self.command(&message) {
+                    println!("MQTT: ERROR, {} failed: {}", message.topic, error);
+                    client.publish(&format!("{}/error", self.topic), json::string(&error).as_bytes(), false)?;
+                }
+                // Show the effect of the command straight away.
+                next_status = Instant::now();
+            }
+        }
+    }
+
+    /// Carry out a command by turning it into an API request.
+    fn command(&self, message: &Message) -> Result<(), String> {
+        let command: &str = message.topic.strip_prefix(&format!("{}/", self.topic)).and_then(|topic| topic.strip_suffix("/set")).unwrap_or("");
+        let payload: String = String::from_utf8_lossy(&message.payload).trim().to_string();
+        let (endpoint, body) = match command {
+            "mode" | "target" | "jog" | "satellite" if payload.starts_with('{') => (command, payload),
+            "mode" => ("mode", format!("{{\"mode\":{}}}", json::string(&payload.to_lowercase()))),
+            "satellite" => ("satellite", format!("{{\"name\":{}}}", json::string(&payload))),
+            "altitude" | "azimuth" => match payload.parse::<f64>() {
+                Ok(angle) if angle.is_finite() => ("target", format!("{{\"{}\":{}}}", command, angle)),
+                _ => return Err(format!("{} must be a number of degrees.", command))
+            },
+            _ => return Err("No such command.".to_string())
+        };
+        let headers: HashMap<String, String> = [("content-type".to_string(), "application/json".to_string())].iter().cloned().collect();
+        let request = Request { method: "PUT".to_string(), path: format!("/api/{}", endpoint), query: HashMap::new(), headers: headers, body: body };
+        let response: Response = self.api.handle(&request).ok_or("No such command.")?;
+        if response.status == 200 {
+            Ok(())
+        }
+        else {
+            // Pass on the message from the API's {"error": ...} body.
+            let error: String = json::parse_object(&String::from_utf8_lossy(&response.body)).ok()
+                .and_then(|body| body.get("error").and_then(|error| error.as_str()).map(|error| error.to_string()))
+                .unwrap_or_else(|| format!("Error {}.", response.status));
+            Err(error)
+        }
+    }
+
+    /// Home Assistant discovery messages, as (component, object ID, config).
+    fn discovery(&self) -> Vec<(&'static str, &'static str, String)> {
+        let common: String = format!("\"state_topic\":{},\"availability_topic\":{},\"device\":{{\"identifiers\":[{}],\"name\":\"Rotator\",\"model\":\"Antenna rotator\",\"sw_version\":{}}}",
+            json::string(&format!("{}/status", self.topic)),
+            json::string(&format!("{}/availability", self.topic)),
+            json::string(&self.client_id),
+            json::string(env!("CARGO_PKG_VERSION")));
+        let command_topic = |command: &str| json::string(&format!("{}/{}/set", self.topic, command));
+        let modes: Vec<String> = MODES.iter().map(|mode| json::string(mode)).collect();
+        let entities: Vec<(&'static str, &'static str, &str, String)> = vec![
+            ("sensor", "altitude", "Altitude", "\"unit_of_measurement\":\"°\",\"state_class\":\"measurement\",\"value_template\":\"{{ value_json.position.altitude | round(1) }}\"".to_string()),
+            ("sensor", "azimuth", "Azimuth", "\"unit_of_measurement\":\"°\",\"state_class\":\"measurement\",\"value_template\":\"{{ value_json.position.azimuth | round(1) }}\"".to_string()),
+            ("sensor", "battery_voltage", "Battery voltage", "\"device_class\":\"voltage\",\"unit_of_measurement\":\"V\",\"state_class\":\"measurement\",\"value_template\":\"{{ value_json.faults.battery_voltage | round(2) }}\"".to_string()),
+            ("binary_sensor", "fault", "Fault", "\"device_class\":\"problem\",\"value_template\":\"{{ 'ON' if value_json.faults.active else 'OFF' }}\",\"json_attributes_topic\":".to_string() + &json::string(&format!("{}/status", self.topic)) + ",\"json_attributes_template\":\"{{ {'faults': value_json.faults.active} | tojson }}\""),
+            ("select", "mode", "Mode", format!("\"command_topic\":{},\"options\":[{}],\"value_template\":\"{{{{ value_json.mode }}}}\"", command_topic("mode"), modes.join(","))),
+            ("number", "target_altitude", "Target altitude", format!("\"command_topic\":{},\"min\":-10,\"max\":190,\"step\":0.1,\"mode\":\"box\",\"unit_of_measurement\":\"°\",\"value_template\":\"{{{{ (value_json.target or value_json.position).altitude | round(1) }}}}\"", command_topic("altitude"))),
+            ("number", "target_azimuth", "Target azimuth", format!("\"command_topic\":{},\"min\":0,\"max\":360,\"step\":0.1,\"mode\":\"box\",\"unit_of_measurement\":\"°\",\"value_template\":\"{{{{ (value_json.target or value_json.position).azimuth | round(1) }}}}\"", command_topic("azimuth"))),
+            ("text", "satellite", "Satellite", format!("\"command_topic\":{},\"value_template\":\"{{{{ value_json.satellite.name }}}}\"", command_topic("satellite"))),
+            ("button", "track", "Track", format!("\"command_topic\":{},\"payload_press\":\"track\"", command_topic("mode"))),
+            ("button", "park", "Park", format!("\"command_topic\":{},\"payload_press\":\"park\"", command_topic("mode"))),
+            ("button", "stop", "Stop", format!("\"command_topic\":{},\"payload_press\":\"stop\"", command_topic("mode")))
+        ];
+        entities.into_iter().map(|(component, object_id, name, extra)| {
+            (component, object_id, format!("{{\"name\":{},\"unique_id\":{},{},{}}}", json::string(name), json::string(&format!("{}_{}", self.client_id, object_id)), common, extra))
+        }).collect()
+    }
+}
+
+/// Connect to an MQTT broker in a background thread, as set up in the config file:
+///
+/// * `mqtt.broker` - Host and port of the broker (e.g. localhost:1883), or "off".
+///
+/// * `mqtt.client_id` - Client ID, which must be different for each rotator on the broker.
+///
+/// * `mqtt.username`, `mqtt.password` - Login, if the broker needs one.
+///
+/// * `mqtt.topic` - Base topic.
+///
+/// * `mqtt.discovery_prefix` - Prefix of Home Assistant discovery topics, or "off".
+///
+/// * `mqtt.status_interval_s` - Seconds between status updates.
+///
+/// The connection is made again whenever it is lost.
+///
+/// # Arguments
+///
+/// * `api` - The API that status comes from and commands go to.
+///
+/// * `config` - The config.
+pub fn start(api: Api, config: &Config) {
+    let broker: String = config.get_str("mqtt.broker", "off").to_string();
+    if broker == "off" {
+        return;
+    }
+    let username: String = config.get_str("mqtt.username", "").to_string();
+    let password: String = config.get_str("mqtt.password", "").to_string();
+    let discovery_prefix: String = match config.get_str("mqtt.discovery_prefix", "homeassistant") {
+        "off" => String::new(),
+        prefix => prefix.to_string()
+    };
+    let bridge = MqttBridge {
+        api: api,
+        topic: config.get_str("mqtt.topic", "rotator").trim_end_matches('/').to_string(),
+        discovery_prefix: discovery_prefix,
+        client_id: config.get_str("mqtt.client_id", "rotator").to_string(),
+        status_interval: Duration::from_secs_f64(config.get_f64("mqtt.status_interval_s", DEFAULT_STATUS_INTERVAL).max(0.1))
+    };
+
+    thread::spawn(move || {
+        let availability: String = format!("{}/availability", bridge.topic);
+        // Only the first of a run of failures is reported, so that a broker that is down does not fill the log.
+        let mut reported_failure: bool = false;
+        loop {
+            match Client::connect(&broker, &bridge.client_id, &username, &password, KEEP_ALIVE, Some((&availability, "offline"))) {
+                Ok(mut client) => {
+                    println!("MQTT: Connected to {}.", broker);
+                    reported_failure = false;
+                    if let Err(error) = bridge.run(&mut client) {
+                        println!("MQTT: ERROR, lost connection to {}: {}.", broker, error);
+                    }
+                }
+                Err(error) if !reported_failure => {
+                    println!("MQTT: ERROR, failed to connect to {}: {}. Retrying every {} seconds.", broker, error, RETRY_INTERVAL.as_secs());
+                    reported_failure = true;
+                }
+                Err(_) => {}
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+    });
+}
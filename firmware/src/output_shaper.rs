@@ -0,0 +1,198 @@
+use std::time::Instant;
+use crate::config::Config;
+
+/// Smallest power level that actually turns the motors (found by slowly ramping up the power until the gears started moving).
+const DEFAULT_MIN_POWER: f64 = 0.15;
+/// Power levels from the speed PIDs smaller than this are sent to the motors as 0.0.
+const DEFAULT_ZERO_THRESHOLD: f64 = 0.01;
+/// Maximum change in motor power level per second.
+const DEFAULT_MAX_SLEW_RATE: f64 = 4.0;
+/// Number of seconds to let a motor coast before reversing its direction.
+const DEFAULT_REVERSAL_COAST_TIME: f64 = 0.05;
+
+/// Shapes the power level produced by a motor speed PID before it is sent to the motor driver.
+///
+/// Three stages are applied, in order:
+///
+/// 1. Slew-rate limiting, so that the power never changes faster than `max_slew_rate` per second.
+/// 2. Deadband compensation, so that any nonzero power is rescaled into the range where the motor
+///    actually overcomes static friction instead of buzzing in place.
+/// 3. Direction-change protection, so that the motor coasts for `reversal_coast_time` seconds
+///    before it is driven in the opposite direction (this keeps the gears from being slammed).
+pub struct OutputShaper {
+    /// Smallest power level (from 0.0 to 1.0) that actually turns the motor. Nonzero outputs are rescaled into the range `min_power..=1.0`.
+    min_power: f64,
+    /// Power levels with a magnitude below this are treated as zero rather than being pushed up to `min_power`.
+    zero_threshold: f64,
+    /// Maximum change in power level per second.
+    max_slew_rate: f64,
+    /// Number of seconds to coast (power 0.0) when the direction of the motor reverses.
+    reversal_coast_time: f64,
+    /// Slew-limited power level from the previous call to shape(), before deadband compensation.
+    prev_power: f64,
+    /// Direction (-1.0, 0.0 or 1.0) that the motor was last driven in.
+    prev_direction: f64,
+    /// Number of seconds that the motor has coasted for a direction reversal, if it is currently coasting.
+    coasted_time: Option<f64>,
+    /// Time of the previous call to shape(). Used to calculate the allowed slew.
+    prev_time: Instant
+}
+
+impl OutputShaper {
+    /// Create an output shaper.
+    ///
+    /// # Arguments
+    ///
+    /// * `min_power` - Smallest power level (from 0.0 to 1.0) that actually turns the motor.
+    ///
+    /// * `zero_threshold` - Power levels with a magnitude below this are sent to the motor as 0.0.
+    ///
+    /// * `max_slew_rate` - Maximum change in power level per second.
+    ///
+    /// * `reversal_coast_time` - Number of seconds to coast when the motor changes direction.
+    pub fn new(min_power: f64, zero_threshold: f64, max_slew_rate: f64, reversal_coast_time: f64) -> OutputShaper {
+        OutputShaper { min_power: min_power, zero_threshold: zero_threshold, max_slew_rate: max_slew_rate, reversal_coast_time: reversal_coast_time, prev_power: 0.0, prev_direction: 0.0, coasted_time: None, prev_time: Instant::now() }
+    }
+
+    /// Create an output shaper with its settings from the config file, or the defaults for any that are not set:
+    ///
+    /// * `<name>.min_power` - Smallest power level (from 0.0 to below 1.0) that actually turns the motor.
+    ///
+    /// * `<name>.zero_threshold` - Power levels with a magnitude below this are sent to the motor as 0.0.
+    ///
+    /// * `<name>.max_slew_rate` - Maximum change in power level per second.
+    ///
+    /// * `<name>.reversal_coast_time` - Number of seconds to coast when the motor changes direction.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The config.
+    ///
+    /// * `name` - Prefix of the shaper's settings in the config file, e.g. "motor_1_shaper".
+    pub fn from_config(config: &Config, name: &str) -> OutputShaper {
+        let min_power: f64 = config.get_f64(&format!("{}.min_power", name), DEFAULT_MIN_POWER);
+        let zero_threshold: f64 = config.get_f64(&format!("{}.zero_threshold", name), DEFAULT_ZERO_THRESHOLD);
+        let max_slew_rate: f64 = config.get_f64(&format!("{}.max_slew_rate", name), DEFAULT_MAX_SLEW_RATE);
+        let reversal_coast_time: f64 = config.get_f64(&format!("{}.reversal_coast_time", name), DEFAULT_REVERSAL_COAST_TIME);
+        if !(0.0..1.0).contains(&min_power) || zero_threshold < 0.0 || max_slew_rate <= 0.0 || reversal_coast_time < 0.0 {
+            println!("Config: ERROR, the settings of {} are not valid (min_power must be from 0 to below 1, max_slew_rate more than 0, and the others at least 0). Using the defaults.", name);
+            return OutputShaper::new(DEFAULT_MIN_POWER, DEFAULT_ZERO_THRESHOLD, DEFAULT_MAX_SLEW_RATE, DEFAULT_REVERSAL_COAST_TIME);
+        }
+        OutputShaper::new(min_power, zero_threshold, max_slew_rate, reversal_coast_time)
+    }
+
+    /// Shape a power level, measuring the time step since the previous call.
+    ///
+    /// # Arguments
+    ///
+    /// * `power` - Power level (from -1.0 to 1.0) requested by the speed PID.
+    ///
+    /// Returns the power level (from -1.0 to 1.0) that should be sent to the motor driver.
+    pub fn shape(&mut self, power: f64) -> f64 {
+        let time = Instant::now();
+        let time_elapsed: f64 = time.duration_since(self.prev_time).as_secs_f64();
+        self.prev_time = time;
+
+        self.shape_with_dt(power, time_elapsed)
+    }
+
+    /// Shape a power level for a known time step.
+    ///
+    /// # Arguments
+    ///
+    /// * `power` - Power level (from -1.0 to 1.0) requested by the speed PID.
+    ///
+    /// * `dt` - Number of seconds since the previous call.
+    ///
+    /// Returns the power level (from -1.0 to 1.0) that should be sent to the motor driver.
+    pub fn shape_with_dt(&mut self, power: f64, dt: f64) -> f64 {
+        let max_change: f64 = self.max_slew_rate * dt;
+        let mut change: f64 = power - self.prev_power;
+        if change > max_change {
+            change = max_change;
+        }
+        else if change < -max_change {
+            change = -max_change;
+        }
+        let power: f64 = self.prev_power + change;
+        self.prev_power = power;
+
+        if power.abs() < self.zero_threshold {
+            return 0.0;
+        }
+        let direction: f64 = power.signum();
+        let shaped: f64 = direction * (self.min_power + (1.0 - self.min_power) * power.abs().min(1.0));
+
+        if self.prev_direction != 0.0 && direction != self.prev_direction {
+            match self.coasted_time {
+                Some(coasted_time) => {
+                    let coasted_time: f64 = coasted_time + dt;
+                    if coasted_time < self.reversal_coast_time {
+                        self.coasted_time = Some(coasted_time);
+                        return 0.0;
+                    }
+                    self.coasted_time = None;
+                }
+                None => {
+                    self.coasted_time = Some(0.0);
+                    return 0.0;
+                }
+            }
+        }
+        else {
+            self.coasted_time = None;
+        }
+
+        self.prev_direction = direction;
+        shaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn lifts_small_powers_over_the_deadband() {
+        let mut shaper = OutputShaper::new(0.15, 0.01, 1000.0, 0.05);
+        assert_eq!(shaper.shape_with_dt(0.005, 0.005), 0.0);
+        assert_close(shaper.shape_with_dt(0.02, 0.005), 0.15 + 0.85 * 0.02);
+        assert_close(shaper.shape_with_dt(0.5, 0.005), 0.575);
+        assert_close(shaper.shape_with_dt(1.0, 0.005), 1.0);
+    }
+
+    #[test]
+    fn limits_slew_rate() {
+        let mut shaper = OutputShaper::new(0.0, 0.0, 4.0, 0.0);
+        assert_close(shaper.shape_with_dt(1.0, 0.01), 0.04);
+        assert_close(shaper.shape_with_dt(1.0, 0.01), 0.08);
+        assert_close(shaper.shape_with_dt(1.0, 0.1), 0.48);
+        // Coming down is limited too.
+        assert_close(shaper.shape_with_dt(0.0, 0.05), 0.28);
+    }
+
+    #[test]
+    fn coasts_before_reversing() {
+        let mut shaper = OutputShaper::new(0.0, 0.0, 1000.0, 0.05);
+        assert_close(shaper.shape_with_dt(0.5, 0.005), 0.5);
+        assert_eq!(shaper.shape_with_dt(-0.5, 0.005), 0.0);
+        assert_eq!(shaper.shape_with_dt(-0.5, 0.02), 0.0);
+        assert_eq!(shaper.shape_with_dt(-0.5, 0.02), 0.0);
+        assert_close(shaper.shape_with_dt(-0.5, 0.02), -0.5);
+        // Carrying on in the same direction needs no coasting.
+        assert_close(shaper.shape_with_dt(-0.3, 0.005), -0.3);
+    }
+
+    #[test]
+    fn coasts_when_reversing_after_a_stop() {
+        let mut shaper = OutputShaper::new(0.0, 0.01, 1000.0, 0.05);
+        shaper.shape_with_dt(0.5, 0.005);
+        assert_eq!(shaper.shape_with_dt(0.0, 0.005), 0.0);
+        // The last direction driven in was forwards, so reversing after stopping still coasts.
+        assert_eq!(shaper.shape_with_dt(-0.5, 0.005), 0.0);
+    }
+}
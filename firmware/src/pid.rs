@@ -1,8 +1,127 @@
-use std::fs::File;
-use std::path::Path;
-use std::io::Write;
-use std::time::{ SystemTime, UNIX_EPOCH };
+use std::time::Instant;
+use crate::telemetry::{ self, Telemetry, Record };
 
+/// How the PID stops its integral term from winding up while the output is saturated at `min` or `max`.
+/// In the config file and tuning commands it is a number: 0 for None, 1 for Clamping and 2 for BackCalculation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AntiWindup {
+    /// Keep integrating regardless of saturation.
+    None,
+    /// Stop integrating while the output is saturated and the error would push it further into saturation.
+    Clamping,
+    /// Bleed the integral term back by the amount of saturation, over the tracking time.
+    BackCalculation
+}
+
+impl AntiWindup {
+    /// Get the method with a number, or None if no method has that number.
+    pub fn from_number(number: f64) -> Option<AntiWindup> {
+        match number {
+            n if n == 0.0 => Some(AntiWindup::None),
+            n if n == 1.0 => Some(AntiWindup::Clamping),
+            n if n == 2.0 => Some(AntiWindup::BackCalculation),
+            _ => None
+        }
+    }
+
+    /// Get the number of the method.
+    pub fn number(&self) -> f64 {
+        match self {
+            AntiWindup::None => 0.0,
+            AntiWindup::Clamping => 1.0,
+            AntiWindup::BackCalculation => 2.0
+        }
+    }
+}
+
+/// Settings of a Pid that can be changed while it is running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PidParameters {
+    /// Proportional gain (output per unit of error).
+    pub p: f64,
+    /// Integral gain (output per unit of error per second).
+    pub i: f64,
+    /// Derivative gain (output per unit of error per second of error change).
+    pub d: f64,
+    /// Smallest output.
+    pub min: f64,
+    /// Largest output.
+    pub max: f64,
+    /// Time constant (in seconds) of the low-pass filter on the D term. Zero disables the filter.
+    pub d_filter_time: f64,
+    /// Fraction of the target used in the P term.
+    pub p_weight: f64,
+    /// Fraction of the target used in the D term. Zero is derivative on measurement.
+    pub d_weight: f64,
+    /// How the integral term is kept from winding up.
+    pub anti_windup: AntiWindup,
+    /// Time constant (in seconds) over which back-calculation unwinds the integral term (smaller unwinds faster).
+    pub tracking_time: f64
+}
+
+impl PidParameters {
+    /// Names of the parameters, as used by get(), set() and the config file.
+    pub const NAMES: [&'static str; 10] = ["p", "i", "d", "min", "max", "d_filter", "p_weight", "d_weight", "anti_windup", "tracking_time"];
+    /// Names of the gains, which are the first of NAMES.
+    pub const GAIN_NAMES: [&'static str; 3] = ["p", "i", "d"];
+
+    /// Get a parameter by name, or None if there is no parameter with that name.
+    pub fn get(&self, name: &str) -> Option<f64> {
+        match name {
+            "p" => Some(self.p),
+            "i" => Some(self.i),
+            "d" => Some(self.d),
+            "min" => Some(self.min),
+            "max" => Some(self.max),
+            "d_filter" => Some(self.d_filter_time),
+            "p_weight" => Some(self.p_weight),
+            "d_weight" => Some(self.d_weight),
+            "anti_windup" => Some(self.anti_windup.number()),
+            "tracking_time" => Some(self.tracking_time),
+            _ => None
+        }
+    }
+
+    /// Set a parameter by name. Returns false if there is no parameter with that name, or the value
+    /// is not one of the anti-windup method numbers.
+    pub fn set(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "p" => self.p = value,
+            "i" => self.i = value,
+            "d" => self.d = value,
+            "min" => self.min = value,
+            "max" => self.max = value,
+            "d_filter" => self.d_filter_time = value,
+            "p_weight" => self.p_weight = value,
+            "d_weight" => self.d_weight = value,
+            "anti_windup" => match AntiWindup::from_number(value) {
+                Some(anti_windup) => self.anti_windup = anti_windup,
+                None => return false
+            },
+            "tracking_time" => self.tracking_time = value,
+            _ => return false
+        }
+        true
+    }
+
+    /// Check that the settings make sense together. Returns why not if they do not.
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.min < self.max) {
+            return Err("min must be less than max".to_string());
+        }
+        if !(self.d_filter_time >= 0.0) {
+            return Err("d_filter must not be negative".to_string());
+        }
+        if !(self.tracking_time > 0.0) {
+            return Err("tracking_time must be more than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// PID controller. The gains are in physical units so that they do not depend on how often
+/// compute() is called: `p` is output per unit of error, `i` is output per unit of error per
+/// second, and `d` is output per unit of error per second of error change (i.e. seconds).
 pub struct Pid {
     p: f64,
     i: f64,
@@ -11,27 +130,78 @@ pub struct Pid {
     max: f64,
     i_accumulator: f64,
     first_time: bool,
-    previous_error: f64,
-    logfile: Option<File>
+    /// Anti-windup method used on the integral term.
+    anti_windup: AntiWindup,
+    /// Time constant (in seconds) of back-calculation anti-windup.
+    tracking_time: f64,
+    /// Fraction of the target used in the P term (the P term acts on `p_weight * target - value`).
+    p_weight: f64,
+    /// Fraction of the target used in the D term (the D term acts on `d_weight * target - value`). Zero means derivative on measurement.
+    d_weight: f64,
+    /// Time constant (in seconds) of the low-pass filter on the D term. Zero disables the filter.
+    d_filter_time: f64,
+    /// Value that the P term acted on during the previous call (`p_weight * target - value`).
+    previous_p_input: f64,
+    /// Value that the D term differentiated on the previous call (`d_weight * target - value`).
+    previous_d_input: f64,
+    /// Filtered D term from the previous call.
+    d_filtered: f64,
+    /// Time of the previous call to compute(). Used to measure the time step.
+    previous_time: Instant,
+    /// Where each update is logged, and the name that it is logged under.
+    telemetry: Option<(Telemetry, &'static str)>
 }
 
 impl Pid {
     pub fn new(p: f64, i: f64, d: f64, min: f64, max: f64) -> Self {
-        Pid { p: p, i: i, d: d, min: min, max: max, i_accumulator: 0.0, first_time: true, previous_error: 0.0, logfile: None }
+        Pid { p: p, i: i, d: d, min: min, max: max, i_accumulator: 0.0, first_time: true, anti_windup: AntiWindup::None, tracking_time: 1.0, p_weight: 1.0, d_weight: 1.0, d_filter_time: 0.0, previous_p_input: 0.0, previous_d_input: 0.0, d_filtered: 0.0, previous_time: Instant::now(), telemetry: None }
     }
 
+    /// Compute the output of the PID, measuring the time step since the previous call.
     pub fn compute(&mut self, value: f64, target_value: f64) -> f64 {
+        let time = Instant::now();
+        // There is no previous call to measure from the first time (or after a reset).
+        let dt: f64 = if self.first_time { 0.0 } else { time.duration_since(self.previous_time).as_secs_f64() };
+        self.previous_time = time;
+
+        self.compute_with_dt(value, target_value, dt)
+    }
+
+    /// Compute the output of the PID for a known time step.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Current value of the controlled quantity.
+    ///
+    /// * `target_value` - Value that the controlled quantity should be at.
+    ///
+    /// * `dt` - Number of seconds since the previous call.
+    pub fn compute_with_dt(&mut self, value: f64, target_value: f64, dt: f64) -> f64 {
         let error: f64 = target_value - value;
+        let d_input: f64 = self.d_weight * target_value - value;
 
         if self.first_time {
-            self.previous_error = error;
+            self.previous_d_input = d_input;
+            self.d_filtered = 0.0;
             self.i_accumulator = 0.0;
             self.first_time = false;
         }
 
-        self.i_accumulator += self.i * error;
+        let p_input: f64 = self.p_weight * target_value - value;
+        let p_term: f64 = self.p * p_input;
 
-        let mut output: f64 = (self.p * error) + self.i_accumulator + (self.d * (error - self.previous_error));
+        let d_raw: f64 = if dt > 0.0 { self.d * (d_input - self.previous_d_input) / dt } else { 0.0 };
+        if self.d_filter_time > 0.0 {
+            self.d_filtered += (dt / (self.d_filter_time + dt)) * (d_raw - self.d_filtered);
+        }
+        else {
+            self.d_filtered = d_raw;
+        }
+        let d_term: f64 = self.d_filtered;
+
+        let i_change: f64 = self.i * error * dt;
+        let unsaturated: f64 = p_term + self.i_accumulator + i_change + d_term;
+        let mut output: f64 = unsaturated;
 
         if output > self.max {
             output = self.max;
@@ -40,25 +210,160 @@ impl Pid {
             output = self.min;
         }
 
-        match self.logfile {
-            Some(ref mut logfile) => {
-                writeln!(logfile, "{},{},{},{},{},{},{},{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(), error, self.p * error, self.i_accumulator, self.d * (error - self.previous_error), output, value, target_value).unwrap();
+        match self.anti_windup {
+            AntiWindup::None => {
+                self.i_accumulator += i_change;
+            }
+            AntiWindup::Clamping => {
+                let winding_up: bool = (unsaturated > self.max && i_change > 0.0) || (unsaturated < self.min && i_change < 0.0);
+                if !winding_up {
+                    self.i_accumulator += i_change;
+                }
+            }
+            AntiWindup::BackCalculation => {
+                self.i_accumulator += i_change + (output - unsaturated) * dt / self.tracking_time;
+            }
+        }
+
+        match self.telemetry {
+            Some((ref telemetry, name)) => {
+                telemetry.log(Record::Pid { name: name, time: telemetry::now_ms(), error: error, p: p_term, i_accumulator: self.i_accumulator, d: d_term, output: output, input: value, target: target_value, dt: dt });
             }
             _ => {}
         }
 
-        self.previous_error = error;
+        self.previous_p_input = p_input;
+        self.previous_d_input = d_input;
 
         output
     }
 
-    pub fn set_logfile(&mut self, logfile_name: &str) {
-        let logfile_path = Path::new(logfile_name);
-        let mut file = File::create(&logfile_path).unwrap();
-        writeln!(file, "Time,Error,P,I-Accumulator,D,Output,Input,Target").unwrap();
-        self.logfile = Some(file);
+    /// Change the gains without a step in the output (bumpless transfer). The filtered D term is
+    /// rescaled to the new D gain, and the integral term absorbs the changes in the P and D terms.
+    pub fn set_gains(&mut self, p: f64, i: f64, d: f64) {
+        if !self.first_time {
+            let d_filtered: f64 = if self.d != 0.0 { self.d_filtered * d / self.d } else { 0.0 };
+            self.i_accumulator += (self.p - p) * self.previous_p_input + (self.d_filtered - d_filtered);
+            self.d_filtered = d_filtered;
+        }
+        self.p = p;
+        self.i = i;
+        self.d = d;
+    }
+
+    /// Carry on from a state recorded in a PID log, as if the update that logged it had just been
+    /// computed. Used to replay a log that starts part way through a run.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Input of the logged update.
+    ///
+    /// * `target_value` - Target of the logged update.
+    ///
+    /// * `i_accumulator` - Integral term after the logged update.
+    ///
+    /// * `d_term` - D term of the logged update.
+    pub fn resume(&mut self, value: f64, target_value: f64, i_accumulator: f64, d_term: f64) {
+        self.first_time = false;
+        self.previous_p_input = self.p_weight * target_value - value;
+        self.previous_d_input = self.d_weight * target_value - value;
+        self.i_accumulator = i_accumulator;
+        self.d_filtered = if d_term.is_finite() { d_term } else { 0.0 };
+        self.previous_time = Instant::now();
+    }
+
+    /// Get the (p, i, d) gains.
+    pub fn get_gains(&self) -> (f64, f64, f64) {
+        (self.p, self.i, self.d)
+    }
+
+    /// Get all of the settings that can be changed while the PID is running.
+    pub fn get_parameters(&self) -> PidParameters {
+        PidParameters { p: self.p, i: self.i, d: self.d, min: self.min, max: self.max, d_filter_time: self.d_filter_time, p_weight: self.p_weight, d_weight: self.d_weight, anti_windup: self.anti_windup, tracking_time: self.tracking_time }
+    }
+
+    /// Change all of the settings at once. The gains change bumplessly (see set_gains()).
+    pub fn set_parameters(&mut self, parameters: &PidParameters) {
+        self.set_gains(parameters.p, parameters.i, parameters.d);
+        self.min = parameters.min;
+        self.max = parameters.max;
+        self.i_accumulator = self.i_accumulator.max(self.min).min(self.max);
+        self.d_filter_time = parameters.d_filter_time;
+        self.p_weight = parameters.p_weight;
+        self.d_weight = parameters.d_weight;
+        self.anti_windup = parameters.anti_windup;
+        self.tracking_time = parameters.tracking_time;
+    }
+
+    /// Set how the integral term is kept from winding up while the output is saturated.
+    ///
+    /// # Arguments
+    ///
+    /// * `anti_windup` - The anti-windup method.
+    ///
+    /// * `tracking_time` - Time constant (in seconds) over which back-calculation unwinds the integral term. Must be more than 0.
+    pub fn set_anti_windup(&mut self, anti_windup: AntiWindup, tracking_time: f64) {
+        self.anti_windup = anti_windup;
+        self.tracking_time = tracking_time;
+    }
+
+    /// Take the D term from the change in the measured value only, instead of the change in error,
+    /// so that a step in the target does not kick the output. This sets the D setpoint weight to 0.0
+    /// (or back to 1.0).
+    pub fn set_derivative_on_measurement(&mut self, derivative_on_measurement: bool) {
+        self.d_weight = if derivative_on_measurement { 0.0 } else { 1.0 };
+    }
+
+    /// Set the time constant (in seconds) of the low-pass filter on the D term. Zero disables the filter.
+    pub fn set_derivative_filter(&mut self, d_filter_time: f64) {
+        self.d_filter_time = d_filter_time;
     }
-    
+
+    /// Set the setpoint weights.
+    ///
+    /// # Arguments
+    ///
+    /// * `p_weight` - Fraction of the target used by the P term. Below 1.0 softens the response to target steps.
+    ///
+    /// * `d_weight` - Fraction of the target used by the D term. 0.0 is derivative on measurement.
+    pub fn set_setpoint_weights(&mut self, p_weight: f64, d_weight: f64) {
+        self.p_weight = p_weight;
+        self.d_weight = d_weight;
+    }
+
+    /// Log every update of the PID (time, error, P term, integral, D term, output, input, target and time step) to telemetry.
+    ///
+    /// # Arguments
+    ///
+    /// * `telemetry` - Telemetry to log to.
+    ///
+    /// * `name` - Name of the PID, which is also the name of its telemetry files.
+    pub fn set_telemetry(&mut self, telemetry: Telemetry, name: &'static str) {
+        self.telemetry = Some((telemetry, name));
+    }
+
+    /// Follow an output that something else is driving (e.g. manual jogging), so that when the PID
+    /// takes back control its output carries on from that value instead of stepping. Call this on
+    /// every loop while the PID is not in control. The D term is assumed to be zero.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Current value of the controlled quantity.
+    ///
+    /// * `target_value` - Value that the controlled quantity should be at.
+    ///
+    /// * `output` - Output that is currently being applied.
+    pub fn track(&mut self, value: f64, target_value: f64, output: f64) {
+        let p_input: f64 = self.p_weight * target_value - value;
+        self.i_accumulator = output - self.p * p_input;
+        self.d_filtered = 0.0;
+        self.previous_p_input = p_input;
+        self.previous_d_input = self.d_weight * target_value - value;
+        self.previous_time = Instant::now();
+        self.first_time = false;
+    }
+
+    /// Start again from scratch, zeroing the integral term. Use track() instead to hand control over without a step in the output.
     pub fn reset(&mut self) {
         self.first_time = true;
     }
@@ -0,0 +1,291 @@
+use crate::absolute_encoder::AbsoluteEncoder;
+
+/// Fraction of the difference between the absolute encoder and the motor encoder that is corrected on each update.
+const ABSOLUTE_CORRECTION_GAIN: f64 = 0.02;
+/// Smoothing factor for the low-pass filter on the difference between the two encoders.
+const DISCREPANCY_FILTER_GAIN: f64 = 0.1;
+/// If the two encoders disagree by more than this many degrees since the last homing, the gears (usually the printed hex coupler) have slipped and the axis is re-homed.
+const SLIP_THRESHOLD: f64 = 2.0;
+
+/// Estimates the angle of one axis of the antenna by fusing the motor's incremental encoder with an (optional) absolute encoder on the main gear.
+///
+/// The motor encoder has far finer resolution and no lag, but only counts relative to wherever the
+/// antenna was when the firmware started. The absolute encoder knows the true angle of the output
+/// shaft at all times. The estimate follows the motor encoder, and is slowly pulled towards the
+/// absolute encoder so that any drift is corrected without adding the absolute encoder's noise.
+pub struct PositionEstimator {
+    /// Absolute encoder on the output shaft of this axis, if one is fitted.
+    absolute_encoder: Option<Box<dyn AbsoluteEncoder + Send>>,
+    /// Number of driving motor revolutions per degree of the axis (negative if the motor turns backwards to increase the angle).
+    revs_per_degree: f64,
+    /// Reading of the absolute encoder (in degrees) when the axis is at 0 degrees.
+    absolute_zero: f64,
+    /// Set to -1.0 if the absolute encoder's angle decreases when the axis angle increases, 1.0 otherwise.
+    absolute_direction: f64,
+    /// Set while reads of the absolute encoder are failing, so that the failure is only reported once.
+    absolute_failing: bool,
+    /// Raw reading (in degrees) of the absolute encoder at its latest read, or NaN if there is no absolute encoder or the read failed.
+    absolute_reading: f64,
+    /// Angle (in degrees) of the axis when the motor encoder read zero revolutions.
+    offset: f64,
+    /// Value of offset at the last homing. The difference from offset is how far the gears have slipped since then.
+    home_offset: f64,
+    /// Low-pass filtered difference (in degrees) between the absolute encoder and the motor encoder
+    /// alone since the last homing. The slow correction of offset is left out, as it would hide a slip.
+    discrepancy: f64,
+    /// Number of times that the axis has been re-homed after slipping.
+    slip_events: u64,
+    /// Set when the encoders disagree by more than SLIP_THRESHOLD, until rehome() is called.
+    rehome_requested: bool,
+    /// Most recent fused angle estimate, in degrees.
+    angle: f64
+}
+
+impl PositionEstimator {
+    /// Create a position estimator. If there is an absolute encoder, the estimate starts at its
+    /// reading, so the antenna does not have to start at its home position.
+    ///
+    /// # Arguments
+    ///
+    /// * `absolute_encoder` - Absolute encoder on the output shaft of this axis, or None to use only the motor encoder.
+    ///
+    /// * `revs_per_degree` - Number of driving motor revolutions per degree of the axis.
+    ///
+    /// * `absolute_zero` - Reading of the absolute encoder (in degrees) when the axis is at 0 degrees.
+    ///
+    /// * `absolute_direction` - -1.0 if the absolute encoder's angle decreases as the axis angle increases, 1.0 otherwise.
+    ///
+    /// * `initial_angle` - Angle (in degrees) that the axis is assumed to start at if it has no absolute encoder.
+    pub fn new(absolute_encoder: Option<Box<dyn AbsoluteEncoder + Send>>, revs_per_degree: f64, absolute_zero: f64, absolute_direction: f64, initial_angle: f64) -> PositionEstimator {
+        let mut estimator = PositionEstimator { absolute_encoder: absolute_encoder, revs_per_degree: revs_per_degree, absolute_zero: absolute_zero, absolute_direction: absolute_direction, absolute_failing: false, absolute_reading: f64::NAN, offset: initial_angle, home_offset: initial_angle, discrepancy: 0.0, slip_events: 0, rehome_requested: false, angle: initial_angle };
+        if let Some(angle) = estimator.read_absolute_angle(initial_angle) {
+            estimator.offset = angle;
+            estimator.home_offset = angle;
+            estimator.angle = angle;
+        }
+        estimator
+    }
+
+    /// Returns true if this axis has an absolute encoder.
+    pub fn has_absolute_encoder(&self) -> bool {
+        self.absolute_encoder.is_some()
+    }
+
+    /// Read the axis angle from the absolute encoder, if there is one.
+    ///
+    /// # Arguments
+    ///
+    /// * `near_angle` - The absolute encoder only knows the angle modulo 360 degrees, so the turn closest to this angle is returned.
+    fn read_absolute_angle(&mut self, near_angle: f64) -> Option<f64> {
+        let absolute_encoder = self.absolute_encoder.as_mut()?;
+        match absolute_encoder.read_angle() {
+            Ok(reading) => {
+                self.absolute_failing = false;
+                self.absolute_reading = reading;
+                let angle: f64 = self.absolute_direction * (reading - self.absolute_zero);
+                Some(angle + 360.0 * ((near_angle - angle) / 360.0).round())
+            }
+            Err(error) => {
+                self.absolute_reading = f64::NAN;
+                if !self.absolute_failing {
+                    println!("PositionEstimator: ERROR, failed to read absolute encoder: {}.", error);
+                    self.absolute_failing = true;
+                }
+                None
+            }
+        }
+    }
+
+    /// Get the angle (in degrees) that the motor encoder alone says the axis is at.
+    fn incremental_angle(&self, motor_revs: f64) -> f64 {
+        motor_revs / self.revs_per_degree + self.offset
+    }
+
+    /// Update the estimate.
+    ///
+    /// # Arguments
+    ///
+    /// * `motor_revs` - Total number of revolutions done by the driving motor of this axis.
+    ///
+    /// Returns the estimated angle of the axis in degrees.
+    pub fn update(&mut self, motor_revs: f64) -> f64 {
+        let incremental_angle: f64 = self.incremental_angle(motor_revs);
+        if let Some(absolute_angle) = self.read_absolute_angle(incremental_angle) {
+            let discrepancy: f64 = absolute_angle - (motor_revs / self.revs_per_degree + self.home_offset);
+            self.discrepancy += DISCREPANCY_FILTER_GAIN * (discrepancy - self.discrepancy);
+            if self.discrepancy.abs() > SLIP_THRESHOLD {
+                self.rehome_requested = true;
+            }
+            self.offset += ABSOLUTE_CORRECTION_GAIN * (absolute_angle - incremental_angle);
+        }
+        self.angle = self.incremental_angle(motor_revs);
+        self.angle
+    }
+
+    /// Carry on from a logged state, as if the estimate had just been updated to an angle, for
+    /// replaying a log that starts part way through a run. The axis counts as freshly homed there.
+    ///
+    /// # Arguments
+    ///
+    /// * `motor_revs` - Total number of revolutions done by the driving motor of this axis.
+    ///
+    /// * `angle` - The estimated angle of the axis in degrees.
+    pub fn resume(&mut self, motor_revs: f64, angle: f64) {
+        self.offset = angle - motor_revs / self.revs_per_degree;
+        self.home_offset = self.offset;
+        self.discrepancy = 0.0;
+        self.rehome_requested = false;
+        self.angle = angle;
+    }
+
+    /// Returns true if the gears have slipped far enough that the axis should be re-homed.
+    pub fn rehome_requested(&self) -> bool {
+        self.rehome_requested
+    }
+
+    /// Re-home the axis by snapping the estimate straight to the absolute encoder, instead of
+    /// waiting for the slow correction in update() to catch up with a slip.
+    ///
+    /// # Arguments
+    ///
+    /// * `motor_revs` - Total number of revolutions done by the driving motor of this axis.
+    pub fn rehome(&mut self, motor_revs: f64) {
+        let incremental_angle: f64 = self.incremental_angle(motor_revs);
+        if let Some(absolute_angle) = self.read_absolute_angle(incremental_angle) {
+            self.offset += absolute_angle - incremental_angle;
+            self.home_offset = self.offset;
+            self.angle = absolute_angle;
+        }
+        if self.rehome_requested {
+            self.slip_events += 1;
+        }
+        self.discrepancy = 0.0;
+        self.rehome_requested = false;
+    }
+
+    /// Get the filtered difference (in degrees) between where the absolute encoder says the axis
+    /// is and where the motor encoder alone says it should be (`revs / gear ratio` from the last
+    /// homing). This stays near zero while the gears are healthy.
+    pub fn get_discrepancy(&self) -> f64 {
+        self.discrepancy
+    }
+
+    /// Get how far (in degrees) the gears have slipped since the axis was last homed. Small steady
+    /// growth is normal backlash and encoder error; a fast-growing value means the hex coupler is slipping.
+    pub fn get_slip(&self) -> f64 {
+        self.offset - self.home_offset
+    }
+
+    /// Get the number of times that the axis has been re-homed after slipping.
+    pub fn get_slip_events(&self) -> u64 {
+        self.slip_events
+    }
+
+    /// Returns true if the absolute encoder failed on its last reading.
+    pub fn is_absolute_encoder_failing(&self) -> bool {
+        self.absolute_failing
+    }
+
+    /// Get the raw reading (in degrees) of the absolute encoder at its latest read, or NaN if there is no absolute encoder or the read failed.
+    pub fn get_absolute_reading(&self) -> f64 {
+        self.absolute_reading
+    }
+
+    /// Get the most recent angle estimate (in degrees).
+    pub fn get_angle(&self) -> f64 {
+        self.angle
+    }
+
+    /// Get the number of driving motor revolutions that corresponds to an axis angle, in the same frame as the motor encoder.
+    ///
+    /// # Arguments
+    ///
+    /// * `angle` - Axis angle in degrees.
+    pub fn angle_to_motor_revs(&self, angle: f64) -> f64 {
+        (angle - self.offset) * self.revs_per_degree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+    use crate::absolute_encoder::AbsoluteEncoderError;
+
+    /// An absolute encoder that reads whatever angle the test sets.
+    struct FakeEncoder {
+        angle: Arc<Mutex<f64>>
+    }
+
+    impl AbsoluteEncoder for FakeEncoder {
+        fn read_angle(&mut self) -> Result<f64, AbsoluteEncoderError> {
+            Ok(*self.angle.lock().unwrap())
+        }
+    }
+
+    /// An estimator with one motor revolution per degree, and the angle that its absolute encoder reads.
+    fn new_estimator(angle: f64) -> (PositionEstimator, Arc<Mutex<f64>>) {
+        let shared_angle = Arc::new(Mutex::new(angle));
+        let encoder = FakeEncoder { angle: Arc::clone(&shared_angle) };
+        (PositionEstimator::new(Some(Box::new(encoder)), 1.0, 0.0, 1.0, 0.0), shared_angle)
+    }
+
+    #[test]
+    fn starts_at_the_absolute_angle() {
+        let (mut estimator, angle) = new_estimator(10.0);
+        assert_eq!(estimator.get_angle(), 10.0);
+        *angle.lock().unwrap() = 15.0;
+        assert_eq!(estimator.update(5.0), 15.0);
+    }
+
+    #[test]
+    fn ignores_healthy_noise() {
+        let (mut estimator, angle) = new_estimator(10.0);
+        for update in 0..1000 {
+            *angle.lock().unwrap() = if update % 2 == 0 { 10.5 } else { 9.5 };
+            estimator.update(0.0);
+        }
+        assert!(!estimator.rehome_requested());
+    }
+
+    #[test]
+    fn detects_a_sudden_slip_just_over_the_threshold() {
+        let (mut estimator, angle) = new_estimator(10.0);
+        estimator.update(0.0);
+        *angle.lock().unwrap() = 12.5;
+        for _ in 0..100 {
+            estimator.update(0.0);
+        }
+        assert!(estimator.rehome_requested());
+        assert!((estimator.get_discrepancy() - 2.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn detects_a_gradual_slip() {
+        let (mut estimator, angle) = new_estimator(10.0);
+        let mut updates: u32 = 0;
+        while !estimator.rehome_requested() && updates < 1000 {
+            updates += 1;
+            *angle.lock().unwrap() = 10.0 + 0.01 * updates as f64;
+            estimator.update(0.0);
+        }
+        assert!(estimator.rehome_requested());
+        // The estimate was being pulled along with the absolute encoder all the while.
+        assert!(estimator.get_slip() > 1.5);
+    }
+
+    #[test]
+    fn rehoming_snaps_to_the_absolute_encoder() {
+        let (mut estimator, angle) = new_estimator(10.0);
+        *angle.lock().unwrap() = 13.0;
+        for _ in 0..100 {
+            estimator.update(0.0);
+        }
+        estimator.rehome(0.0);
+        assert_eq!(estimator.get_angle(), 13.0);
+        assert_eq!(estimator.get_slip_events(), 1);
+        assert!(!estimator.rehome_requested());
+        assert_eq!(estimator.get_discrepancy(), 0.0);
+        assert_eq!(estimator.get_slip(), 0.0);
+    }
+}
@@ -0,0 +1,497 @@
+use std::fs;
+use std::sync::{ Arc, Mutex };
+use crate::absolute_encoder::{ AbsoluteEncoder, AbsoluteEncoderError };
+use crate::analysis::{ self, PidLogRow };
+use crate::gain_schedule::ScheduledPid;
+use crate::pid::Pid;
+use crate::position::PositionEstimator;
+
+/// Number of differing rows printed in detail.
+const MAX_REPORTED_DIFFERENCES: usize = 10;
+/// Header of the rotator telemetry stream (see telemetry::Record::Rotator).
+const ROTATOR_LOG_HEADER: &str = "Time,Source,Target-Altitude,Target-Azimuth,Altitude,Azimuth,Altitude-Revs,Azimuth-Revs,Altitude-Absolute,Azimuth-Absolute";
+/// Header of the rotator telemetry stream from before the motor revolutions and absolute encoder readings were logged.
+const ROTATOR_LOG_HEADER_WITHOUT_ENCODERS: &str = "Time,Source,Target-Altitude,Target-Azimuth,Altitude,Azimuth";
+
+/// A controller that a PID log can be replayed through.
+pub trait Controller {
+    /// Carry on from the state logged in a row, as if that row's update had just been computed.
+    fn resume(&mut self, row: &PidLogRow);
+
+    /// Compute the output for an input and target, `dt` seconds after the previous update.
+    fn compute(&mut self, input: f64, target: f64, dt: f64) -> f64;
+}
+
+impl Controller for Pid {
+    fn resume(&mut self, row: &PidLogRow) {
+        Pid::resume(self, row.input, row.target, row.i_accumulator, row.d);
+    }
+
+    fn compute(&mut self, input: f64, target: f64, dt: f64) -> f64 {
+        self.compute_with_dt(input, target, dt)
+    }
+}
+
+impl Controller for ScheduledPid {
+    fn resume(&mut self, row: &PidLogRow) {
+        ScheduledPid::resume(self, row.input, row.target, row.i_accumulator, row.d);
+    }
+
+    fn compute(&mut self, input: f64, target: f64, dt: f64) -> f64 {
+        self.compute_with_dt(input, target, dt)
+    }
+}
+
+/// How closely a replay matched the recorded log.
+pub struct ReplayResult {
+    /// Number of rows replayed (every row but the first).
+    pub rows: usize,
+    /// Largest difference between the replayed and recorded outputs.
+    pub max_difference: f64,
+    /// RMS difference between the replayed and recorded outputs.
+    pub rms_difference: f64,
+    /// Rows whose outputs differed by more than the tolerance, as (row index, recorded output, replayed output).
+    pub differences: Vec<(usize, f64, f64)>
+}
+
+/// Feed the recorded inputs and targets of a PID log through a controller, and compare its outputs with the recorded ones.
+///
+/// A log may start part way through a run, when the PID already had an integral and a previous
+/// input that the log does not show, so the first row is not replayed. Instead the controller
+/// resumes from the integral and D term logged in it, and the replay starts from the second row.
+///
+/// # Arguments
+///
+/// * `rows` - The log.
+///
+/// * `tolerance` - Outputs that differ by more than this count as differences.
+///
+/// * `controller` - The controller.
+pub fn replay(rows: &[PidLogRow], tolerance: f64, controller: &mut dyn Controller) -> ReplayResult {
+    let mut result = ReplayResult { rows: rows.len().saturating_sub(1), max_difference: 0.0, rms_difference: 0.0, differences: Vec::new() };
+    let first_row: &PidLogRow = match rows.first() {
+        Some(row) => row,
+        None => return result
+    };
+    controller.resume(first_row);
+
+    let mut squared_total: f64 = 0.0;
+    for (index, row) in rows.iter().enumerate().skip(1) {
+        // Logs from before the time step was logged only have times to the millisecond.
+        let dt: f64 = row.dt.unwrap_or(row.time - rows[index - 1].time);
+
+        let output: f64 = controller.compute(row.input, row.target, dt);
+        let difference: f64 = (output - row.output).abs();
+        squared_total += difference * difference;
+        result.max_difference = result.max_difference.max(difference);
+        if difference > tolerance {
+            result.differences.push((index, row.output, output));
+        }
+    }
+    if result.rows > 0 {
+        result.rms_difference = (squared_total / result.rows as f64).sqrt();
+    }
+    result
+}
+
+/// Replay a PID log through a controller and print how well it matched. Returns true if every output was within the tolerance.
+///
+/// Only updates that the PID computed are logged, so a log that includes jogging or stopping (when
+/// the position PIDs only follow along) or gain changes made while running will not replay exactly.
+///
+/// # Arguments
+///
+/// * `path` - The PID log.
+///
+/// * `tolerance` - Outputs that differ by more than this count as differences.
+///
+/// * `controller` - The controller (see replay()).
+pub fn run(path: &str, tolerance: f64, controller: &mut dyn Controller) -> bool {
+    let rows: Vec<PidLogRow> = match analysis::read_pid_log(path) {
+        Ok(rows) => rows,
+        Err(error) => {
+            println!("Replay: ERROR, {}.", error);
+            return false;
+        }
+    };
+
+    let result: ReplayResult = replay(&rows, tolerance, controller);
+    println!("Replay: {} rows, largest output difference {:.6}, RMS difference {:.6}.", result.rows, result.max_difference, result.rms_difference);
+    for &(index, recorded, replayed) in result.differences.iter().take(MAX_REPORTED_DIFFERENCES) {
+        let row: &PidLogRow = &rows[index];
+        println!("Replay:     Line {} at {:.3} s (input {}, target {}): recorded {:.6}, replayed {:.6}.", index + 2, row.time, row.input, row.target, recorded, replayed);
+    }
+    if result.differences.len() > MAX_REPORTED_DIFFERENCES {
+        println!("Replay:     ... and {} more.", result.differences.len() - MAX_REPORTED_DIFFERENCES);
+    }
+
+    if result.differences.is_empty() {
+        println!("Replay: PASSED, every output is within {} of the recording.", tolerance);
+        true
+    }
+    else {
+        println!("Replay: FAILED, {} of {} outputs differ by more than {}.", result.differences.len(), result.rows, tolerance);
+        false
+    }
+}
+
+/// One update of the position loop, from the rotator telemetry stream. Angles are in degrees.
+#[derive(Clone, Copy, Debug)]
+pub struct RotatorLogRow {
+    /// Seconds since the first row.
+    pub time: f64,
+    pub altitude: f64,
+    pub azimuth: f64,
+    /// Total revolutions of the driving motor of each axis.
+    pub altitude_revs: f64,
+    pub azimuth_revs: f64,
+    /// Raw readings of the absolute encoder of each axis (NaN where there is none or it failed to read).
+    pub altitude_absolute: f64,
+    pub azimuth_absolute: f64
+}
+
+/// Read a rotator telemetry log. Times are converted from milliseconds since the UNIX epoch to seconds since the first row.
+pub fn read_rotator_log(path: &str) -> Result<Vec<RotatorLogRow>, String> {
+    let contents: String = fs::read_to_string(path).map_err(|error| format!("failed to read {}: {}", path, error))?;
+    let mut lines = contents.lines();
+    match lines.next().map(|header| header.trim()) {
+        Some(ROTATOR_LOG_HEADER) => {}
+        Some(ROTATOR_LOG_HEADER_WITHOUT_ENCODERS) => return Err(format!("{} was logged before the encoders were, so it cannot be replayed", path)),
+        _ => return Err(format!("{} is not a rotator log (expected the header {})", path, ROTATOR_LOG_HEADER))
+    }
+
+    let mut rows: Vec<RotatorLogRow> = Vec::new();
+    let mut first_time: Option<f64> = None;
+    for (index, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Every column but the source (the mode name) is a number.
+        let values: Vec<f64> = match line.split(',').enumerate().filter(|&(column, _)| column != 1).map(|(_, value)| value.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>() {
+            Ok(values) if values.len() == 9 => values,
+            _ => return Err(format!("line {} of {} is malformed: {}", index + 2, path, line))
+        };
+        let time_ms: f64 = values[0];
+        let start: f64 = *first_time.get_or_insert(time_ms);
+        rows.push(RotatorLogRow { time: (time_ms - start) / 1000.0, altitude: values[3], azimuth: values[4], altitude_revs: values[5], azimuth_revs: values[6], altitude_absolute: values[7], azimuth_absolute: values[8] });
+    }
+    Ok(rows)
+}
+
+/// An absolute encoder that gives back logged readings. NaN readings (where the original read failed) fail to read.
+pub struct ReplayedEncoder {
+    reading: Arc<Mutex<f64>>
+}
+
+impl ReplayedEncoder {
+    /// Create a replayed encoder. Returns it, and the reading that it gives back, for the replay to set.
+    pub fn new() -> (ReplayedEncoder, Arc<Mutex<f64>>) {
+        let reading = Arc::new(Mutex::new(f64::NAN));
+        (ReplayedEncoder { reading: Arc::clone(&reading) }, reading)
+    }
+}
+
+impl AbsoluteEncoder for ReplayedEncoder {
+    fn read_angle(&mut self) -> Result<f64, AbsoluteEncoderError> {
+        let reading: f64 = *self.reading.lock().unwrap();
+        if reading.is_nan() { Err(AbsoluteEncoderError::BadReply) } else { Ok(reading) }
+    }
+}
+
+/// Feed the logged motor revolutions and absolute encoder readings of one axis through a position
+/// estimator, re-homing it whenever it asks to as the position loop does, and compare its angles with the logged ones.
+///
+/// As with replay(), the first row is not replayed: the estimator resumes from the angle logged in
+/// it. Only the latest absolute encoder reading of each update is logged, so that reading is also
+/// used for the second read that a re-home makes.
+///
+/// # Arguments
+///
+/// * `rows` - The log.
+///
+/// * `tolerance` - Angles (in degrees) that differ by more than this count as differences.
+///
+/// * `estimator` - The position estimator of the axis.
+///
+/// * `reading` - The reading of the estimator's ReplayedEncoder, if it has one.
+///
+/// * `columns` - Gets the motor revolutions, absolute encoder reading and angle of the axis from a row.
+pub fn replay_position(rows: &[RotatorLogRow], tolerance: f64, estimator: &mut PositionEstimator, reading: Option<&Mutex<f64>>, columns: fn(&RotatorLogRow) -> (f64, f64, f64)) -> ReplayResult {
+    let mut result = ReplayResult { rows: rows.len().saturating_sub(1), max_difference: 0.0, rms_difference: 0.0, differences: Vec::new() };
+    let (first_revs, _, first_angle) = match rows.first() {
+        Some(row) => columns(row),
+        None => return result
+    };
+    estimator.resume(first_revs, first_angle);
+
+    let mut squared_total: f64 = 0.0;
+    for (index, row) in rows.iter().enumerate().skip(1) {
+        let (revs, absolute, recorded_angle) = columns(row);
+        if let Some(reading) = reading {
+            *reading.lock().unwrap() = absolute;
+        }
+        let angle: f64 = estimator.update(revs);
+        if estimator.rehome_requested() {
+            estimator.rehome(revs);
+        }
+
+        let difference: f64 = (angle - recorded_angle).abs();
+        squared_total += difference * difference;
+        result.max_difference = result.max_difference.max(difference);
+        if difference > tolerance {
+            result.differences.push((index, recorded_angle, angle));
+        }
+    }
+    if result.rows > 0 {
+        result.rms_difference = (squared_total / result.rows as f64).sqrt();
+    }
+    result
+}
+
+/// Replay a rotator telemetry log through the position estimators of both axes and print how well
+/// they matched. Returns true if every angle was within the tolerance.
+///
+/// An axis whose absolute encoder never read in the log is replayed without one.
+///
+/// # Arguments
+///
+/// * `path` - The rotator log.
+///
+/// * `tolerance` - Angles (in degrees) that differ by more than this count as differences.
+///
+/// * `new_estimator` - Creates the position estimator of an axis ("altitude" or "azimuth") as it is set up on the rotator, with an absolute encoder.
+pub fn run_position(path: &str, tolerance: f64, new_estimator: &dyn Fn(&str, Option<Box<dyn AbsoluteEncoder + Send>>) -> PositionEstimator) -> bool {
+    let rows: Vec<RotatorLogRow> = match read_rotator_log(path) {
+        Ok(rows) => rows,
+        Err(error) => {
+            println!("Replay: ERROR, {}.", error);
+            return false;
+        }
+    };
+
+    let axes: [(&str, fn(&RotatorLogRow) -> (f64, f64, f64)); 2] = [
+        ("altitude", |row| (row.altitude_revs, row.altitude_absolute, row.altitude)),
+        ("azimuth", |row| (row.azimuth_revs, row.azimuth_absolute, row.azimuth))
+    ];
+    let mut passed: bool = true;
+    for &(axis, columns) in axes.iter() {
+        let (mut estimator, reading) = if rows.iter().any(|row| !columns(row).1.is_nan()) {
+            let (encoder, reading) = ReplayedEncoder::new();
+            (new_estimator(axis, Some(Box::new(encoder))), Some(reading))
+        }
+        else {
+            (new_estimator(axis, None), None)
+        };
+        let result: ReplayResult = replay_position(&rows, tolerance, &mut estimator, reading.as_deref(), columns);
+        println!("Replay: {} {} rows, largest angle difference {:.6} degrees, RMS difference {:.6} degrees, {} re-homes.", axis, result.rows, result.max_difference, result.rms_difference, estimator.get_slip_events());
+        for &(index, recorded, replayed) in result.differences.iter().take(MAX_REPORTED_DIFFERENCES) {
+            println!("Replay:     Line {} at {:.3} s: recorded {:.6}, replayed {:.6}.", index + 2, rows[index].time, recorded, replayed);
+        }
+        if result.differences.len() > MAX_REPORTED_DIFFERENCES {
+            println!("Replay:     ... and {} more.", result.differences.len() - MAX_REPORTED_DIFFERENCES);
+        }
+        if !result.differences.is_empty() {
+            println!("Replay: FAILED, {} of {} {} angles differ by more than {} degrees.", result.differences.len(), result.rows, axis, tolerance);
+            passed = false;
+        }
+    }
+    if passed {
+        println!("Replay: PASSED, every angle is within {} degrees of the recording.", tolerance);
+    }
+    passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The position PID as it was when altitude_encoder.csv was logged: gains per update rather than per second, and no D filter or anti-windup.
+    struct FixedStepPid {
+        p: f64,
+        i: f64,
+        d: f64,
+        i_accumulator: f64,
+        previous_error: f64
+    }
+
+    impl Controller for FixedStepPid {
+        fn resume(&mut self, row: &PidLogRow) {
+            self.i_accumulator = row.i_accumulator;
+            self.previous_error = row.error;
+        }
+
+        fn compute(&mut self, input: f64, target: f64, _dt: f64) -> f64 {
+            let error: f64 = target - input;
+            self.i_accumulator += self.i * error;
+            let output: f64 = self.p * error + self.i_accumulator + self.d * (error - self.previous_error);
+            self.previous_error = error;
+            output.max(-1.0).min(1.0)
+        }
+    }
+
+    /// Records the time steps that it is given.
+    struct TimeStepRecorder {
+        resumed_from: Option<f64>,
+        time_steps: Vec<f64>
+    }
+
+    impl Controller for TimeStepRecorder {
+        fn resume(&mut self, row: &PidLogRow) {
+            self.resumed_from = Some(row.input);
+        }
+
+        fn compute(&mut self, _input: f64, _target: f64, dt: f64) -> f64 {
+            self.time_steps.push(dt);
+            0.0
+        }
+    }
+
+    fn altitude_encoder_log() -> Vec<PidLogRow> {
+        analysis::read_pid_log(concat!(env!("CARGO_MANIFEST_DIR"), "/altitude_encoder.csv")).unwrap()
+    }
+
+    fn row(time: f64, input: f64, dt: Option<f64>) -> PidLogRow {
+        PidLogRow { time: time, error: 0.0, p: 0.0, i_accumulator: 0.0, d: 0.0, output: 0.0, input: input, target: 0.0, dt: dt }
+    }
+
+    fn altitude_columns(row: &RotatorLogRow) -> (f64, f64, f64) {
+        (row.altitude_revs, row.altitude_absolute, row.altitude)
+    }
+
+    /// Run the altitude axis of a position loop, with two motor revolutions per degree, as the
+    /// absolute encoder reads some angles, and log it. The gears slip by 5 degrees part way through.
+    fn rotator_log(readings: &[f64]) -> Vec<RotatorLogRow> {
+        let (encoder, reading) = ReplayedEncoder::new();
+        *reading.lock().unwrap() = readings[0];
+        let mut estimator = PositionEstimator::new(Some(Box::new(encoder)), 2.0, 0.0, 1.0, 0.0);
+        let mut rows: Vec<RotatorLogRow> = Vec::new();
+        for (index, &absolute) in readings.iter().enumerate() {
+            let revs: f64 = index as f64 * 0.1;
+            *reading.lock().unwrap() = if absolute.is_nan() { absolute } else { absolute + revs / 2.0 };
+            let angle: f64 = estimator.update(revs);
+            if estimator.rehome_requested() {
+                estimator.rehome(revs);
+            }
+            rows.push(RotatorLogRow { time: index as f64 * 0.01, altitude: angle, azimuth: 0.0, altitude_revs: revs, azimuth_revs: 0.0, altitude_absolute: *reading.lock().unwrap(), azimuth_absolute: f64::NAN });
+        }
+        assert_eq!(estimator.get_slip_events(), 1);
+        rows
+    }
+
+    fn slipping_readings() -> Vec<f64> {
+        (0..300).map(|index| match index {
+            50..=59 => f64::NAN,
+            0..=149 => 10.0,
+            _ => 15.0
+        }).collect()
+    }
+
+    #[test]
+    fn replays_position_log() {
+        let rows: Vec<RotatorLogRow> = rotator_log(&slipping_readings());
+        let (encoder, reading) = ReplayedEncoder::new();
+        let mut estimator = PositionEstimator::new(Some(Box::new(encoder)), 2.0, 0.0, 1.0, 0.0);
+        let result: ReplayResult = replay_position(&rows, 1.0e-9, &mut estimator, Some(&reading), altitude_columns);
+        assert_eq!(result.rows, 299);
+        assert!(result.differences.is_empty(), "{:?}", result.differences.first());
+        assert_eq!(estimator.get_slip_events(), 1);
+        assert!((estimator.get_angle() - rows[299].altitude).abs() < 1.0e-9);
+    }
+
+    #[test]
+    fn reports_differences_from_position_log() {
+        let rows: Vec<RotatorLogRow> = rotator_log(&slipping_readings());
+        // Without the absolute encoder, the slip is never corrected.
+        let mut estimator = PositionEstimator::new(None, 2.0, 0.0, 1.0, 0.0);
+        let result: ReplayResult = replay_position(&rows, 0.5, &mut estimator, None, altitude_columns);
+        assert_eq!(estimator.get_slip_events(), 0);
+        assert!(!result.differences.is_empty());
+        assert!(result.max_difference > 4.0);
+        // The estimator resumed from the first row, so the rows before the slip match.
+        assert!(result.differences[0].0 > 150);
+    }
+
+    #[test]
+    fn reads_rotator_logs() {
+        let directory = std::env::temp_dir().join(format!("rotator_replay_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("rotator.csv");
+        let path: &str = path.to_str().unwrap();
+
+        fs::write(path, format!("{}\n1000,goto,30,120,29.5,119,59,238,29.5,NaN\n1010,stop,NaN,NaN,29.6,119,59.2,238,NaN,NaN\n", ROTATOR_LOG_HEADER)).unwrap();
+        let rows: Vec<RotatorLogRow> = read_rotator_log(path).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!((rows[1].time - 0.01).abs() < 1.0e-12);
+        assert_eq!((rows[1].altitude, rows[1].azimuth, rows[1].altitude_revs, rows[1].azimuth_revs), (29.6, 119.0, 59.2, 238.0));
+        assert_eq!(rows[0].altitude_absolute, 29.5);
+        assert!(rows[0].azimuth_absolute.is_nan() && rows[1].altitude_absolute.is_nan());
+
+        fs::write(path, format!("{}\n1000,goto,30,120,29.5,119\n", ROTATOR_LOG_HEADER_WITHOUT_ENCODERS)).unwrap();
+        assert!(read_rotator_log(path).unwrap_err().contains("cannot be replayed"));
+        fs::write(path, format!("{}\n1000,goto,30,120,29.5\n", ROTATOR_LOG_HEADER)).unwrap();
+        assert!(read_rotator_log(path).unwrap_err().contains("line 2"));
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn replays_altitude_encoder_log() {
+        let rows: Vec<PidLogRow> = altitude_encoder_log();
+        assert_eq!(rows.len(), 2045);
+        assert!(rows.iter().all(|row| row.dt.is_none()));
+
+        let mut pid = FixedStepPid { p: 2.0, i: 0.005, d: 20.0, i_accumulator: 0.0, previous_error: 0.0 };
+        let result: ReplayResult = replay(&rows, 1.0e-9, &mut pid);
+        assert_eq!(result.rows, 2044);
+        assert!(result.differences.is_empty());
+        assert!(result.max_difference < 1.0e-9);
+    }
+
+    #[test]
+    fn reports_differences_from_altitude_encoder_log() {
+        let rows: Vec<PidLogRow> = altitude_encoder_log();
+        let mut pid = FixedStepPid { p: 1.0, i: 0.005, d: 20.0, i_accumulator: 0.0, previous_error: 0.0 };
+        let result: ReplayResult = replay(&rows, 1.0e-9, &mut pid);
+        assert!(!result.differences.is_empty());
+        // Row 0 only seeds the controller, so it is never compared.
+        assert!(result.differences.iter().all(|&(index, _, _)| index >= 1));
+        assert!(result.max_difference > 0.1);
+    }
+
+    #[test]
+    fn resumes_from_first_row() {
+        let rows: Vec<PidLogRow> = vec![row(0.0, 1.0, Some(0.0)), row(0.001, 2.0, Some(0.25)), row(0.002, 3.0, Some(0.5))];
+        let mut recorder = TimeStepRecorder { resumed_from: None, time_steps: Vec::new() };
+        let result: ReplayResult = replay(&rows, 0.0, &mut recorder);
+        assert_eq!(result.rows, 2);
+        assert_eq!(recorder.resumed_from, Some(1.0));
+        assert_eq!(recorder.time_steps, vec![0.25, 0.5]);
+    }
+
+    #[test]
+    fn falls_back_to_logged_times() {
+        let rows: Vec<PidLogRow> = vec![row(0.0, 0.0, None), row(0.011, 0.0, None), row(0.021, 0.0, None)];
+        let mut recorder = TimeStepRecorder { resumed_from: None, time_steps: Vec::new() };
+        replay(&rows, 0.0, &mut recorder);
+        assert_eq!(recorder.time_steps.len(), 2);
+        assert!((recorder.time_steps[0] - 0.011).abs() < 1.0e-12);
+        assert!((recorder.time_steps[1] - 0.010).abs() < 1.0e-12);
+    }
+
+    #[test]
+    fn resumed_pid_carries_on_from_log() {
+        // A PID that has run for a while, and a copy that only knows what the log of its last update says.
+        let mut live = Pid::new(1.0, 0.5, 0.2, -1.0, 1.0);
+        for step in 0..50 {
+            live.compute_with_dt(step as f64 * 0.01, 1.0, 0.01);
+        }
+        // The last update moves 0.01 in 0.01 s, so its D term is -0.2 and its P term is 0.5.
+        let d_term: f64 = -0.2;
+        let i_accumulator: f64 = live.compute_with_dt(0.5, 1.0, 0.01) - 0.5 - d_term;
+        let mut resumed = Pid::new(1.0, 0.5, 0.2, -1.0, 1.0);
+        resumed.resume(0.5, 1.0, i_accumulator, d_term);
+        for step in 1..10 {
+            let value: f64 = 0.5 + step as f64 * 0.005;
+            assert!((live.compute_with_dt(value, 1.0, 0.01) - resumed.compute_with_dt(value, 1.0, 0.01)).abs() < 1.0e-12);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use crate::control_port::Protocol;
+use crate::state::{ RotatorState, Mode };
+
+/// First byte of every frame.
+const START: u8 = b'W';
+/// Last byte of every frame.
+const END: u8 = 0x20;
+/// Length of a command frame.
+const COMMAND_LENGTH: usize = 13;
+/// Command bytes (K in the frame).
+const COMMAND_STOP: u8 = 0x0F;
+const COMMAND_STATUS: u8 = 0x1F;
+const COMMAND_SET: u8 = 0x2F;
+
+/// SPID Rot2Prog rotator controller command set. Every command is a 13 byte frame:
+///
+/// `W H1 H2 H3 H4 PH V1 V2 V3 V4 PV K 0x20`
+///
+/// H1 to H4 are ASCII digits of the azimuth in pulses, offset by 360 degrees (PH * (360 + azimuth)),
+/// and V1 to V4 the same for the elevation. PH and PV are the pulses per degree, but the controller
+/// ignores them and uses its own setting. K is the command: 0x0F stop, 0x1F status or 0x2F set.
+///
+/// Stop and status reply with a 12 byte frame holding the position, in tenths of a degree:
+///
+/// `W H1 H2 H3 H4 PH V1 V2 V3 V4 PV 0x20`
+///
+/// Here H1 to H4 are binary digits (0 to 9) of 10 * (360 + azimuth), and likewise V1 to V4 for the elevation.
+pub struct Rot2Prog {
+    state: Arc<RotatorState>,
+    /// Pulses per degree of the set command.
+    resolution: u8,
+    /// Frame received so far.
+    frame: Vec<u8>
+}
+
+impl Rot2Prog {
+    /// Create a Rot2Prog protocol handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The rotator state that commands act on.
+    ///
+    /// * `resolution` - Pulses per degree (1, 2 or 4 on a real controller), as set in the controlling software.
+    pub fn new(state: Arc<RotatorState>, resolution: u8) -> Rot2Prog {
+        Rot2Prog { state: state, resolution: resolution.max(1), frame: Vec::with_capacity(COMMAND_LENGTH) }
+    }
+
+    /// Read an angle from the ASCII digits of a set command. Returns None if they are not digits.
+    fn decode_angle(&self, digits: &[u8]) -> Option<f64> {
+        let pulses: u32 = std::str::from_utf8(digits).ok()?.parse::<u32>().ok()?;
+        Some(pulses as f64 / self.resolution as f64 - 360.0)
+    }
+
+    /// Write an angle as the binary digits of a reply.
+    fn encode_angle(angle: f64, reply: &mut Vec<u8>) {
+        let tenths: u32 = ((angle + 360.0) * 10.0).round().max(0.0).min(9999.0) as u32;
+        reply.extend_from_slice(&[(tenths / 1000 % 10) as u8, (tenths / 100 % 10) as u8, (tenths / 10 % 10) as u8, (tenths % 10) as u8]);
+    }
+
+    /// Reply with the current position.
+    fn position_reply(&self) -> Vec<u8> {
+        let mut reply: Vec<u8> = vec![START];
+        Rot2Prog::encode_angle(self.state.azimuth.load(Ordering::Relaxed).rem_euclid(360.0), &mut reply);
+        reply.push(self.resolution);
+        Rot2Prog::encode_angle(self.state.altitude.load(Ordering::Relaxed), &mut reply);
+        reply.push(self.resolution);
+        reply.push(END);
+        reply
+    }
+
+    /// Run one complete command frame. Returns the reply (which may be empty).
+    fn command(&self, frame: &[u8]) -> Vec<u8> {
+        match frame[11] {
+            COMMAND_STOP => {
+                self.state.set_mode(Mode::Stop);
+                self.position_reply()
+            }
+            COMMAND_STATUS => self.position_reply(),
+            COMMAND_SET => {
+                match (self.decode_angle(&frame[1..5]), self.decode_angle(&frame[6..10])) {
+                    (Some(azimuth), Some(elevation)) => if let Err(error) = self.state.goto(elevation, azimuth) {
+                        println!("Rot2Prog: ERROR, set command refused: {}", error);
+                    },
+                    _ => println!("Rot2Prog: ERROR, set command with an invalid angle.")
+                }
+                Vec::new()
+            }
+            _ => Vec::new()
+        }
+    }
+}
+
+impl Protocol for Rot2Prog {
+    fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut reply: Vec<u8> = Vec::new();
+        for &byte in data.iter() {
+            // Skip anything before the start of a frame.
+            if self.frame.is_empty() && byte != START {
+                continue;
+            }
+            self.frame.push(byte);
+            if self.frame.len() == COMMAND_LENGTH {
+                if byte == END {
+                    reply.extend(self.command(&self.frame));
+                    self.frame.clear();
+                }
+                else {
+                    // Out of step with the frames, so look for the next start byte within this one.
+                    let start: usize = self.frame[1..].iter().position(|&byte| byte == START).map(|index| index + 1).unwrap_or(COMMAND_LENGTH);
+                    self.frame.drain(..start);
+                }
+            }
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control_port::testing::new_protocol;
+
+    /// A command frame with the angles written as ASCII pulses.
+    fn frame(azimuth: &[u8; 4], elevation: &[u8; 4], resolution: u8, command: u8) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![START];
+        frame.extend_from_slice(azimuth);
+        frame.push(resolution);
+        frame.extend_from_slice(elevation);
+        frame.push(resolution);
+        frame.push(command);
+        frame.push(END);
+        frame
+    }
+
+    #[test]
+    fn replies_to_status_with_position() {
+        let (mut rot2prog, _) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 1));
+        let reply: Vec<u8> = rot2prog.receive(&frame(b"0000", b"0000", 0, COMMAND_STATUS));
+        assert_eq!(reply, vec![START, 4, 8, 3, 6, 1, 4, 0, 5, 4, 1, END]);
+    }
+
+    #[test]
+    fn encodes_angles_in_tenths() {
+        let mut reply: Vec<u8> = Vec::new();
+        Rot2Prog::encode_angle(0.0, &mut reply);
+        Rot2Prog::encode_angle(359.96, &mut reply);
+        Rot2Prog::encode_angle(-5.0, &mut reply);
+        assert_eq!(reply, vec![3, 6, 0, 0, 7, 2, 0, 0, 3, 5, 5, 0]);
+    }
+
+    #[test]
+    fn stops_and_replies() {
+        let (mut rot2prog, state) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 1));
+        state.jog(1.0, 1.0).unwrap();
+        let reply: Vec<u8> = rot2prog.receive(&frame(b"0000", b"0000", 0, COMMAND_STOP));
+        assert_eq!(reply.len(), 12);
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn decodes_set_command() {
+        let (mut rot2prog, state) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 1));
+        assert!(rot2prog.receive(&frame(b"0483", b"0405", 1, COMMAND_SET)).is_empty());
+        assert_eq!(state.get_mode(), Mode::Goto);
+        assert_eq!(state.target_azimuth.load(Ordering::Relaxed), 123.0);
+        assert_eq!(state.target_altitude.load(Ordering::Relaxed), 45.0);
+
+        // The configured resolution is used, not the one in the frame.
+        let (mut rot2prog, state) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 2));
+        rot2prog.receive(&frame(b"0967", b"0810", 1, COMMAND_SET));
+        assert_eq!(state.target_azimuth.load(Ordering::Relaxed), 123.5);
+        assert_eq!(state.target_altitude.load(Ordering::Relaxed), 45.0);
+    }
+
+    #[test]
+    fn refuses_bad_set_commands() {
+        let (mut rot2prog, state) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 1));
+        rot2prog.receive(&frame(b"9639", b"0405", 1, COMMAND_SET));
+        rot2prog.receive(&frame(b"0483", b"9999", 1, COMMAND_SET));
+        rot2prog.receive(&frame(b"04x3", b"0405", 1, COMMAND_SET));
+        assert_eq!(state.get_mode(), Mode::Stop);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let (mut rot2prog, state) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 1));
+        let mut data: Vec<u8> = b"xxW01".to_vec();
+        data.extend(frame(b"0483", b"0405", 1, COMMAND_SET));
+        assert!(rot2prog.receive(&data).is_empty());
+        assert_eq!(state.get_mode(), Mode::Goto);
+        assert_eq!(state.target_azimuth.load(Ordering::Relaxed), 123.0);
+
+        // Back in step, the next frame is answered.
+        assert_eq!(rot2prog.receive(&frame(b"0000", b"0000", 0, COMMAND_STATUS)).len(), 12);
+    }
+
+    #[test]
+    fn joins_frames_split_across_reads() {
+        let (mut rot2prog, _) = new_protocol(45.4, 123.6, |state| Rot2Prog::new(state, 1));
+        let status: Vec<u8> = frame(b"0000", b"0000", 0, COMMAND_STATUS);
+        assert!(rot2prog.receive(&status[..5]).is_empty());
+        assert_eq!(rot2prog.receive(&status[5..]).len(), 12);
+    }
+}
@@ -0,0 +1,87 @@
+use std::ffi::{ CStr, CString };
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+
+/// An open serial port or pseudo-terminal.
+pub struct SerialPort {
+    /// The port, for reading and writing.
+    pub file: File,
+    /// Path of the device that clients open. For a pseudo-terminal, this is its slave side.
+    pub path: String,
+    /// Slave side of a pseudo-terminal, kept open so that reads do not fail while no client has it open.
+    _slave: Option<File>
+}
+
+/// Put a terminal into raw mode (no echo, no line editing, 8N1) with blocking reads of at least one byte.
+fn make_raw(fd: i32, baud: Option<u32>) -> io::Result<()> {
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if let Some(baud) = baud {
+            let speed: libc::speed_t = match baud {
+                600 => libc::B600,
+                1200 => libc::B1200,
+                2400 => libc::B2400,
+                4800 => libc::B4800,
+                9600 => libc::B9600,
+                19200 => libc::B19200,
+                38400 => libc::B38400,
+                57600 => libc::B57600,
+                115200 => libc::B115200,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported baud rate {}", baud)))
+            };
+            libc::cfsetispeed(&mut termios, speed);
+            libc::cfsetospeed(&mut termios, speed);
+        }
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+impl SerialPort {
+    /// Open a serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - Path of the device, e.g. "/dev/ttyUSB0".
+    ///
+    /// * `baud` - Baud rate.
+    pub fn open(device: &str, baud: u32) -> io::Result<SerialPort> {
+        let path = CString::new(device).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "device path contains a NUL"))?;
+        let fd: i32 = unsafe { libc::open(path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        make_raw(fd, Some(baud))?;
+        Ok(SerialPort { file: file, path: device.to_string(), _slave: None })
+    }
+
+    /// Create a pseudo-terminal, so that programs on the Pi itself (or tests) can talk to the rotator as if it were on a serial port.
+    pub fn open_pty() -> io::Result<SerialPort> {
+        let mut master: i32 = -1;
+        let mut slave: i32 = -1;
+        if unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master_file = unsafe { File::from_raw_fd(master) };
+        let slave_file = unsafe { File::from_raw_fd(slave) };
+        make_raw(slave, None)?;
+
+        let name = unsafe { libc::ttyname(slave) };
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path: String = unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned();
+        Ok(SerialPort { file: master_file, path: path, _slave: Some(slave_file) })
+    }
+}
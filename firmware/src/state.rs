@@ -0,0 +1,352 @@
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, AtomicU8, AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+use atomicfloat::AtomicF64;
+
+/// Battery voltage below which the battery is reported as low.
+const LOW_BATTERY_VOLTAGE: f64 = 10.5;
+
+/// Jog speeds (in degrees per second) that the keyboard and joystick step through.
+pub const JOG_SPEEDS: [f64; 5] = [0.5, 1.0, 2.0, 5.0, 10.0];
+/// Index into JOG_SPEEDS of the speed that the keyboard and joystick start at.
+pub const DEFAULT_JOG_SPEED: usize = 3;
+/// Fastest jog (in degrees per second) accepted from anything.
+pub const MAX_JOG_SPEED: f64 = 20.0;
+/// Range of target altitudes (in degrees) accepted. The antenna can tip a little below the horizon and over the zenith.
+pub const ALTITUDE_LIMITS: (f64, f64) = (-10.0, 190.0);
+/// Range of target azimuths (in degrees) accepted. Up to a turn past 0 or 360 is allowed for
+/// controllers that overlap (e.g. GS-232's 450 degrees), but no more, so that a bad target cannot wind the cables up.
+pub const AZIMUTH_LIMITS: (f64, f64) = (-360.0, 720.0);
+
+/// Jog deadline of a jog that lasts until told otherwise.
+const NO_DEADLINE: u64 = u64::MAX;
+
+/// Check that a value is a number within limits.
+///
+/// # Arguments
+///
+/// * `name` - What the value is, for the error message.
+///
+/// * `value` - The value.
+///
+/// * `limits` - Smallest and largest values allowed.
+///
+/// * `unit` - Unit of the value, for the error message.
+fn check_limit(name: &str, value: f64, limits: (f64, f64), unit: &str) -> Result<(), String> {
+    if value >= limits.0 && value <= limits.1 {
+        Ok(())
+    }
+    else {
+        Err(format!("{} must be a number from {} to {} {}.", name, limits.0, limits.1, unit))
+    }
+}
+
+/// What is deciding where the antenna points.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Hold the motors still.
+    Stop,
+    /// Move each axis at the jog speed.
+    Manual,
+    /// Move to the target angles and hold there.
+    Goto,
+    /// Follow the satellite being tracked.
+    Track,
+    /// Return to the home position and hold there.
+    Park
+}
+
+impl Mode {
+    /// Every mode.
+    pub const ALL: [Mode; 5] = [Mode::Stop, Mode::Manual, Mode::Goto, Mode::Track, Mode::Park];
+
+    /// Get the lowercase name of the mode, as used by the control interfaces.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Stop => "stop",
+            Mode::Manual => "manual",
+            Mode::Goto => "goto",
+            Mode::Track => "track",
+            Mode::Park => "park"
+        }
+    }
+
+    /// Look up a mode by its name.
+    pub fn from_name(name: &str) -> Option<Mode> {
+        Mode::ALL.iter().cloned().find(|mode| mode.name() == name)
+    }
+}
+
+/// Satellite that Track mode follows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Satellite {
+    /// Name of the satellite, as written in the TLE file.
+    pub name: String,
+    /// Path of the TLE file that holds its orbit.
+    pub tle_file: String
+}
+
+/// Health of the hardware, as last seen by the control loop.
+pub struct Faults {
+    /// Latest battery voltage reading (0 until the first reading).
+    pub battery_voltage: AtomicF64,
+    /// Number of illegal transitions seen by the altitude and azimuth motor encoders.
+    pub altitude_encoder_errors: AtomicU64,
+    pub azimuth_encoder_errors: AtomicU64,
+    /// Number of failed I2C transfers to the motor controller.
+    pub i2c_errors: AtomicU64,
+    /// Number of times that the altitude and azimuth gears have slipped and been re-homed.
+    pub altitude_slip_events: AtomicU64,
+    pub azimuth_slip_events: AtomicU64,
+    /// Filtered difference (in degrees) between the absolute encoder and the motor encoder of the altitude and azimuth axes
+    /// since they were last homed. It stays near zero while the gears are healthy (and is zero without an absolute encoder).
+    pub altitude_encoder_discrepancy: AtomicF64,
+    pub azimuth_encoder_discrepancy: AtomicF64,
+    /// How far (in degrees) the altitude and azimuth gears have slipped since they were last homed.
+    pub altitude_slip: AtomicF64,
+    pub azimuth_slip: AtomicF64,
+    /// Whether the altitude and azimuth absolute encoders are failing to read.
+    pub altitude_absolute_encoder_failing: AtomicBool,
+    pub azimuth_absolute_encoder_failing: AtomicBool
+}
+
+impl Faults {
+    fn new() -> Faults {
+        Faults {
+            battery_voltage: AtomicF64::new(0.0),
+            altitude_encoder_errors: AtomicU64::new(0),
+            azimuth_encoder_errors: AtomicU64::new(0),
+            i2c_errors: AtomicU64::new(0),
+            altitude_slip_events: AtomicU64::new(0),
+            azimuth_slip_events: AtomicU64::new(0),
+            altitude_encoder_discrepancy: AtomicF64::new(0.0),
+            azimuth_encoder_discrepancy: AtomicF64::new(0.0),
+            altitude_slip: AtomicF64::new(0.0),
+            azimuth_slip: AtomicF64::new(0.0),
+            altitude_absolute_encoder_failing: AtomicBool::new(false),
+            azimuth_absolute_encoder_failing: AtomicBool::new(false)
+        }
+    }
+
+    /// Describe every current fault, one per entry. Empty if all is well.
+    pub fn active(&self) -> Vec<String> {
+        let mut faults: Vec<String> = Vec::new();
+        let battery_voltage: f64 = self.battery_voltage.load(Ordering::Relaxed);
+        if battery_voltage > 0.0 && battery_voltage < LOW_BATTERY_VOLTAGE {
+            faults.push(format!("Battery low ({:.1} V).", battery_voltage));
+        }
+        let i2c_errors: u64 = self.i2c_errors.load(Ordering::Relaxed);
+        if i2c_errors > 0 {
+            faults.push(format!("Motor controller has seen {} failed I2C transfers (check the wiring).", i2c_errors));
+        }
+        for &(axis, encoder_errors, slip_events, absolute_encoder_failing) in [
+            ("Altitude", &self.altitude_encoder_errors, &self.altitude_slip_events, &self.altitude_absolute_encoder_failing),
+            ("Azimuth", &self.azimuth_encoder_errors, &self.azimuth_slip_events, &self.azimuth_absolute_encoder_failing)
+        ].iter() {
+            let encoder_errors: u64 = encoder_errors.load(Ordering::Relaxed);
+            if encoder_errors > 0 {
+                faults.push(format!("{} motor encoder has seen {} illegal transitions (check the wiring).", axis, encoder_errors));
+            }
+            let slip_events: u64 = slip_events.load(Ordering::Relaxed);
+            if slip_events > 0 {
+                faults.push(format!("{} gears have slipped {} times.", axis, slip_events));
+            }
+            if absolute_encoder_failing.load(Ordering::Relaxed) {
+                faults.push(format!("{} absolute encoder is failing to read.", axis));
+            }
+        }
+        faults
+    }
+}
+
+/// State of the rotator shared between the control loop and everything that commands it.
+///
+/// Angles are in degrees and speeds in degrees per second, all in the frame of the antenna (not the motors).
+pub struct RotatorState {
+    /// Current mode, stored as an index into Mode::ALL.
+    mode: AtomicU8,
+    /// Altitude that the antenna should point at in Goto, Track and Park modes.
+    pub target_altitude: AtomicF64,
+    /// Azimuth that the antenna should point at in Goto, Track and Park modes.
+    pub target_azimuth: AtomicF64,
+    /// Speed of the altitude axis in Manual mode.
+    pub jog_altitude_speed: AtomicF64,
+    /// Speed of the azimuth axis in Manual mode.
+    pub jog_azimuth_speed: AtomicF64,
+    /// Time (in milliseconds since `start`) after which the jog speeds lapse to zero, or NO_DEADLINE if they do not lapse.
+    jog_deadline: AtomicU64,
+    /// When the state was created. Jog deadlines are measured from here on the monotonic clock, so
+    /// that the system time being set (e.g. by NTP after boot) cannot end a jog early or make it run on.
+    start: Instant,
+    /// Altitude that the antenna is currently pointing at.
+    pub altitude: AtomicF64,
+    /// Azimuth that the antenna is currently pointing at.
+    pub azimuth: AtomicF64,
+    /// Satellite that Track mode follows.
+    satellite: Mutex<Satellite>,
+    /// Health of the hardware.
+    pub faults: Faults
+}
+
+impl RotatorState {
+    /// Create the rotator state, stopped, with the antenna and its target at the given angles.
+    ///
+    /// # Arguments
+    ///
+    /// * `altitude` - Altitude that the antenna is pointing at.
+    ///
+    /// * `azimuth` - Azimuth that the antenna is pointing at.
+    ///
+    /// * `satellite` - Satellite for Track mode to follow.
+    pub fn new(altitude: f64, azimuth: f64, satellite: Satellite) -> RotatorState {
+        RotatorState {
+            mode: AtomicU8::new(0),
+            target_altitude: AtomicF64::new(altitude),
+            target_azimuth: AtomicF64::new(azimuth),
+            jog_altitude_speed: AtomicF64::new(0.0),
+            jog_azimuth_speed: AtomicF64::new(0.0),
+            jog_deadline: AtomicU64::new(NO_DEADLINE),
+            start: Instant::now(),
+            altitude: AtomicF64::new(altitude),
+            azimuth: AtomicF64::new(azimuth),
+            satellite: Mutex::new(satellite),
+            faults: Faults::new()
+        }
+    }
+
+    /// Get the current mode.
+    pub fn get_mode(&self) -> Mode {
+        Mode::ALL[self.mode.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Change the mode.
+    pub fn set_mode(&self, mode: Mode) {
+        let index = Mode::ALL.iter().position(|&m| m == mode).unwrap();
+        self.mode.store(index as u8, Ordering::Relaxed);
+    }
+
+    /// Get the satellite that Track mode follows.
+    pub fn get_satellite(&self) -> Satellite {
+        self.satellite.lock().unwrap().clone()
+    }
+
+    /// Change the satellite that Track mode follows.
+    pub fn set_satellite(&self, satellite: Satellite) {
+        *self.satellite.lock().unwrap() = satellite;
+    }
+
+    /// Point the antenna at an altitude and azimuth (switches to Goto mode). Returns an error, and
+    /// changes nothing, if either angle is outside ALTITUDE_LIMITS or AZIMUTH_LIMITS (or is not a number).
+    pub fn goto(&self, altitude: f64, azimuth: f64) -> Result<(), String> {
+        check_limit("Altitude", altitude, ALTITUDE_LIMITS, "degrees")?;
+        check_limit("Azimuth", azimuth, AZIMUTH_LIMITS, "degrees")?;
+        self.target_altitude.store(altitude, Ordering::Relaxed);
+        self.target_azimuth.store(azimuth, Ordering::Relaxed);
+        self.set_mode(Mode::Goto);
+        Ok(())
+    }
+
+    /// Move the axes at the given speeds until told otherwise (switches to Manual mode). Returns an
+    /// error, and changes nothing, if either speed is faster than MAX_JOG_SPEED (or is not a number).
+    pub fn jog(&self, altitude_speed: f64, azimuth_speed: f64) -> Result<(), String> {
+        self.set_jog(altitude_speed, azimuth_speed, NO_DEADLINE)
+    }
+
+    /// Check and set the jog speeds and when they lapse (in milliseconds since `start`), and switch to Manual mode.
+    fn set_jog(&self, altitude_speed: f64, azimuth_speed: f64, deadline: u64) -> Result<(), String> {
+        check_limit("Altitude speed", altitude_speed, (-MAX_JOG_SPEED, MAX_JOG_SPEED), "degrees per second")?;
+        check_limit("Azimuth speed", azimuth_speed, (-MAX_JOG_SPEED, MAX_JOG_SPEED), "degrees per second")?;
+        self.jog_deadline.store(deadline, Ordering::Relaxed);
+        self.jog_altitude_speed.store(altitude_speed, Ordering::Relaxed);
+        self.jog_azimuth_speed.store(azimuth_speed, Ordering::Relaxed);
+        self.set_mode(Mode::Manual);
+        Ok(())
+    }
+
+    /// Milliseconds since the state was created, on the monotonic clock.
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    /// Move the axes at the given speeds for a short time (switches to Manual mode). The axes stop
+    /// unless this is called again before the time is up, so that a held key, button or stick acts
+    /// as a deadman control: the rotator stops if the input stops arriving. The speeds are checked as by jog().
+    ///
+    /// # Arguments
+    ///
+    /// * `altitude_speed` - Speed of the altitude axis in degrees per second.
+    ///
+    /// * `azimuth_speed` - Speed of the azimuth axis in degrees per second.
+    ///
+    /// * `timeout` - How long the speeds last.
+    pub fn jog_for(&self, altitude_speed: f64, azimuth_speed: f64, timeout: Duration) -> Result<(), String> {
+        self.set_jog(altitude_speed, azimuth_speed, self.elapsed_ms() + timeout.as_millis() as u64)
+    }
+
+    /// Get the speeds (in degrees per second) that the altitude and azimuth axes should move at in
+    /// Manual mode. They are zero once a jog_for() has run out.
+    pub fn get_jog_speeds(&self) -> (f64, f64) {
+        if self.elapsed_ms() > self.jog_deadline.load(Ordering::Relaxed) {
+            (0.0, 0.0)
+        }
+        else {
+            (self.jog_altitude_speed.load(Ordering::Relaxed), self.jog_azimuth_speed.load(Ordering::Relaxed))
+        }
+    }
+
+    /// Point the antenna at an altitude, keeping the azimuth on its current target if there is one, or where it is otherwise (switches to Goto mode).
+    pub fn goto_altitude(&self, altitude: f64) -> Result<(), String> {
+        let azimuth: f64 = if self.get_mode() == Mode::Goto { self.target_azimuth.load(Ordering::Relaxed) } else { self.azimuth.load(Ordering::Relaxed) };
+        self.goto(altitude, azimuth)
+    }
+
+    /// Point the antenna at an azimuth, keeping the altitude on its current target if there is one, or where it is otherwise (switches to Goto mode).
+    pub fn goto_azimuth(&self, azimuth: f64) -> Result<(), String> {
+        let altitude: f64 = if self.get_mode() == Mode::Goto { self.target_altitude.load(Ordering::Relaxed) } else { self.altitude.load(Ordering::Relaxed) };
+        self.goto(altitude, azimuth)
+    }
+
+    /// Move the altitude axis at a speed. The azimuth axis carries on jogging if it was, and is
+    /// stopped otherwise (switches to Manual mode). If the azimuth jog was from jog_for(), both axes
+    /// stop when it runs out, so that the deadman control still works.
+    pub fn jog_altitude(&self, speed: f64) -> Result<(), String> {
+        let (azimuth_speed, deadline) = self.carried_jog().1;
+        self.set_jog(speed, azimuth_speed, deadline)
+    }
+
+    /// Move the azimuth axis at a speed. The altitude axis carries on jogging if it was, and is
+    /// stopped otherwise (switches to Manual mode). If the altitude jog was from jog_for(), both axes
+    /// stop when it runs out.
+    pub fn jog_azimuth(&self, speed: f64) -> Result<(), String> {
+        let (altitude_speed, deadline) = self.carried_jog().0;
+        self.set_jog(altitude_speed, speed, deadline)
+    }
+
+    /// Get the jog speed of each axis, with the deadline to keep if it carries on jogging at that
+    /// speed while the other axis changes, as ((altitude speed, deadline), (azimuth speed, deadline)).
+    fn carried_jog(&self) -> ((f64, u64), (f64, u64)) {
+        let (altitude_speed, azimuth_speed) = if self.get_mode() == Mode::Manual { self.get_jog_speeds() } else { (0.0, 0.0) };
+        let deadline: u64 = self.jog_deadline.load(Ordering::Relaxed);
+        let carry = |speed: f64| if speed != 0.0 { (speed, deadline) } else { (0.0, NO_DEADLINE) };
+        (carry(altitude_speed), carry(azimuth_speed))
+    }
+
+    /// Stop the altitude axis. While jogging, the azimuth axis carries on. While going to a target, the altitude axis holds where it is. Otherwise, everything stops.
+    pub fn stop_altitude(&self) {
+        match self.get_mode() {
+            Mode::Manual => self.jog_altitude_speed.store(0.0, Ordering::Relaxed),
+            // The antenna may be outside the limits of a new target, so the hold is set directly.
+            Mode::Goto => self.target_altitude.store(self.altitude.load(Ordering::Relaxed), Ordering::Relaxed),
+            _ => self.set_mode(Mode::Stop)
+        }
+    }
+
+    /// Stop the azimuth axis. While jogging, the altitude axis carries on. While going to a target, the azimuth axis holds where it is. Otherwise, everything stops.
+    pub fn stop_azimuth(&self) {
+        match self.get_mode() {
+            Mode::Manual => self.jog_azimuth_speed.store(0.0, Ordering::Relaxed),
+            Mode::Goto => self.target_azimuth.store(self.azimuth.load(Ordering::Relaxed), Ordering::Relaxed),
+            _ => self.set_mode(Mode::Stop)
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ self, BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::mpsc::{ self, Receiver, RecvTimeoutError, SyncSender, TrySendError };
+use std::thread;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// Number of records that can wait for the writer thread before new ones are dropped.
+const QUEUE_LENGTH: usize = 8192;
+/// Buffered records are written to disk at least this often.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// How long sync() waits for the writer thread to catch up.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Get the current time in milliseconds since the UNIX epoch, as used in the Time column of every telemetry file.
+pub fn now_ms() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+/// One row of telemetry. Each kind of record goes to its own stream of files.
+#[derive(Clone)]
+pub enum Record {
+    /// One update of a PID. Goes to the stream named after the PID.
+    Pid {
+        /// Name of the PID.
+        name: &'static str,
+        /// Milliseconds since the UNIX epoch.
+        time: u128,
+        error: f64,
+        p: f64,
+        i_accumulator: f64,
+        d: f64,
+        output: f64,
+        input: f64,
+        target: f64,
+        /// Seconds since the previous update that the PID used (0.0 for its first update).
+        dt: f64
+    },
+    /// One update of the motor control loop.
+    Motors {
+        /// Milliseconds since the UNIX epoch.
+        time: u128,
+        /// Power level (from -1.0 to 1.0) sent to motor 1.
+        power_1: f64,
+        /// Power level (from -1.0 to 1.0) sent to motor 2.
+        power_2: f64,
+        /// Latest battery voltage reading.
+        battery_voltage: f64
+    },
+    /// One update of the position control loop. Angles are in degrees.
+    Rotator {
+        /// Milliseconds since the UNIX epoch.
+        time: u128,
+        /// What is deciding the target (the name of the rotator mode).
+        source: &'static str,
+        target_altitude: f64,
+        target_azimuth: f64,
+        altitude: f64,
+        azimuth: f64,
+        /// Total revolutions of the driving motor of each axis.
+        altitude_revs: f64,
+        azimuth_revs: f64,
+        /// Raw readings of the absolute encoder of each axis, or NaN where there is none or it failed to read.
+        altitude_absolute: f64,
+        azimuth_absolute: f64
+    },
+    /// Ask the writer thread to write everything out and then acknowledge.
+    Sync(SyncSender<()>)
+}
+
+impl Record {
+    /// Name of the stream that the record belongs to.
+    fn stream(&self) -> &'static str {
+        match self {
+            Record::Pid { name, .. } => name,
+            Record::Motors { .. } => "motors",
+            Record::Rotator { .. } => "rotator",
+            Record::Sync(_) => ""
+        }
+    }
+
+    /// CSV header line of the record's stream.
+    fn header(&self) -> &'static str {
+        match self {
+            Record::Pid { .. } => "Time,Error,P,I-Accumulator,D,Output,Input,Target,Dt",
+            Record::Motors { .. } => "Time,Power-1,Power-2,Battery-Voltage",
+            Record::Rotator { .. } => "Time,Source,Target-Altitude,Target-Azimuth,Altitude,Azimuth,Altitude-Revs,Azimuth-Revs,Altitude-Absolute,Azimuth-Absolute",
+            Record::Sync(_) => ""
+        }
+    }
+
+    /// The record as a JSON object, for live telemetry.
+    pub fn to_json(&self) -> String {
+        use crate::json::{ number, string };
+        match self {
+            Record::Pid { time, error, p, i_accumulator, d, output, input, target, dt, .. } => format!("{{\"time\":{},\"error\":{},\"p\":{},\"i_accumulator\":{},\"d\":{},\"output\":{},\"input\":{},\"target\":{},\"dt\":{}}}", time, number(*error), number(*p), number(*i_accumulator), number(*d), number(*output), number(*input), number(*target), number(*dt)),
+            Record::Motors { time, power_1, power_2, battery_voltage } => format!("{{\"time\":{},\"power_1\":{},\"power_2\":{},\"battery_voltage\":{}}}", time, number(*power_1), number(*power_2), number(*battery_voltage)),
+            Record::Rotator { time, source, target_altitude, target_azimuth, altitude, azimuth, altitude_revs, azimuth_revs, altitude_absolute, azimuth_absolute } => format!("{{\"time\":{},\"source\":{},\"target_altitude\":{},\"target_azimuth\":{},\"altitude\":{},\"azimuth\":{},\"altitude_revs\":{},\"azimuth_revs\":{},\"altitude_absolute\":{},\"azimuth_absolute\":{}}}", time, string(source), number(*target_altitude), number(*target_azimuth), number(*altitude), number(*azimuth), number(*altitude_revs), number(*azimuth_revs), number(*altitude_absolute), number(*azimuth_absolute)),
+            Record::Sync(_) => "null".to_string()
+        }
+    }
+
+    /// The record as a CSV line.
+    fn row(&self) -> String {
+        match self {
+            Record::Pid { time, error, p, i_accumulator, d, output, input, target, dt, .. } => format!("{},{},{},{},{},{},{},{},{}", time, error, p, i_accumulator, d, output, input, target, dt),
+            Record::Motors { time, power_1, power_2, battery_voltage } => format!("{},{},{},{}", time, power_1, power_2, battery_voltage),
+            Record::Rotator { time, source, target_altitude, target_azimuth, altitude, azimuth, altitude_revs, azimuth_revs, altitude_absolute, azimuth_absolute } => format!("{},{},{},{},{},{},{},{},{},{}", time, source, target_altitude, target_azimuth, altitude, azimuth, altitude_revs, azimuth_revs, altitude_absolute, azimuth_absolute),
+            Record::Sync(_) => String::new()
+        }
+    }
+}
+
+/// Handle for sending telemetry to the background writer. Cheap to clone, and logging never
+/// blocks: if the writer falls behind, records are dropped (and counted) rather than stalling a
+/// control loop.
+#[derive(Clone)]
+pub struct Telemetry {
+    /// Queue to the writer thread.
+    sender: SyncSender<Record>,
+    /// Number of records dropped because the queue was full.
+    dropped: Arc<AtomicU64>,
+    /// Latest record of each stream, for live telemetry.
+    latest: Arc<Mutex<HashMap<&'static str, Record>>>
+}
+
+impl Telemetry {
+    /// Start the background writer thread.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - Directory that the telemetry files are written to. It is created if needed.
+    ///
+    /// * `max_file_size` - A file is closed and a new one started once it reaches this many bytes.
+    ///
+    /// * `max_files` - Number of files kept for each stream. The oldest are deleted.
+    pub fn start(directory: &str, max_file_size: u64, max_files: usize) -> Telemetry {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let latest = Arc::new(Mutex::new(HashMap::new()));
+        let mut writer = Writer { directory: PathBuf::from(directory), max_file_size: max_file_size, max_files: max_files.max(1), files: HashMap::new(), latest: Arc::clone(&latest) };
+        thread::spawn(move || writer.run(receiver));
+        Telemetry { sender: sender, dropped: Arc::new(AtomicU64::new(0)), latest: latest }
+    }
+
+    /// Queue a record to be written.
+    pub fn log(&self, record: Record) {
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Get the latest record of each stream that has been logged, keyed by stream name (e.g. "motor_1_speed", "motors" or "rotator").
+    pub fn latest(&self) -> HashMap<&'static str, Record> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Wait (for up to a couple of seconds) until everything queued so far is on disk. Call this before exiting.
+    pub fn sync(&self) {
+        let (ack_sender, ack_receiver) = mpsc::sync_channel(1);
+        if self.sender.send(Record::Sync(ack_sender)).is_ok() {
+            let _ = ack_receiver.recv_timeout(SYNC_TIMEOUT);
+        }
+        let dropped: u64 = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            println!("Telemetry: Dropped {} records because the disk could not keep up.", dropped);
+        }
+    }
+}
+
+/// File that one stream is currently being written to.
+struct StreamFile {
+    file: BufWriter<File>,
+    /// Number of bytes written to the file so far.
+    size: u64
+}
+
+/// Runs in the background thread, writing records to rotating files.
+struct Writer {
+    directory: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    /// Open file of each stream.
+    files: HashMap<&'static str, StreamFile>,
+    /// Latest record of each stream (shared with Telemetry).
+    latest: Arc<Mutex<HashMap<&'static str, Record>>>
+}
+
+impl Writer {
+    /// Write records until every Telemetry handle has been dropped.
+    fn run(&mut self, receiver: Receiver<Record>) {
+        // Without the directory, records are still kept for live telemetry.
+        let writing: bool = match fs::create_dir_all(&self.directory) {
+            Ok(()) => true,
+            Err(error) => {
+                println!("Telemetry: ERROR, failed to create {}: {}. Telemetry is not written to disk.", self.directory.display(), error);
+                false
+            }
+        };
+
+        loop {
+            match receiver.recv_timeout(FLUSH_INTERVAL) {
+                Ok(Record::Sync(ack)) => {
+                    self.flush();
+                    let _ = ack.send(());
+                }
+                Ok(record) => {
+                    if writing {
+                        if let Err(error) = self.write(&record) {
+                            println!("Telemetry: ERROR, failed to write {} telemetry: {}.", record.stream(), error);
+                            self.files.remove(record.stream());
+                        }
+                    }
+                    self.latest.lock().unwrap().insert(record.stream(), record);
+                }
+                Err(RecvTimeoutError::Timeout) => self.flush(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Write one record, starting a new file for its stream if there is none yet or the current one is full.
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let stream: &'static str = record.stream();
+        let full: bool = match self.files.get(stream) {
+            Some(stream_file) => stream_file.size >= self.max_file_size,
+            None => true
+        };
+        if full {
+            if let Some(mut old) = self.files.remove(stream) {
+                old.file.flush()?;
+            }
+            let stream_file = self.open(stream, record.header())?;
+            self.files.insert(stream, stream_file);
+            self.remove_old_files(stream);
+        }
+
+        let stream_file = self.files.get_mut(stream).unwrap();
+        let row: String = record.row();
+        writeln!(stream_file.file, "{}", row)?;
+        stream_file.size += row.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Start a new file for a stream, named after the stream and the time (so that the files sort in time order).
+    fn open(&self, stream: &str, header: &str) -> io::Result<StreamFile> {
+        let timestamp: String = time::now_utc().strftime("%Y%m%d-%H%M%S").unwrap().to_string();
+        let mut path: PathBuf = self.directory.join(format!("{}_{}.csv", stream, timestamp));
+        let mut count: u32 = 1;
+        while path.exists() {
+            path = self.directory.join(format!("{}_{}-{:03}.csv", stream, timestamp, count));
+            count += 1;
+        }
+
+        let mut file = BufWriter::new(File::create(&path)?);
+        writeln!(file, "{}", header)?;
+        Ok(StreamFile { file: file, size: header.len() as u64 + 1 })
+    }
+
+    /// Delete the oldest files of a stream so that at most max_files are left.
+    fn remove_old_files(&self, stream: &str) {
+        let prefix: String = format!("{}_", stream);
+        let mut paths: Vec<PathBuf> = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).filter(|path| is_stream_file(path, &prefix)).collect(),
+            Err(_) => return
+        };
+        if paths.len() <= self.max_files {
+            return;
+        }
+        // Sort on the names without the extension, so that a file started in the same second as another ("-001") sorts after it.
+        paths.sort_by(|a, b| a.file_stem().cmp(&b.file_stem()));
+        for path in paths[..paths.len() - self.max_files].iter() {
+            if let Err(error) = fs::remove_file(path) {
+                println!("Telemetry: ERROR, failed to remove {}: {}.", path.display(), error);
+            }
+        }
+    }
+
+    /// Write everything buffered to disk.
+    fn flush(&mut self) {
+        for (stream, stream_file) in self.files.iter_mut() {
+            if let Err(error) = stream_file.file.flush() {
+                println!("Telemetry: ERROR, failed to write {} telemetry: {}.", stream, error);
+            }
+        }
+    }
+}
+
+/// Returns true if `path` is a telemetry file of the stream whose files start with `prefix`.
+fn is_stream_file(path: &Path, prefix: &str) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        // The part after the prefix is a timestamp, so it starts with a digit. This keeps e.g. "motors_" from matching another stream's files.
+        Some(name) => name.starts_with(prefix) && name.ends_with(".csv") && name[prefix.len()..].starts_with(|c: char| c.is_ascii_digit()),
+        None => false
+    }
+}
@@ -3,7 +3,9 @@ extern crate rppal;
 use rppal::i2c::I2c;
 
 pub struct Thunderborg {
-    i2c: I2c
+    i2c: I2c,
+    /// Number of I2C transfers that have failed while driving the motors or reading the battery.
+    errors: u64
 }
 
 impl Thunderborg {
@@ -20,6 +22,10 @@ impl Thunderborg {
     const COMMAND_SET_B_FWD: u8 = 11;
     const COMMAND_SET_A_REV: u8 = 9;
     const COMMAND_SET_A_FWD: u8 = 8;
+    const COMMAND_GET_BATT_VOLT: u8 = 21;
+    const COMMAND_ANALOG_MAX: f64 = 1023.0;
+    const VOLTAGE_PIN_MAX: f64 = 36.3;
+    const VOLTAGE_PIN_CORRECTION: f64 = 0.0;
 
     pub fn new(address: u16) -> Thunderborg {
         let mut i2c_bus: I2c = I2c::new().unwrap();
@@ -34,7 +40,7 @@ impl Thunderborg {
         else {
             println!("Thunderborg: ERROR, failed to find Thunderborg device on I2C bus.");
         }
-        Thunderborg { i2c: i2c_bus }
+        Thunderborg { i2c: i2c_bus, errors: 0 }
     }
 
     pub fn set_led_show_battery(&mut self, state: bool) {
@@ -78,6 +84,17 @@ impl Thunderborg {
         }
     }
 
+    /// Read the battery voltage. Returns NaN if the I2C transfer fails.
+    pub fn get_battery_voltage(&mut self) -> f64 {
+        let mut buf: [u8; Thunderborg::I2C_MAX_LEN] = [0; Thunderborg::I2C_MAX_LEN];
+        if self.i2c.write(&Thunderborg::COMMAND_GET_BATT_VOLT.to_ne_bytes()).is_err() || self.i2c.read(&mut buf).is_err() {
+            self.errors += 1;
+            return f64::NAN;
+        }
+        let raw: u16 = ((buf[1] as u16) << 8) | (buf[2] as u16);
+        (raw as f64 / Thunderborg::COMMAND_ANALOG_MAX) * Thunderborg::VOLTAGE_PIN_MAX + Thunderborg::VOLTAGE_PIN_CORRECTION
+    }
+
     pub fn set_motor_1(&mut self, power: f64) {
         let command: u8;
         let mut pwm: u8;
@@ -94,7 +111,10 @@ impl Thunderborg {
         }
 
         let buf: [u8; 2] = [command, pwm];
-        self.i2c.write(&buf).unwrap();
+        // A failed write is counted rather than stopping the motor thread: the next loop sends the power again.
+        if self.i2c.write(&buf).is_err() {
+            self.errors += 1;
+        }
     }
 
     pub fn set_motor_2(&mut self, power: f64) {
@@ -113,6 +133,13 @@ impl Thunderborg {
         }
 
         let buf: [u8; 2] = [command, pwm];
-        self.i2c.write(&buf).unwrap();
+        if self.i2c.write(&buf).is_err() {
+            self.errors += 1;
+        }
+    }
+
+    /// Get the number of I2C transfers that have failed while driving the motors or reading the battery.
+    pub fn get_errors(&self) -> u64 {
+        self.errors
     }
 }
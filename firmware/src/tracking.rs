@@ -0,0 +1,102 @@
+use gpredict::{ Predict, Location, Tle };
+use crate::state::Satellite;
+
+/// Most passes that are looked for in one go.
+const MAX_PASSES: usize = 20;
+/// Time step (in seconds) used to find the highest elevation of a pass.
+const PASS_STEP: i64 = 30;
+/// Time step (in seconds) between the points of a pass track.
+const TRACK_STEP: i64 = 15;
+
+/// A pass of a satellite over the rotator.
+pub struct Pass {
+    /// Acquisition of signal, when the satellite rises above the horizon. For a pass that is already underway, the time it was looked for from.
+    pub aos: time::Tm,
+    /// Loss of signal, when the satellite sets.
+    pub los: time::Tm,
+    /// Highest elevation (in degrees) of the satellite during the pass.
+    pub max_elevation: f64
+}
+
+/// Load the orbit of a satellite, ready to predict where it is from a location.
+///
+/// # Arguments
+///
+/// * `satellite` - The satellite.
+///
+/// * `location` - Where the rotator is.
+pub fn load(satellite: &Satellite, location: &Location) -> Result<Predict, String> {
+    match Tle::from_file(&satellite.name, &satellite.tle_file) {
+        Ok(tle) => Ok(Predict::new(&tle, location)),
+        Err(_) => Err(format!("no satellite called \"{}\" in {}", satellite.name, satellite.tle_file))
+    }
+}
+
+/// Find the next passes of a satellite.
+///
+/// # Arguments
+///
+/// * `predict` - Predictor for the satellite. Its current position is changed by the search.
+///
+/// * `start` - Time to look from. A pass that is underway at this time is included.
+///
+/// * `count` - Number of passes to find (at most MAX_PASSES). Fewer are returned if the satellite never rises.
+pub fn passes(predict: &mut Predict, start: time::Tm, count: usize) -> Vec<Pass> {
+    let mut passes: Vec<Pass> = Vec::new();
+    let mut search_time: time::Tm = start;
+    while passes.len() < count.min(MAX_PASSES) {
+        predict.update(Some(search_time));
+        let aos: time::Tm = if predict.sat.el_deg > 0.0 {
+            search_time
+        }
+        else {
+            match predict.sat.aos {
+                Some(aos) => aos,
+                None => break
+            }
+        };
+        // The predicted LOS is for the pass at the search time, so look again from the AOS.
+        predict.update(Some(aos));
+        let los: time::Tm = match predict.sat.los {
+            Some(los) if los > aos => los,
+            _ => break
+        };
+
+        let mut max_elevation: f64 = predict.sat.el_deg;
+        let mut sample_time: time::Tm = aos;
+        while sample_time < los {
+            sample_time = sample_time + time::Duration::seconds(PASS_STEP);
+            predict.update(Some(if sample_time < los { sample_time } else { los }));
+            max_elevation = max_elevation.max(predict.sat.el_deg);
+        }
+
+        passes.push(Pass { aos: aos, los: los, max_elevation: max_elevation });
+        search_time = los + time::Duration::seconds(PASS_STEP);
+    }
+    passes
+}
+
+/// Get the path of a satellite across the sky during a pass, as (time, azimuth, elevation) points
+/// with angles in degrees. The first point is at the AOS and the last at the LOS.
+///
+/// # Arguments
+///
+/// * `predict` - Predictor for the satellite. Its current position is changed.
+///
+/// * `pass` - The pass.
+pub fn pass_track(predict: &mut Predict, pass: &Pass) -> Vec<(time::Tm, f64, f64)> {
+    let mut points: Vec<(time::Tm, f64, f64)> = Vec::new();
+    let mut point_time: time::Tm = pass.aos;
+    loop {
+        let last: bool = point_time >= pass.los;
+        if last {
+            point_time = pass.los;
+        }
+        predict.update(Some(point_time));
+        points.push((point_time, predict.sat.az_deg, predict.sat.el_deg));
+        if last {
+            return points;
+        }
+        point_time = point_time + time::Duration::seconds(TRACK_STEP);
+    }
+}
@@ -0,0 +1,470 @@
+use std::io::{ self, Write };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::Ordering;
+use std::time::{ Duration, Instant };
+use gpredict::{ Location, Predict };
+use crate::state::{ RotatorState, Mode, Satellite, JOG_SPEEDS, DEFAULT_JOG_SPEED };
+use crate::telemetry::{ Telemetry, Record };
+use crate::tracking::{ self, Pass };
+
+/// Time between screen updates. Only the lines that change are sent, so this costs little bandwidth.
+const FRAME_INTERVAL: Duration = Duration::from_millis(250);
+/// The whole screen is redrawn this often, to clean up after messages printed by other threads.
+const FULL_REDRAW_INTERVAL: Duration = Duration::from_secs(5);
+/// The pass list is recalculated this often.
+const PASS_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of passes listed.
+const PASS_COUNT: usize = 4;
+/// How long an arrow key press keeps an axis moving. Holding the key down repeats it, so this must
+/// be longer than the terminal's delay before it starts repeating (usually 250 to 660 ms).
+const KEY_JOG_TIMEOUT: Duration = Duration::from_millis(700);
+/// Speed of light, in kilometres per second.
+const SPEED_OF_LIGHT: f64 = 299792.458;
+
+/// ANSI colours used on screen.
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+/// Terminal settings from before the TUI changed them, while it has them changed. Kept here rather
+/// than in Terminal so that restore_terminal() can put them back when the process exits without
+/// unwinding (e.g. after the second Control-C).
+static ORIGINAL_TERMIOS: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// A key pressed by the user.
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Escape,
+    Backspace,
+    Char(char)
+}
+
+/// The controlling terminal, switched to unbuffered input and the alternate screen for as long as
+/// this exists. Output processing is left on, so that messages printed by other threads still
+/// make sense, and so is Control-C.
+struct Terminal {
+    /// Lines currently on screen.
+    previous: Vec<String>,
+    /// When the whole screen was last redrawn.
+    last_full_redraw: Instant
+}
+
+impl Terminal {
+    fn open() -> io::Result<Terminal> {
+        let original: libc::termios = unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            termios
+        };
+        let mut termios: libc::termios = original;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+        // Reads return after at most a tenth of a second, so that the screen keeps updating.
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 1;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *ORIGINAL_TERMIOS.lock().unwrap() = Some(original);
+        // Alternate screen, hidden cursor.
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Terminal { previous: Vec::new(), last_full_redraw: Instant::now() })
+    }
+
+    /// Get the size of the terminal as (columns, rows).
+    fn size(&self) -> (usize, usize) {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+            (size.ws_col as usize, size.ws_row as usize)
+        }
+        else {
+            (80, 24)
+        }
+    }
+
+    /// Wait briefly for key presses. Several may arrive at once when typing fast or pasting.
+    fn read_keys(&mut self) -> Vec<Key> {
+        let mut buffer: [u8; 64] = [0; 64];
+        let count: isize = unsafe { libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) };
+        if count <= 0 {
+            return Vec::new();
+        }
+        let text: String = String::from_utf8_lossy(&buffer[..count as usize]).into_owned();
+        let mut keys: Vec<Key> = Vec::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            keys.push(match c {
+                '\x1b' => match chars.peek() {
+                    Some('[') | Some('O') => {
+                        chars.next();
+                        match chars.next() {
+                            Some('A') => Key::Up,
+                            Some('B') => Key::Down,
+                            Some('C') => Key::Right,
+                            Some('D') => Key::Left,
+                            _ => continue
+                        }
+                    }
+                    _ => Key::Escape
+                },
+                '\r' | '\n' => Key::Enter,
+                '\x7f' | '\x08' => Key::Backspace,
+                c => Key::Char(c)
+            });
+        }
+        keys
+    }
+
+    /// Put lines on screen, sending only the ones that changed since the last frame.
+    fn draw(&mut self, lines: Vec<String>) {
+        let full: bool = self.last_full_redraw.elapsed() >= FULL_REDRAW_INTERVAL || lines.len() != self.previous.len();
+        let mut output = String::new();
+        if full {
+            output.push_str("\x1b[2J");
+            self.previous.clear();
+            self.last_full_redraw = Instant::now();
+        }
+        let (columns, _) = self.size();
+        for (row, line) in lines.iter().enumerate() {
+            if self.previous.get(row) != Some(line) {
+                output.push_str(&format!("\x1b[{};1H{}{}\x1b[K", row + 1, fit(line, columns), RESET));
+            }
+        }
+        if !output.is_empty() {
+            print!("{}", output);
+            let _ = io::stdout().flush();
+        }
+        self.previous = lines;
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// Put the terminal back as it was before the TUI started: leave the alternate screen, show the
+/// cursor and restore the terminal settings. Does nothing if the TUI is not running. Call this
+/// before exiting the process from outside the TUI.
+pub fn restore_terminal() {
+    let original: Option<libc::termios> = match ORIGINAL_TERMIOS.lock() {
+        Ok(mut original) => original.take(),
+        Err(_) => None
+    };
+    if let Some(original) = original {
+        print!("{}\x1b[?25h\x1b[?1049l", RESET);
+        let _ = io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &original) };
+    }
+}
+
+/// Cut a line down to a number of visible characters, so that it does not wrap. Colour codes do not count.
+fn fit(line: &str, columns: usize) -> &str {
+    let mut visible: usize = 0;
+    let mut escape: bool = false;
+    for (index, c) in line.char_indices() {
+        if escape {
+            escape = !c.is_ascii_alphabetic();
+        }
+        else if c == '\x1b' {
+            escape = true;
+        }
+        else {
+            if visible == columns {
+                return &line[..index];
+            }
+            visible += 1;
+        }
+    }
+    line
+}
+
+/// Returns true if the console is an interactive terminal that the TUI can run on.
+pub fn available() -> bool {
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Draw a gauge of an angle, filled up to the current angle, with the target marked.
+fn gauge(value: f64, target: Option<f64>, min: f64, max: f64, width: usize) -> String {
+    let position = |angle: f64| ((angle - min) / (max - min) * width as f64).max(0.0).min(width as f64 - 1.0) as usize;
+    let filled: usize = position(value) + 1;
+    let target: Option<usize> = target.filter(|target| target.is_finite()).map(position);
+    // Colour runs of cells rather than single cells, to keep the output small.
+    let mut bar = String::new();
+    let mut colour: &str = "";
+    for index in 0..width {
+        let (cell_colour, cell) = if Some(index) == target { (YELLOW, '◆') } else if index < filled { (CYAN, '█') } else { (RESET, '░') };
+        if cell_colour != colour {
+            bar.push_str(cell_colour);
+            colour = cell_colour;
+        }
+        bar.push(cell);
+    }
+    bar.push_str(RESET);
+    bar
+}
+
+/// Draw a motor power level (-1 to 1) as a bar growing left or right from the middle.
+fn power_bar(power: f64, width: usize) -> String {
+    let half: usize = width / 2;
+    let length: usize = (power.abs().min(1.0) * half as f64).round() as usize;
+    let colour: &str = if power.abs() > 0.9 { RED } else { GREEN };
+    let mut bar = String::new();
+    for index in 0..half {
+        bar.push(if power < 0.0 && index >= half - length { '█' } else { ' ' });
+    }
+    bar.push('│');
+    for index in 0..half {
+        bar.push(if power > 0.0 && index < length { '█' } else { ' ' });
+    }
+    format!("{}{}{}", colour, bar, RESET)
+}
+
+/// Format a number of seconds as e.g. "1h 02m 03s".
+fn countdown(seconds: i64) -> String {
+    let seconds: i64 = seconds.max(0);
+    if seconds >= 3600 {
+        format!("{}h {:02}m {:02}s", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+    else {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    }
+}
+
+/// Format an angle, or a dash if there is none.
+fn angle(value: Option<f64>) -> String {
+    match value {
+        Some(value) if value.is_finite() => format!("{:7.2}°", value),
+        _ => "      –  ".to_string()
+    }
+}
+
+/// Full-screen terminal interface for running the rotator in the field.
+pub struct Tui {
+    state: Arc<RotatorState>,
+    telemetry: Telemetry,
+    location: &'static Location,
+    /// Downlink and uplink frequencies (in hertz) to Doppler-correct. 0 hides a frequency.
+    downlink_frequency: f64,
+    uplink_frequency: f64,
+    /// Index into JOG_SPEEDS of the jog speed.
+    jog_speed: usize,
+    /// Text typed at the goto prompt, while it is open.
+    input: Option<String>,
+    /// Message shown at the bottom of the screen.
+    message: String,
+    /// Satellite that predict and passes are for.
+    satellite: Option<Satellite>,
+    predict: Option<Predict>,
+    passes: Vec<Pass>,
+    /// When the passes were last calculated.
+    passes_time: Option<Instant>
+}
+
+impl Tui {
+    /// Create the TUI.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The rotator state that keys act on.
+    ///
+    /// * `telemetry` - Telemetry that the control loops log to, for the motor power levels.
+    ///
+    /// * `location` - Where the rotator is.
+    ///
+    /// * `downlink_frequency` - Satellite downlink frequency in hertz, or 0 to hide it.
+    ///
+    /// * `uplink_frequency` - Satellite uplink frequency in hertz, or 0 to hide it.
+    pub fn new(state: Arc<RotatorState>, telemetry: Telemetry, location: &'static Location, downlink_frequency: f64, uplink_frequency: f64) -> Tui {
+        Tui { state: state, telemetry: telemetry, location: location, downlink_frequency: downlink_frequency, uplink_frequency: uplink_frequency, jog_speed: DEFAULT_JOG_SPEED, input: None, message: String::new(), satellite: None, predict: None, passes: Vec::new(), passes_time: None }
+    }
+
+    /// Run the TUI until the user quits it. Returns an error if the terminal cannot be set up.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut terminal = Terminal::open()?;
+        let mut last_frame: Option<Instant> = None;
+        loop {
+            for key in terminal.read_keys() {
+                if !self.key(key) {
+                    return Ok(());
+                }
+                // Show the effect of a key straight away.
+                last_frame = None;
+            }
+            if last_frame.map_or(true, |last_frame| last_frame.elapsed() >= FRAME_INTERVAL) {
+                self.update_prediction();
+                let (columns, rows) = terminal.size();
+                terminal.draw(self.render(columns, rows));
+                last_frame = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Handle a key press. Returns false if the user quit.
+    fn key(&mut self, key: Key) -> bool {
+        if let Some(mut input) = self.input.take() {
+            match key {
+                Key::Enter => self.goto(&input),
+                Key::Escape => self.message.clear(),
+                Key::Backspace => {
+                    input.pop();
+                    self.input = Some(input);
+                }
+                Key::Char(c) => {
+                    input.push(c);
+                    self.input = Some(input);
+                }
+                _ => self.input = Some(input)
+            }
+            return true;
+        }
+
+        let speed: f64 = JOG_SPEEDS[self.jog_speed];
+        match key {
+            // Each press moves one axis briefly, so the rotator only moves while an arrow is held down.
+            Key::Up => self.jog(speed, 0.0),
+            Key::Down => self.jog(-speed, 0.0),
+            Key::Right => self.jog(0.0, speed),
+            Key::Left => self.jog(0.0, -speed),
+            Key::Char(' ') | Key::Char('s') => self.state.set_mode(Mode::Stop),
+            Key::Char('t') => self.state.set_mode(Mode::Track),
+            Key::Char('p') => self.state.set_mode(Mode::Park),
+            Key::Char('+') | Key::Char('=') => self.jog_speed = (self.jog_speed + 1).min(JOG_SPEEDS.len() - 1),
+            Key::Char('-') => self.jog_speed = self.jog_speed.saturating_sub(1),
+            Key::Char('g') => {
+                self.input = Some(String::new());
+                self.message = "Go to <altitude> <azimuth>, then Enter (Escape cancels):".to_string();
+            }
+            Key::Char('q') => return false,
+            _ => {}
+        }
+        true
+    }
+
+    /// Jog briefly, as for one press of an arrow key.
+    fn jog(&mut self, altitude_speed: f64, azimuth_speed: f64) {
+        if let Err(error) = self.state.jog_for(altitude_speed, azimuth_speed, KEY_JOG_TIMEOUT) {
+            self.message = error;
+        }
+    }
+
+    /// Go to the angles typed at the goto prompt.
+    fn goto(&mut self, input: &str) {
+        let angles: Vec<f64> = input.split_whitespace().filter_map(|word| word.parse::<f64>().ok()).filter(|angle| angle.is_finite()).collect();
+        match angles.as_slice() {
+            [altitude, azimuth] if input.split_whitespace().count() == 2 => {
+                self.message = match self.state.goto(*altitude, *azimuth) {
+                    Ok(()) => format!("Going to altitude {:.1}°, azimuth {:.1}°.", altitude, azimuth),
+                    Err(error) => error
+                };
+            }
+            _ => self.message = format!("\"{}\" is not an altitude and an azimuth.", input)
+        }
+    }
+
+    /// Follow changes of satellite, move the prediction to now, and recalculate the passes when they are due.
+    fn update_prediction(&mut self) {
+        let satellite: Satellite = self.state.get_satellite();
+        if self.satellite.as_ref() != Some(&satellite) {
+            self.predict = match tracking::load(&satellite, self.location) {
+                Ok(predict) => Some(predict),
+                Err(error) => {
+                    self.message = format!("ERROR, {}.", error);
+                    None
+                }
+            };
+            self.satellite = Some(satellite);
+            self.passes_time = None;
+        }
+        if let Some(predict) = self.predict.as_mut() {
+            if self.passes_time.map_or(true, |passes_time| passes_time.elapsed() >= PASS_INTERVAL) {
+                self.passes = tracking::passes(predict, time::now_utc(), PASS_COUNT);
+                self.passes_time = Some(Instant::now());
+            }
+            predict.update(None);
+        }
+    }
+
+    /// Lay out the screen.
+    fn render(&self, columns: usize, rows: usize) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let mode: Mode = self.state.get_mode();
+        let satellite_name: &str = self.satellite.as_ref().map_or("", |satellite| satellite.name.as_str());
+        lines.push(format!("{}ROTATOR{}  mode {}{}{}  satellite {}  {}", BOLD, RESET, BOLD, mode.name(), RESET, satellite_name, time::now_utc().strftime("%H:%M:%S UTC").unwrap()));
+        lines.push(String::new());
+
+        // Angles and gauges.
+        let altitude: f64 = self.state.altitude.load(Ordering::Relaxed);
+        let azimuth: f64 = self.state.azimuth.load(Ordering::Relaxed);
+        let (target_altitude, target_azimuth) = match self.telemetry.latest().get("rotator") {
+            Some(Record::Rotator { target_altitude, target_azimuth, .. }) => (Some(*target_altitude), Some(*target_azimuth)),
+            _ => (None, None)
+        };
+        let gauge_width: usize = columns.saturating_sub(40).max(10);
+        lines.push("            actual     target     error".to_string());
+        lines.push(format!("Altitude  {}  {}  {}  {}", angle(Some(altitude)), angle(target_altitude), angle(target_altitude.map(|target| target - altitude)), gauge(altitude, target_altitude, 0.0, 90.0, gauge_width)));
+        lines.push(format!("Azimuth   {}  {}  {}  {}", angle(Some(azimuth)), angle(target_azimuth), angle(target_azimuth.map(|target| target - azimuth)), gauge(azimuth.rem_euclid(360.0), target_azimuth.map(|target| target.rem_euclid(360.0)), 0.0, 360.0, gauge_width)));
+        lines.push(String::new());
+
+        // Motors.
+        let (power_1, power_2, battery_voltage) = match self.telemetry.latest().get("motors") {
+            Some(Record::Motors { power_1, power_2, battery_voltage, .. }) => (*power_1, *power_2, *battery_voltage),
+            _ => (0.0, 0.0, 0.0)
+        };
+        let power_width: usize = columns.saturating_sub(24).min(41).max(11);
+        lines.push(format!("Altitude motor {:+5.2} {}", power_1, power_bar(power_1, power_width)));
+        lines.push(format!("Azimuth motor  {:+5.2} {}", power_2, power_bar(power_2, power_width)));
+        let faults: Vec<String> = self.state.faults.active();
+        lines.push(format!("Battery {:.1} V   {}", battery_voltage, if faults.is_empty() { format!("{}No faults{}", GREEN, RESET) } else { format!("{}{}{}", RED, faults.join(" "), RESET) }));
+        lines.push(String::new());
+
+        // Satellite, Doppler and passes.
+        if let Some(predict) = self.predict.as_ref() {
+            let sat = &predict.sat;
+            lines.push(format!("{}  az {:6.1}°  el {:5.1}°  range {:7.0} km  range rate {:+6.2} km/s", satellite_name, sat.az_deg, sat.el_deg, sat.range_km, sat.range_rate_km_sec));
+            // The satellite moving away (positive range rate) lowers the frequency received on the ground, and an uplink must be sent higher to arrive on frequency.
+            let doppler: f64 = sat.range_rate_km_sec / SPEED_OF_LIGHT;
+            let mut frequencies: Vec<String> = Vec::new();
+            if self.downlink_frequency > 0.0 {
+                frequencies.push(format!("Downlink RX {:.4} MHz ({:+.0} Hz)", self.downlink_frequency * (1.0 - doppler) / 1.0e6, -self.downlink_frequency * doppler));
+            }
+            if self.uplink_frequency > 0.0 {
+                frequencies.push(format!("Uplink TX {:.4} MHz ({:+.0} Hz)", self.uplink_frequency / (1.0 - doppler) / 1.0e6, self.uplink_frequency / (1.0 - doppler) - self.uplink_frequency));
+            }
+            lines.push(frequencies.join("   "));
+        }
+        else {
+            lines.push("No satellite loaded.".to_string());
+            lines.push(String::new());
+        }
+        lines.push(format!("{}Passes            AOS in          Length   Max el{}", BOLD, RESET));
+        let now: time::Tm = time::now_utc();
+        for pass in self.passes.iter().filter(|pass| pass.los > now) {
+            let starts: String = if pass.aos <= now { format!("{}now, LOS in {}{}", GREEN, countdown((pass.los - now).num_seconds()), RESET) } else { countdown((pass.aos - now).num_seconds()) };
+            lines.push(format!("{}  {:<16}  {:>3} min  {:5.1}°", pass.aos.to_local().strftime("%a %H:%M").unwrap(), starts, (pass.los - pass.aos).num_minutes(), pass.max_elevation));
+        }
+
+        // Help and prompt at the bottom.
+        while lines.len() + 3 < rows {
+            lines.push(String::new());
+        }
+        lines.push(format!("Hold arrows to jog ({} °/s, +/- to change)  Space stop  t track  p park  g goto  q quit", JOG_SPEEDS[self.jog_speed]));
+        lines.push(self.message.clone());
+        lines.push(match self.input.as_ref() {
+            Some(input) => format!("> {}█", input),
+            None => String::new()
+        });
+        lines.truncate(rows.max(1));
+        lines
+    }
+}
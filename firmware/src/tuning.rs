@@ -0,0 +1,299 @@
+use std::io::{ BufRead, BufReader, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::thread;
+use crate::config::Config;
+use crate::gain_schedule::ScheduledPid;
+use crate::pid::{ Pid, PidParameters };
+
+/// Shared handle to the settings of a PID that runs in a control loop. Anything can read or change
+/// the settings through the handle, and the control loop picks up changes on its next update.
+pub struct PidHandle {
+    /// Name of the PID, as used in the config file.
+    name: String,
+    /// Settings that the PID is running with.
+    current: Mutex<PidParameters>,
+    /// Settings waiting for the control loop to apply them.
+    pending: Mutex<Option<PidParameters>>,
+    /// Whether only the gains belong to this handle, because it controls a later entry of a gain
+    /// schedule whose other settings are shared with entry 0 (see apply_scheduled()).
+    gains_only: bool
+}
+
+impl PidHandle {
+    /// Create a handle for a PID that starts with the given settings.
+    pub fn new(name: &str, parameters: PidParameters) -> Arc<PidHandle> {
+        Arc::new(PidHandle { name: name.to_string(), current: Mutex::new(parameters), pending: Mutex::new(None), gains_only: false })
+    }
+
+    /// Create a handle for an entry after the first of a gain-scheduled PID. Only its gains can be
+    /// looked at or changed through tuning commands.
+    pub fn new_schedule_entry(name: &str, parameters: PidParameters) -> Arc<PidHandle> {
+        Arc::new(PidHandle { name: name.to_string(), current: Mutex::new(parameters), pending: Mutex::new(None), gains_only: true })
+    }
+
+    /// Get the name of the PID.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the names of the settings that can be looked at and changed through the handle.
+    pub fn parameter_names(&self) -> &'static [&'static str] {
+        if self.gains_only { &PidParameters::GAIN_NAMES } else { &PidParameters::NAMES }
+    }
+
+    /// Get the settings of the PID, including any change that the control loop has not picked up yet.
+    pub fn get(&self) -> PidParameters {
+        match *self.pending.lock().unwrap() {
+            Some(parameters) => parameters,
+            None => *self.current.lock().unwrap()
+        }
+    }
+
+    /// Change the settings of the PID. They take effect on the control loop's next update.
+    pub fn set(&self, parameters: PidParameters) {
+        *self.pending.lock().unwrap() = Some(parameters);
+    }
+
+    /// Apply any changed settings to a PID. Call this from the control loop that runs the PID, before computing its output.
+    pub fn apply(&self, pid: &mut Pid) {
+        if let Some(parameters) = self.pending.lock().unwrap().take() {
+            pid.set_parameters(&parameters);
+        }
+        *self.current.lock().unwrap() = pid.get_parameters();
+    }
+
+    /// Apply any changed settings to one entry of a gain-scheduled PID. The gains only change that
+    /// entry. The other settings (limits, filter and weights) are shared by every entry, so they are
+    /// only taken from the handle of entry 0. The handles of the other entries should be made with
+    /// new_schedule_entry(), so that tuning commands do not offer settings that would be ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `pid` - The scheduled PID.
+    ///
+    /// * `entry` - Index of the schedule entry that this handle controls.
+    pub fn apply_scheduled(&self, pid: &mut ScheduledPid, entry: usize) {
+        if let Some(parameters) = self.pending.lock().unwrap().take() {
+            pid.set_entry_gains(entry, (parameters.p, parameters.i, parameters.d));
+            if entry == 0 {
+                let mut shared: PidParameters = parameters;
+                let gains = pid.pid_mut().get_gains();
+                shared.p = gains.0;
+                shared.i = gains.1;
+                shared.d = gains.2;
+                pid.pid_mut().set_parameters(&shared);
+            }
+        }
+        let mut parameters: PidParameters = pid.pid_mut().get_parameters();
+        let gains = pid.get_entry_gains(entry);
+        parameters.p = gains.0;
+        parameters.i = gains.1;
+        parameters.d = gains.2;
+        *self.current.lock().unwrap() = parameters;
+    }
+}
+
+/// Every PID that can be tuned while the rotator is running, and the config file that their settings are saved to.
+pub struct Tuning {
+    /// Handles for the tunable PIDs.
+    pids: Vec<Arc<PidHandle>>,
+    /// Config that the settings are loaded from and saved to.
+    config: Mutex<Config>
+}
+
+impl Tuning {
+    /// Create an empty set of tunable PIDs.
+    pub fn new(config: Config) -> Tuning {
+        Tuning { pids: Vec::new(), config: Mutex::new(config) }
+    }
+
+    /// Add a tunable PID, starting with its settings from the config file.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the PID, as used in the config file and in commands.
+    ///
+    /// * `default` - Settings used for anything that the config file does not set.
+    pub fn add(&mut self, name: &str, default: PidParameters) -> Arc<PidHandle> {
+        let parameters: PidParameters = self.config.lock().unwrap().get_pid_parameters(name, default);
+        let handle = PidHandle::new(name, parameters);
+        self.pids.push(Arc::clone(&handle));
+        handle
+    }
+
+    /// Add the gains of an entry after the first of a gain-scheduled PID, starting with them from
+    /// the config file. Its other settings are tuned through the handle of entry 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the schedule entry, as used in the config file and in commands.
+    ///
+    /// * `default` - Settings used for any gain that the config file does not set.
+    pub fn add_schedule_entry(&mut self, name: &str, default: PidParameters) -> Arc<PidHandle> {
+        let mut parameters: PidParameters = default;
+        let gains = self.config.lock().unwrap().get_pid_gains(name, (default.p, default.i, default.d));
+        parameters.p = gains.0;
+        parameters.i = gains.1;
+        parameters.d = gains.2;
+        let handle = PidHandle::new_schedule_entry(name, parameters);
+        self.pids.push(Arc::clone(&handle));
+        handle
+    }
+
+    /// Lock and get the config that the settings are saved to.
+    pub fn config(&self) -> MutexGuard<Config> {
+        self.config.lock().unwrap()
+    }
+
+    /// Find a tunable PID by name.
+    fn find(&self, name: &str) -> Option<&Arc<PidHandle>> {
+        self.pids.iter().find(|pid| pid.name() == name)
+    }
+
+    /// Describe the settings of a PID on one line.
+    fn describe(pid: &PidHandle) -> String {
+        let parameters: PidParameters = pid.get();
+        let values: Vec<String> = pid.parameter_names().iter().map(|name| format!("{}={}", name, parameters.get(name).unwrap())).collect();
+        format!("{} {}", pid.name(), values.join(" "))
+    }
+
+    /// Check that a PID has a parameter that can be looked at and changed, or get the error reply.
+    fn check_parameter(pid: &PidHandle, parameter: &str) -> Result<(), String> {
+        if pid.parameter_names().contains(&parameter) {
+            Ok(())
+        }
+        else if PidParameters::NAMES.contains(&parameter) {
+            Err(format!("ERROR, {} only has its own p, i and d. Its {} is shared with the first PID of its gain schedule.", pid.name(), parameter))
+        }
+        else {
+            Err(format!("ERROR, no parameter called {}.", parameter))
+        }
+    }
+
+    /// Run a tuning command and return the reply. The commands are:
+    ///
+    /// * `list` - Show the settings of every PID, one PID per line.
+    ///
+    /// * `get <pid> [<parameter>]` - Show all of the settings of a PID, or just one.
+    ///
+    /// * `set <pid> <parameter> <value>` - Change a setting. Parameters are p, i, d, min, max, d_filter, p_weight, d_weight,
+    ///   anti_windup (0 for none, 1 for clamping, 2 for back-calculation) and tracking_time, except that later entries of a
+    ///   gain schedule only have p, i and d.
+    ///
+    /// * `save` - Write the current settings of every PID to the config file, so that they are used on the next start.
+    pub fn command(&self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["list"] => {
+                let lines: Vec<String> = self.pids.iter().map(|pid| Tuning::describe(pid)).collect();
+                lines.join("\n")
+            }
+            ["get", name] => match self.find(name) {
+                Some(pid) => Tuning::describe(pid),
+                None => format!("ERROR, no PID called {}.", name)
+            },
+            ["get", name, parameter] => match self.find(name) {
+                Some(pid) => match Tuning::check_parameter(pid, parameter) {
+                    Ok(()) => format!("{}.{}={}", name, parameter, pid.get().get(parameter).unwrap()),
+                    Err(error) => error
+                },
+                None => format!("ERROR, no PID called {}.", name)
+            },
+            ["set", name, parameter, value] => {
+                let pid = match self.find(name) {
+                    Some(pid) => pid,
+                    None => return format!("ERROR, no PID called {}.", name)
+                };
+                if let Err(error) = Tuning::check_parameter(pid, parameter) {
+                    return error;
+                }
+                let value: f64 = match value.parse::<f64>() {
+                    Ok(value) if value.is_finite() => value,
+                    _ => return format!("ERROR, {} is not a number.", value)
+                };
+                let mut parameters: PidParameters = pid.get();
+                if !parameters.set(parameter, value) {
+                    return format!("ERROR, {} is not a valid {}.", value, parameter);
+                }
+                if let Err(error) = parameters.check() {
+                    return format!("ERROR, {}.", error);
+                }
+                pid.set(parameters);
+                Tuning::describe(pid)
+            }
+            ["save"] => {
+                let mut config = self.config.lock().unwrap();
+                for pid in self.pids.iter() {
+                    let parameters: PidParameters = pid.get();
+                    if pid.gains_only {
+                        config.set_pid_gains(pid.name(), (parameters.p, parameters.i, parameters.d));
+                    }
+                    else {
+                        config.set_pid_parameters(pid.name(), &parameters);
+                    }
+                }
+                match config.save() {
+                    Ok(()) => "OK, saved to the config file.".to_string(),
+                    Err(error) => format!("ERROR, failed to save the config file: {}.", error)
+                }
+            }
+            _ => "ERROR, unknown command. Commands are: list, get <pid> [<parameter>], set <pid> <parameter> <value>, save.".to_string()
+        }
+    }
+}
+
+/// Answer tuning commands from one client, one command per line.
+fn handle_client(tuning: Arc<Tuning>, stream: TcpStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(error) => {
+            println!("Tuning: ERROR, failed to set up connection: {}.", error);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if writeln!(writer, "{}", tuning.command(&line)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Accept tuning commands over TCP in a background thread. Each client sends one command per line (see Tuning::command()) and gets the reply back.
+///
+/// # Arguments
+///
+/// * `tuning` - The tunable PIDs.
+///
+/// * `address` - Address and port to listen on, e.g. "127.0.0.1:4540", or "off".
+pub fn serve(tuning: Arc<Tuning>, address: &str) {
+    if address == "off" {
+        return;
+    }
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            println!("Tuning: ERROR, failed to listen on {}: {}. Tuning is only available from the console.", address, error);
+            return;
+        }
+    };
+    println!("Tuning: Listening on {}.", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tuning_ref = Arc::clone(&tuning);
+                    thread::spawn(move || handle_client(tuning_ref, stream));
+                }
+                Err(error) => println!("Tuning: ERROR, failed to accept connection: {}.", error)
+            }
+        }
+    });
+}
@@ -0,0 +1,320 @@
+use std::io::{ self, Read, Write };
+use std::net::TcpStream;
+use std::sync::{ Arc, Mutex };
+
+/// Appended to the client's key to make the handshake reply (RFC 6455, section 1.3).
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest frame accepted from a client, in bytes. Clients only send control frames and short commands.
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// SHA-1 hash of some data. Only used for the WebSocket handshake, where it is required by the standard.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message: Vec<u8> = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w: [u32; 80] = [0; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([chunk[i * 4], chunk[i * 4 + 1], chunk[i * 4 + 2], chunk[i * 4 + 3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp: u32 = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest: [u8; 20] = [0; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+/// Encode data as standard base64, with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits: u32 = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        encoded.push(ALPHABET[(bits >> 18) as usize & 63] as char);
+        encoded.push(ALPHABET[(bits >> 12) as usize & 63] as char);
+        encoded.push(if chunk.len() > 1 { ALPHABET[(bits >> 6) as usize & 63] as char } else { '=' });
+        encoded.push(if chunk.len() > 2 { ALPHABET[bits as usize & 63] as char } else { '=' });
+    }
+    encoded
+}
+
+/// Work out the Sec-WebSocket-Accept reply to a client's Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), HANDSHAKE_GUID).as_bytes()))
+}
+
+/// A message from a client.
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// The client closed the connection.
+    Close
+}
+
+/// Server side of a WebSocket connection, after the handshake. Clones share the connection, so
+/// that one thread can send while another receives.
+#[derive(Clone)]
+pub struct WebSocket {
+    /// For receiving.
+    reader: Arc<Mutex<TcpStream>>,
+    /// For sending. Locked for a whole frame at a time, so that frames from different threads do not interleave.
+    writer: Arc<Mutex<TcpStream>>
+}
+
+impl WebSocket {
+    /// Wrap a connection whose handshake has been done.
+    pub fn new(stream: TcpStream) -> io::Result<WebSocket> {
+        // Sends are not allowed to hang forever on a client that has gone away.
+        stream.set_write_timeout(Some(std::time::Duration::from_secs(5)))?;
+        stream.set_read_timeout(None)?;
+        let writer: TcpStream = stream.try_clone()?;
+        Ok(WebSocket { reader: Arc::new(Mutex::new(stream)), writer: Arc::new(Mutex::new(writer)) })
+    }
+
+    /// Send one frame. Server frames are never masked.
+    fn send_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame: Vec<u8> = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        if payload.len() < 126 {
+            frame.push(payload.len() as u8);
+        }
+        else if payload.len() <= 0xFFFF {
+            frame.push(126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        else {
+            frame.push(127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        frame.extend_from_slice(payload);
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&frame)?;
+        writer.flush()
+    }
+
+    /// Send a text message.
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send_frame(OPCODE_TEXT, text.as_bytes())
+    }
+
+    /// Close the connection.
+    pub fn close(&self) {
+        let _ = self.send_frame(OPCODE_CLOSE, &[]);
+        let _ = self.writer.lock().unwrap().shutdown(std::net::Shutdown::Both);
+    }
+
+    /// Wait for the next message from the client, answering pings along the way.
+    pub fn receive(&self) -> io::Result<Message> {
+        let mut reader = self.reader.lock().unwrap();
+        let mut message: Vec<u8> = Vec::new();
+        let mut message_opcode: u8 = OPCODE_TEXT;
+        loop {
+            let mut header: [u8; 2] = [0; 2];
+            reader.read_exact(&mut header)?;
+            let finished: bool = header[0] & 0x80 != 0;
+            let opcode: u8 = header[0] & 0x0F;
+            let masked: bool = header[1] & 0x80 != 0;
+            let length: u64 = match header[1] & 0x7F {
+                126 => {
+                    let mut length: [u8; 2] = [0; 2];
+                    reader.read_exact(&mut length)?;
+                    u16::from_be_bytes(length) as u64
+                }
+                127 => {
+                    let mut length: [u8; 8] = [0; 8];
+                    reader.read_exact(&mut length)?;
+                    u64::from_be_bytes(length)
+                }
+                length => length as u64
+            };
+            if length > MAX_FRAME_SIZE || message.len() as u64 + length > MAX_FRAME_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+            }
+            let mut mask: [u8; 4] = [0; 4];
+            if masked {
+                reader.read_exact(&mut mask)?;
+            }
+            let mut payload: Vec<u8> = vec![0; length as usize];
+            reader.read_exact(&mut payload)?;
+            if masked {
+                for (i, byte) in payload.iter_mut().enumerate() {
+                    *byte ^= mask[i % 4];
+                }
+            }
+
+            match opcode {
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let _ = self.send_frame(OPCODE_CLOSE, &payload);
+                    return Ok(Message::Close);
+                }
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    if opcode != OPCODE_CONTINUATION {
+                        message_opcode = opcode;
+                    }
+                    message.extend_from_slice(&payload);
+                    if finished {
+                        return Ok(if message_opcode == OPCODE_TEXT { Message::Text(String::from_utf8_lossy(&message).into_owned()) } else { Message::Binary(message) });
+                    }
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown opcode"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Open a connection over localhost. Returns the server side, wrapped as a WebSocket, and the client side.
+    fn connect() -> (WebSocket, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (WebSocket::new(server).unwrap(), client)
+    }
+
+    /// Make a client frame, masked with a fixed key.
+    fn masked_frame(finished: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame: Vec<u8> = vec![if finished { 0x80 } else { 0x00 } | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn hashes_with_sha1() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // Long enough to need a second block.
+        assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    }
+
+    #[test]
+    fn accepts_the_rfc_6455_example_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(accept_key(" dGhlIHNhbXBsZSBub25jZQ==\r"), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn unmasks_text_frames() {
+        let (websocket, mut client) = connect();
+        client.write_all(&masked_frame(true, OPCODE_TEXT, b"Hello")).unwrap();
+        match websocket.receive().unwrap() {
+            Message::Text(text) => assert_eq!(text, "Hello"),
+            _ => panic!("expected a text message")
+        }
+    }
+
+    #[test]
+    fn joins_fragments_and_answers_pings_between_them() {
+        let (websocket, mut client) = connect();
+        client.write_all(&masked_frame(false, OPCODE_TEXT, b"Hel")).unwrap();
+        client.write_all(&masked_frame(true, OPCODE_PING, b"hi")).unwrap();
+        client.write_all(&masked_frame(false, OPCODE_CONTINUATION, b"l")).unwrap();
+        client.write_all(&masked_frame(true, OPCODE_CONTINUATION, b"o")).unwrap();
+        match websocket.receive().unwrap() {
+            Message::Text(text) => assert_eq!(text, "Hello"),
+            _ => panic!("expected a text message")
+        }
+        let mut pong: [u8; 4] = [0; 4];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x80 | OPCODE_PONG, 2, b'h', b'i']);
+    }
+
+    #[test]
+    fn reads_extended_lengths_and_binary_messages() {
+        let (websocket, mut client) = connect();
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut frame: Vec<u8> = vec![0x80 | OPCODE_BINARY, 126];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&payload);
+        client.write_all(&frame).unwrap();
+        match websocket.receive().unwrap() {
+            Message::Binary(data) => assert_eq!(data, payload),
+            _ => panic!("expected a binary message")
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let (websocket, mut client) = connect();
+        let mut frame: Vec<u8> = vec![0x80 | OPCODE_TEXT, 127];
+        frame.extend_from_slice(&(MAX_FRAME_SIZE + 1).to_be_bytes());
+        client.write_all(&frame).unwrap();
+        assert_eq!(websocket.receive().err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_fragments_that_add_up_to_too_much() {
+        let (websocket, mut client) = connect();
+        let fragment: Vec<u8> = vec![b'a'; MAX_FRAME_SIZE as usize / 2 + 1];
+        // Written from another thread, as the fragments may not fit in the socket buffers.
+        let writer = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let mut frame: Vec<u8> = vec![OPCODE_TEXT, 126];
+                frame.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+                frame.extend_from_slice(&fragment);
+                let _ = client.write_all(&frame);
+            }
+        });
+        assert_eq!(websocket.receive().err().unwrap().kind(), io::ErrorKind::InvalidData);
+        websocket.close();
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn echoes_close_frames() {
+        let (websocket, mut client) = connect();
+        client.write_all(&masked_frame(true, OPCODE_CLOSE, &[0x03, 0xE8])).unwrap();
+        assert!(matches!(websocket.receive().unwrap(), Message::Close));
+        let mut close: [u8; 4] = [0; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xE8]);
+    }
+}